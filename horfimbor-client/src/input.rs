@@ -3,6 +3,9 @@ use reqwasm::http::{Request, Response};
 use serde::Serialize;
 use std::fmt::Debug;
 
+/// header carrying the key of `send_idempotent_command`
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
 /// # Errors
 ///
 /// Will return `Err` when the error cannot be sent or something wrong happens on the backend
@@ -13,7 +16,40 @@ pub async fn send_command<C: Serialize + Debug + Send + Sync, P: EventStoreProps
     cmd: &C,
     props: P,
 ) -> Result<Response, String> {
-    Request::post(&format!(
+    command_request(cmd, &props)?
+        .send()
+        .await
+        .map_err(|_| "fail to send command".to_string())
+}
+
+/// same as `send_command`, retrying with the same key cannot append the command twice
+///
+/// # Errors
+///
+/// Will return `Err` when the error cannot be sent or something wrong happens on the backend
+///
+/// future not send because of <https://github.com/cloudflare/workers-rs/issues/485>
+#[allow(clippy::future_not_send)]
+pub async fn send_idempotent_command<
+    C: Serialize + Debug + Send + Sync,
+    P: EventStoreProps + 'static,
+>(
+    cmd: &C,
+    idempotency_key: &str,
+    props: P,
+) -> Result<Response, String> {
+    command_request(cmd, &props)?
+        .header(IDEMPOTENCY_KEY_HEADER, idempotency_key)
+        .send()
+        .await
+        .map_err(|_| "fail to send command".to_string())
+}
+
+fn command_request<C: Serialize + Debug, P: EventStoreProps>(
+    cmd: &C,
+    props: &P,
+) -> Result<Request, String> {
+    Ok(Request::post(&format!(
         "{endpoint}/{path}/{id}",
         endpoint = props.endpoint(),
         path = props.path(),
@@ -21,8 +57,5 @@ pub async fn send_command<C: Serialize + Debug + Send + Sync, P: EventStoreProps
     ))
     .body(serde_json::to_string(&cmd).map_err(|_| format!("cannot serialize cmd {cmd:?}"))?)
     .header("Content-Type", "application/json")
-    .header("Authorization", props.jwt())
-    .send()
-    .await
-    .map_err(|_| "fail to send command".to_string())
+    .header("Authorization", props.jwt()))
 }
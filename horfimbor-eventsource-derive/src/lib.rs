@@ -106,8 +106,9 @@ pub fn derive_command(input: TokenStream) -> TokenStream {
 ///
 /// it generates it from the event enum :
 /// the attribute `state` give the prefix for the name
-/// unless the attribute `composite_state` in which case the current enum level is skip
-#[proc_macro_derive(Event, attributes(state, composite_state))]
+/// unless the attribute `composite_state` in which case the current enum level is skip.
+/// The optional attribute `event_version` give the schema version of the payload.
+#[proc_macro_derive(Event, attributes(state, composite_state, event_version))]
 pub fn derive_event(input: TokenStream) -> TokenStream {
     let input: DeriveInput = parse_macro_input!(input as DeriveInput);
 
@@ -125,10 +126,20 @@ pub fn derive_event(input: TokenStream) -> TokenStream {
         };
     }
 
+    let event_version = match get_event_version(&input) {
+        Ok(value) => value,
+        Err(value) => return value,
+    };
+
+    if *is_composite_state && event_version.is_some() {
+        return derive_error!("composite events take the version of the inner events");
+    }
+
     // get enum name
     let name = &input.ident;
     let data = &input.data;
     let mut fn_core;
+    let mut fn_version = TokenStream2::new();
 
     match data {
         Data::Enum(data_enum) => {
@@ -159,6 +170,13 @@ pub fn derive_event(input: TokenStream) -> TokenStream {
                             event.event_name()
                         },
                     });
+
+                    fn_version.extend(quote! {
+                        #name::#variant_name #fields_in_variant => {
+
+                            event.event_version()
+                        },
+                    });
                 } else {
                     // Variant can have unnamed fields like `Variant(i32, i64)`
                     // Variant can have named fields like `Variant {x: i32, y: i32}`
@@ -207,6 +225,8 @@ pub fn derive_event(input: TokenStream) -> TokenStream {
         _ => return derive_error!("Event is only implemented for enums"),
     }
 
+    let fn_version = event_version_fn(*is_composite_state, event_version, &fn_version);
+
     let output = quote! {
         impl Event for #name {
             fn event_name(&self) -> EventName {
@@ -214,6 +234,8 @@ pub fn derive_event(input: TokenStream) -> TokenStream {
                     #fn_core
                 }
             }
+
+            #fn_version
         }
     };
     output.into()
@@ -272,3 +294,47 @@ fn get_state_name(input: &DeriveInput) -> Result<Ident, TokenStream> {
     };
     Ok(state_name)
 }
+
+fn get_event_version(input: &DeriveInput) -> Result<Option<u32>, TokenStream> {
+    let attrs = &input.attrs;
+
+    let Some(version) = attrs
+        .iter()
+        .find(|attr| attr.path().is_ident("event_version"))
+    else {
+        return Ok(None);
+    };
+
+    let version = version
+        .parse_args::<syn::LitInt>()
+        .and_then(|v| v.base10_parse::<u32>());
+
+    version
+        .map(Some)
+        .map_err(|_| derive_error!("attribute 'event_version' must be an u32"))
+}
+
+fn event_version_fn(
+    is_composite_state: bool,
+    event_version: Option<u32>,
+    fn_version: &TokenStream2,
+) -> TokenStream2 {
+    if is_composite_state {
+        return quote! {
+            fn event_version(&self) -> u32 {
+                match self {
+                    #fn_version
+                }
+            }
+        };
+    }
+
+    // without the attribute the default of the trait is used
+    event_version.map_or_else(TokenStream2::new, |event_version| {
+        quote! {
+            fn event_version(&self) -> u32 {
+                #event_version
+            }
+        }
+    })
+}
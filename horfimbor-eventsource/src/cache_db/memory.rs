@@ -0,0 +1,399 @@
+//! In memory implementation of the `CacheDb`
+//!
+//! the least recently used models are evicted once the capacity is reached,
+//! and an optional TTL bound how long a model is served.
+//! In front of another `CacheDb` it is a near-cache : the misses are read from it
+//! and every `set` is written in both.
+//! The models are kept decoded, a hit does not parse anything.
+//! A model cached with another `Dto::cache_fingerprint` is a miss.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+
+use crate::Dto;
+use crate::cache_db::{CacheDb, DbError, NoCache, decode_cached, encode_cached};
+use crate::codec::Codec;
+use crate::model_key::ModelKey;
+use crate::repository::ModelWithPosition;
+
+/// The `MemoryCache` can be cloned, all the clones share the same models and counters
+#[derive(Clone)]
+pub struct MemoryCache<S, C = NoCache<S>> {
+    shared: Arc<Shared<S>>,
+    backend: C,
+}
+
+struct Shared<S> {
+    lru: Mutex<Lru<ModelWithPosition<S>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entry<V> {
+    value: V,
+    expire_at: Option<Instant>,
+    used: u64,
+}
+
+/// the entries and their last use, the oldest use is the first of `order`
+struct Lru<V> {
+    capacity: usize,
+    ttl: Option<Duration>,
+    entries: HashMap<String, Entry<V>>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl<V> Lru<V>
+where
+    V: Clone,
+{
+    fn get(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.get_mut(key)?;
+
+        if entry
+            .expire_at
+            .is_some_and(|expire_at| expire_at <= Instant::now())
+        {
+            self.order.remove(&entry.used);
+            self.entries.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        self.order.remove(&entry.used);
+        self.order.insert(self.tick, key.to_string());
+        entry.used = self.tick;
+
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, key: String, value: V) {
+        self.tick += 1;
+
+        let entry = Entry {
+            value,
+            expire_at: self.ttl.map(|ttl| Instant::now() + ttl),
+            used: self.tick,
+        };
+
+        if let Some(previous) = self.entries.insert(key.clone(), entry) {
+            self.order.remove(&previous.used);
+        }
+        self.order.insert(self.tick, key);
+
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.used);
+        }
+    }
+}
+
+impl<S> MemoryCache<S> {
+    /// keep at most `capacity` models, at least one
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self::near(capacity, NoCache::new())
+    }
+}
+
+impl<S, C> MemoryCache<S, C> {
+    /// near-cache in front of the `backend`
+    #[must_use]
+    pub fn near(capacity: usize, backend: C) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                lru: Mutex::new(Lru {
+                    capacity: capacity.max(1),
+                    ttl: None,
+                    entries: HashMap::new(),
+                    order: BTreeMap::new(),
+                    tick: 0,
+                }),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+            backend,
+        }
+    }
+
+    /// a model is served at most `ttl` after being set, shared by the clones
+    #[must_use]
+    pub fn with_ttl(self, ttl: Duration) -> Self {
+        self.lock().ttl = Some(ttl);
+        self
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn backend(&self) -> &C {
+        &self.backend
+    }
+
+    /// the number of `get` served from memory
+    #[must_use]
+    pub fn hits(&self) -> u64 {
+        self.shared.hits.load(Ordering::Relaxed)
+    }
+
+    /// the number of `get` not served from memory, including the expired and stale models
+    #[must_use]
+    pub fn misses(&self) -> u64 {
+        self.shared.misses.load(Ordering::Relaxed)
+    }
+
+    /// the number of models in memory, the expired ones included until they are read
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// `true` when nothing is in memory
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn memory_key(prefix: Option<&str>, key: &ModelKey) -> String {
+        prefix.map_or_else(|| key.format(), |prefix| format!("{prefix}-{key}"))
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lru<ModelWithPosition<S>>> {
+        self.shared
+            .lru
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[async_trait]
+impl<S, C> CacheDb<S> for MemoryCache<S, C>
+where
+    S: Dto,
+    C: CacheDb<S>,
+{
+    /// a model in memory is encoded again as plain json
+    async fn get_from_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<Option<String>, DbError> {
+        let memory_key = Self::memory_key(prefix, key);
+
+        let cached = self.lock().get(&memory_key);
+        if let Some(model) = cached {
+            return encode_cached(&model, Codec::default()).map(Some);
+        }
+
+        let value = self.backend.get_from_db(prefix, key).await?;
+
+        if let Some(model) = value
+            .as_deref()
+            .map(decode_cached::<S>)
+            .transpose()?
+            .flatten()
+        {
+            self.lock().insert(memory_key, model);
+        }
+
+        Ok(value)
+    }
+
+    /// a value cached with another fingerprint is not kept in memory
+    async fn set_in_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+        state: String,
+    ) -> Result<(), DbError> {
+        let memory_key = Self::memory_key(prefix, key);
+        let decoded = decode_cached::<S>(&state);
+
+        self.backend.set_in_db(prefix, key, state).await?;
+
+        if let Ok(Some(model)) = decoded {
+            self.lock().insert(memory_key, model);
+            return Ok(());
+        }
+
+        self.lock().remove(&memory_key);
+
+        decoded.map(|_| ())
+    }
+
+    /// the backend write the model with its own `set_with_codec`
+    async fn set_with_codec(
+        &self,
+        key: &ModelKey,
+        data: &ModelWithPosition<S>,
+        prefix: Option<&str>,
+        codec: Codec,
+    ) -> Result<(), DbError> {
+        self.backend
+            .set_with_codec(key, data, prefix, codec)
+            .await?;
+
+        self.lock()
+            .insert(Self::memory_key(prefix, key), data.clone());
+
+        Ok(())
+    }
+
+    /// the hits and misses are counted here, the fingerprint was checked before the model was kept
+    async fn get(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<ModelWithPosition<S>, DbError> {
+        let memory_key = Self::memory_key(prefix, key);
+
+        let cached = self.lock().get(&memory_key);
+        if let Some(model) = cached {
+            self.shared.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(model);
+        }
+        self.shared.misses.fetch_add(1, Ordering::Relaxed);
+
+        let model = self.backend.get(prefix, key).await?;
+
+        // nothing, or a stale model, in the backend
+        if model.position().is_some() {
+            self.lock().insert(memory_key, model.clone());
+        }
+
+        Ok(model)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::cache_db::notify::{BroadcastNotifier, NotifyingCache};
+    use crate::{Event, EventName};
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct Nothing;
+
+    impl Event for Nothing {
+        fn event_name(&self) -> EventName {
+            "nothing"
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    struct Empty;
+
+    impl Dto for Empty {
+        type Event = Nothing;
+
+        fn play_event(&mut self, _event: &Self::Event) {}
+    }
+
+    fn cached(fingerprint: &str, position: u64) -> String {
+        format!(r#"{{"fingerprint":"{fingerprint}","position":{position},"model":null}}"#)
+    }
+
+    fn set(cache: &impl CacheDb<Empty>, key: &ModelKey, position: u64) {
+        let value = cached(&Empty::cache_fingerprint(), position);
+        block_on(cache.set_in_db(None, key, value)).expect("set");
+    }
+
+    /// the position of the model read by `get`
+    fn get(cache: &impl CacheDb<Empty>, key: &ModelKey) -> Option<u64> {
+        block_on(cache.get(None, key)).expect("get").position()
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let cache = MemoryCache::<Empty>::new(2);
+        let (a, b, c) = (
+            ModelKey::new("lru", Uuid::new_v4()),
+            ModelKey::new("lru", Uuid::new_v4()),
+            ModelKey::new("lru", Uuid::new_v4()),
+        );
+
+        set(&cache, &a, 1);
+        set(&cache, &b, 2);
+        assert_eq!(get(&cache, &a), Some(1));
+
+        set(&cache, &c, 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(get(&cache, &b), None);
+        assert_eq!(get(&cache, &a), Some(1));
+        assert_eq!(get(&cache, &c), Some(3));
+
+        assert_eq!((cache.hits(), cache.misses()), (3, 1));
+    }
+
+    #[test]
+    fn expired_model_is_a_miss() {
+        let cache = MemoryCache::<Empty>::new(2).with_ttl(Duration::ZERO);
+        let key = ModelKey::new("ttl", Uuid::new_v4());
+
+        set(&cache, &key, 1);
+
+        assert_eq!(get(&cache, &key), None);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn near_cache_read_the_backend_once() {
+        let backend = MemoryCache::<Empty>::new(10);
+        let near = MemoryCache::near(1, backend.clone());
+        let (a, b) = (
+            ModelKey::new("near", Uuid::new_v4()),
+            ModelKey::new("near", Uuid::new_v4()),
+        );
+
+        set(&backend, &a, 1);
+        set(&near, &b, 2);
+
+        assert_eq!(get(&near, &a), Some(1));
+        assert_eq!(get(&near, &a), Some(1));
+        assert_eq!(get(&near, &b), Some(2));
+
+        // `b` was evicted from the near-cache by `a`, it is read from the backend
+        assert_eq!((near.hits(), near.misses()), (1, 2));
+        assert_eq!((backend.hits(), backend.misses()), (2, 0));
+    }
+
+    #[test]
+    fn stale_model_is_a_miss() {
+        let cache = MemoryCache::<Empty>::new(2);
+        let key = ModelKey::new("stale", Uuid::new_v4());
+
+        let stale = cached("other.v0", 1);
+        block_on(cache.set_in_db(None, &key, stale)).expect("set");
+
+        assert_eq!(get(&cache, &key), None);
+        assert_eq!((cache.hits(), cache.misses()), (0, 1));
+    }
+
+    #[test]
+    fn notifying_cache_count_the_hits() {
+        let cache = MemoryCache::<Empty>::new(2);
+        let notifying = NotifyingCache::new(cache.clone(), BroadcastNotifier::new(1));
+        let key = ModelKey::new("notify", Uuid::new_v4());
+
+        set(&notifying, &key, 1);
+
+        assert_eq!(get(&notifying, &key), Some(1));
+        assert_eq!((cache.hits(), cache.misses()), (1, 0));
+    }
+}
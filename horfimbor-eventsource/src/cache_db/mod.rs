@@ -2,16 +2,26 @@
 
 use std::marker::PhantomData;
 
+use async_trait::async_trait;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use serde::Serialize;
 use thiserror::Error;
 
 use crate::Dto;
+use crate::codec::{Codec, CodecError};
 use crate::model_key::ModelKey;
 use crate::repository::ModelWithPosition;
 
+pub mod memory;
+pub mod notify;
 #[cfg(feature = "cache-redis")]
 pub mod redis;
+#[cfg(feature = "cache-sqlite")]
+pub mod sqlite;
 
 /// `CacheDb` has only one purpose, reading and writing state somewhere
+#[async_trait]
 pub trait CacheDb<S>: Clone + Send + Sync
 where
     S: Dto,
@@ -21,29 +31,40 @@ where
     /// # Errors
     ///
     /// Will return `Err` if any error append when calling the DB.
-    fn get_from_db(&self, prefix: Option<&str>, key: &ModelKey) -> Result<Option<String>, DbError>;
+    async fn get_from_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<Option<String>, DbError>;
 
     /// internal function to write in the db
     ///
     /// # Errors
     ///
     /// Will return `Err` if any error append when calling the DB.
-    fn set_in_db(&self, prefix: Option<&str>, key: &ModelKey, state: String)
-    -> Result<(), DbError>;
+    async fn set_in_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+        state: String,
+    ) -> Result<(), DbError>;
 
     /// public function to read the db
     ///
     /// # Errors
     ///
     /// Will return `Err` if any error append when calling the DB.
-    fn get(&self, prefix: Option<&str>, key: &ModelKey) -> Result<ModelWithPosition<S>, DbError> {
-        let data = self.get_from_db(prefix, key);
+    async fn get(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<ModelWithPosition<S>, DbError> {
+        let data = self.get_from_db(prefix, key).await;
 
         match data {
             Ok(None) => Ok(ModelWithPosition::default()),
-            Ok(Some(value)) => {
-                Ok(serde_json::from_str(value.as_str()).map_err(DbError::SerdeJson)?)
-            }
+            // cached before a schema change, it is rebuilt from the event store
+            Ok(Some(value)) => Ok(decode_cached(value.as_str())?.unwrap_or_default()),
             Err(err) => Err(err),
         }
     }
@@ -53,17 +74,102 @@ where
     /// # Errors
     ///
     /// Will return `Err` if any error append when calling the DB.
-    fn set(
+    async fn set(
+        &self,
+        key: &ModelKey,
+        data: &ModelWithPosition<S>,
+        prefix: Option<&str>,
+    ) -> Result<(), DbError> {
+        self.set_with_codec(key, data, prefix, Codec::default())
+            .await
+    }
+
+    /// write in the db with the `codec`, the plain json is written as is,
+    /// the other codecs are written as `{content_type};{fingerprint};{base64 payload}`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the codec fail or any error append when calling the DB.
+    async fn set_with_codec(
         &self,
         key: &ModelKey,
-        data: ModelWithPosition<S>,
+        data: &ModelWithPosition<S>,
         prefix: Option<&str>,
+        codec: Codec,
     ) -> Result<(), DbError> {
-        let s = serde_json::to_string(&data).map_err(DbError::SerdeJson)?;
-        self.set_in_db(prefix, key, s)
+        let s = encode_cached(data, codec)?;
+
+        self.set_in_db(prefix, key, s).await
     }
 }
 
+/// the value written by `CacheDb::set_with_codec`
+pub(crate) fn encode_cached<S>(data: &ModelWithPosition<S>, codec: Codec) -> Result<String, DbError>
+where
+    S: Dto,
+{
+    if codec.is_plain_json() {
+        let cached = CachedModel {
+            fingerprint: S::cache_fingerprint(),
+            data,
+        };
+        return serde_json::to_string(&cached).map_err(DbError::SerdeJson);
+    }
+
+    Ok(format!(
+        "{};{};{}",
+        codec.content_type(),
+        S::cache_fingerprint(),
+        BASE64_STANDARD.encode(codec.encode(data)?)
+    ))
+}
+
+/// read a value written by `CacheDb::set_with_codec` with any codec,
+/// `None` when it was cached with another `Dto::cache_fingerprint`
+pub(crate) fn decode_cached<S>(value: &str) -> Result<Option<ModelWithPosition<S>>, DbError>
+where
+    S: Dto,
+{
+    if value.starts_with('{') {
+        // parsed once, the model of another fingerprint may not match `S`
+        let value: serde_json::Value = serde_json::from_str(value)?;
+
+        let fingerprint = value.get("fingerprint").and_then(serde_json::Value::as_str);
+        if fingerprint != Some(S::cache_fingerprint().as_str()) {
+            return Ok(None);
+        }
+
+        return Ok(Some(serde_json::from_value(value)?));
+    }
+
+    let (content_type, fingerprint, payload) = value
+        .split_once(';')
+        .and_then(|(content_type, rest)| {
+            rest.rsplit_once(';')
+                .map(|(fingerprint, payload)| (content_type, fingerprint, payload))
+        })
+        .ok_or_else(|| CodecError::Unknown("missing content type".to_string()))?;
+
+    if fingerprint != S::cache_fingerprint() {
+        return Ok(None);
+    }
+
+    let codec = Codec::from_content_type(content_type)?;
+    let payload = BASE64_STANDARD
+        .decode(payload)
+        .map_err(|e| CodecError::Decode(e.to_string()))?;
+
+    Ok(Some(codec.decode(&payload)?))
+}
+
+/// what is written by `CacheDb::set`
+#[derive(Serialize)]
+struct CachedModel<'a, S> {
+    fingerprint: String,
+    #[serde(flatten)]
+    data: &'a ModelWithPosition<S>,
+}
+
 /// cache db can fail in multiple ways.
 #[derive(Error, Debug)]
 pub enum DbError {
@@ -78,6 +184,10 @@ pub enum DbError {
     /// serde error while reading or writing the cache
     #[error("corruptCache `{0}`")]
     SerdeJson(#[from] serde_json::Error),
+
+    /// the value cannot be written or read with its `Codec`
+    #[error("codec `{0}`")]
+    Codec(#[from] CodecError),
 }
 
 /// `NoCache` is a placeholder allowing quick development,
@@ -101,11 +211,12 @@ impl<S> Default for NoCache<S> {
     }
 }
 
+#[async_trait]
 impl<S> CacheDb<S> for NoCache<S>
 where
     S: Dto,
 {
-    fn get_from_db(
+    async fn get_from_db(
         &self,
         _prefix: Option<&str>,
         _key: &ModelKey,
@@ -113,7 +224,7 @@ where
         Ok(None)
     }
 
-    fn set_in_db(
+    async fn set_in_db(
         &self,
         _prefix: Option<&str>,
         _key: &ModelKey,
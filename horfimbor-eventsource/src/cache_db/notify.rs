@@ -0,0 +1,181 @@
+//! change notifications published by the cache writes
+//!
+//! `NotifyingCache` wrap any `CacheDb` : once a model is written by `set`,
+//! a `StateChange` is published with its `ModelKey` and position.
+//! `BroadcastNotifier` fan out the changes in process,
+//! `redis::RedisNotifier` publish them to the other services with Redis pub/sub.
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, Receiver, Sender};
+
+use crate::Dto;
+use crate::cache_db::{CacheDb, DbError};
+use crate::codec::Codec;
+use crate::model_key::ModelKey;
+use crate::repository::ModelWithPosition;
+
+/// `StateChange` tell that a model changed, the model itself is in the cache
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct StateChange {
+    key: ModelKey,
+    prefix: Option<String>,
+    position: Option<u64>,
+}
+
+impl StateChange {
+    /// straight forward constructor
+    #[must_use]
+    pub const fn new(key: ModelKey, prefix: Option<String>, position: Option<u64>) -> Self {
+        Self {
+            key,
+            prefix,
+            position,
+        }
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn key(&self) -> &ModelKey {
+        &self.key
+    }
+
+    /// the cache prefix of the repository, `None` for a `StateRepository`
+    #[must_use]
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    /// the position of the cached model
+    #[must_use]
+    pub const fn position(&self) -> Option<u64> {
+        self.position
+    }
+}
+
+/// `ChangeNotifier` publish the `StateChange` somewhere
+#[async_trait]
+pub trait ChangeNotifier: Clone + Send + Sync {
+    /// publish the change, nobody listening is not an error
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the change cannot be published
+    async fn publish(&self, change: &StateChange) -> Result<(), DbError>;
+}
+
+/// `BroadcastNotifier` is an in process channel, the clones share it
+#[derive(Clone)]
+pub struct BroadcastNotifier {
+    sender: Sender<StateChange>,
+}
+
+impl BroadcastNotifier {
+    /// a slow receiver lose the changes older than the last `capacity` ones
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity.max(1));
+        Self { sender }
+    }
+
+    /// receive the changes published after the call
+    #[must_use]
+    pub fn subscribe(&self) -> Receiver<StateChange> {
+        self.sender.subscribe()
+    }
+
+    /// the number of receivers
+    #[must_use]
+    pub fn receiver_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+}
+
+#[async_trait]
+impl ChangeNotifier for BroadcastNotifier {
+    async fn publish(&self, change: &StateChange) -> Result<(), DbError> {
+        // the only error is having no receiver
+        let _ = self.sender.send(change.clone());
+        Ok(())
+    }
+}
+
+/// `NotifyingCache` publish a `StateChange` after each `set` of the wrapped `CacheDb`
+#[derive(Clone)]
+pub struct NotifyingCache<C, N> {
+    cache: C,
+    notifier: N,
+}
+
+impl<C, N> NotifyingCache<C, N> {
+    /// straight forward constructor
+    #[must_use]
+    pub const fn new(cache: C, notifier: N) -> Self {
+        Self { cache, notifier }
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn cache(&self) -> &C {
+        &self.cache
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn notifier(&self) -> &N {
+        &self.notifier
+    }
+}
+
+#[async_trait]
+impl<S, C, N> CacheDb<S> for NotifyingCache<C, N>
+where
+    S: Dto,
+    C: CacheDb<S>,
+    N: ChangeNotifier,
+{
+    async fn get_from_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<Option<String>, DbError> {
+        self.cache.get_from_db(prefix, key).await
+    }
+
+    /// forwarded, the wrapped `CacheDb` may not read through `get_from_db`
+    async fn get(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<ModelWithPosition<S>, DbError> {
+        self.cache.get(prefix, key).await
+    }
+
+    /// the raw values have no position, nothing is published
+    async fn set_in_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+        state: String,
+    ) -> Result<(), DbError> {
+        self.cache.set_in_db(prefix, key, state).await
+    }
+
+    async fn set_with_codec(
+        &self,
+        key: &ModelKey,
+        data: &ModelWithPosition<S>,
+        prefix: Option<&str>,
+        codec: Codec,
+    ) -> Result<(), DbError> {
+        self.cache.set_with_codec(key, data, prefix, codec).await?;
+
+        let change = StateChange::new(
+            key.clone(),
+            prefix.map(ToString::to_string),
+            data.position(),
+        );
+
+        self.notifier.publish(&change).await
+    }
+}
@@ -1,67 +1,269 @@
 //! Redis implementation of the `CacheDb`
+//!
+//! all the `StateDb` sharing a `RedisConnection` use the same multiplexed connection,
+//! it is opened on the first call and opened again after the connection dropped.
+//!
+//! `RedisNotifier` publish the `StateChange` on a Redis channel,
+//! each service forward them to its own `BroadcastNotifier` with `listen`.
 
 use std::marker::PhantomData;
+use std::sync::Arc;
 
-use redis::{Client, Commands};
+use async_trait::async_trait;
+use futures::StreamExt;
+use redis::aio::MultiplexedConnection;
+use redis::{Client, Cmd, FromRedisValue, RedisError};
+use tokio::sync::Mutex;
 
 use crate::Dto;
+use crate::cache_db::notify::{BroadcastNotifier, ChangeNotifier, StateChange};
 use crate::cache_db::{CacheDb, DbError};
 use crate::model_key::ModelKey;
 
+/// `RedisConnection` is a multiplexed connection, reconnected when dropped
+#[derive(Clone)]
+pub struct RedisConnection {
+    client: Client,
+    slot: Arc<Mutex<Slot>>,
+}
+
+/// the current connection, the generation tell apart the successive ones
+#[derive(Default)]
+struct Slot {
+    generation: u64,
+    connection: Option<MultiplexedConnection>,
+}
+
+impl RedisConnection {
+    /// nothing is opened before the first call
+    #[must_use]
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            slot: Arc::new(Mutex::new(Slot::default())),
+        }
+    }
+
+    /// run the command, once again on a new connection if the current one dropped :
+    /// the command must be idempotent, see `query_once`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if Redis cannot be reached or the command fail
+    pub async fn query<T>(&self, command: &Cmd) -> Result<T, DbError>
+    where
+        T: FromRedisValue,
+    {
+        match self.try_query(command).await? {
+            Err(e) if is_dropped(&e) => self.query_once(command).await,
+            result => result.map_err(|e| DbError::Internal(e.to_string())),
+        }
+    }
+
+    /// run the command a single time, for the commands like `PUBLISH` that must not be sent twice.
+    /// The next call use a new connection if the current one dropped
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if Redis cannot be reached or the command fail
+    pub async fn query_once<T>(&self, command: &Cmd) -> Result<T, DbError>
+    where
+        T: FromRedisValue,
+    {
+        self.try_query(command).await?.map_err(|e| {
+            if is_dropped(&e) {
+                DbError::Disconnect(e.to_string())
+            } else {
+                DbError::Internal(e.to_string())
+            }
+        })
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// the dropped connection is removed from the slot, unless it was already replaced
+    async fn try_query<T>(&self, command: &Cmd) -> Result<Result<T, RedisError>, DbError>
+    where
+        T: FromRedisValue,
+    {
+        let (generation, mut connection) = self.connection().await?;
+
+        let result = command.query_async(&mut connection).await;
+
+        if let Err(e) = &result
+            && is_dropped(e)
+        {
+            let mut slot = self.slot.lock().await;
+            if slot.generation == generation {
+                slot.connection = None;
+            }
+        }
+
+        Ok(result)
+    }
+
+    async fn connection(&self) -> Result<(u64, MultiplexedConnection), DbError> {
+        let mut slot = self.slot.lock().await;
+
+        if let Some(connection) = slot.connection.as_ref() {
+            return Ok((slot.generation, connection.clone()));
+        }
+
+        let connection = self
+            .client
+            .get_multiplexed_async_connection()
+            .await
+            .map_err(|e| DbError::Disconnect(e.to_string()))?;
+
+        slot.generation += 1;
+        slot.connection = Some(connection.clone());
+
+        Ok((slot.generation, connection))
+    }
+}
+
+fn is_dropped(error: &RedisError) -> bool {
+    error.is_connection_dropped() || error.is_io_error() || error.is_unrecoverable_error()
+}
+
 /// The `StateDb` is a container for the Type system and a db connection
 #[derive(Clone)]
 pub struct StateDb<S> {
-    client: Client,
+    connection: RedisConnection,
     state: PhantomData<S>,
 }
 
 impl<S> StateDb<S> {
     /// simple constructor
     #[must_use]
-    pub const fn new(client: Client) -> Self {
+    pub fn new(client: Client) -> Self {
+        Self::with_connection(RedisConnection::new(client))
+    }
+
+    /// share the connection with other `StateDb`
+    #[must_use]
+    pub const fn with_connection(connection: RedisConnection) -> Self {
         Self {
-            client,
+            connection,
             state: PhantomData,
         }
     }
+
+    /// simple getter
+    #[must_use]
+    pub const fn connection(&self) -> &RedisConnection {
+        &self.connection
+    }
 }
 
+#[async_trait]
 impl<S> CacheDb<S> for StateDb<S>
 where
     S: Dto,
 {
-    fn get_from_db(&self, prefix: Option<&str>, key: &ModelKey) -> Result<Option<String>, DbError> {
-        let mut connection = self
-            .client
-            .get_connection()
-            .map_err(|e| DbError::Disconnect(e.to_string()))?;
-
+    async fn get_from_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<Option<String>, DbError> {
         let key = prefix.map_or_else(|| key.format(), |prefix| format!("{prefix}-{key}"));
 
-        let data: Option<String> = connection
-            .get(key)
-            .map_err(|e| DbError::Internal(e.to_string()))?;
-
-        Ok(data)
+        self.connection.query(redis::cmd("GET").arg(key)).await
     }
 
-    fn set_in_db(
+    async fn set_in_db(
         &self,
         prefix: Option<&str>,
         key: &ModelKey,
         state: String,
     ) -> Result<(), DbError> {
-        let mut connection = self
-            .client
-            .get_connection()
+        let key = prefix.map_or_else(|| key.format(), |prefix| format!("{prefix}-{key}"));
+
+        self.connection
+            .query(redis::cmd("SET").arg(key).arg(state))
+            .await
+    }
+}
+
+/// `RedisNotifier` publish the `StateChange` on a Redis channel
+#[derive(Clone)]
+pub struct RedisNotifier {
+    connection: RedisConnection,
+    channel: String,
+}
+
+impl RedisNotifier {
+    /// the changes are published as json on `channel`
+    #[must_use]
+    pub fn new(connection: RedisConnection, channel: &str) -> Self {
+        Self {
+            connection,
+            channel: channel.to_string(),
+        }
+    }
+
+    /// simple getter
+    #[must_use]
+    pub fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    /// forward the changes published on the channel to the `BroadcastNotifier`,
+    /// one listener per service is enough. The malformed payloads are skipped
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if Redis cannot be reached or once the subscription dropped
+    pub async fn listen(&self, local: &BroadcastNotifier) -> Result<(), DbError> {
+        let mut pubsub = self
+            .connection
+            .client()
+            .get_async_pubsub()
+            .await
             .map_err(|e| DbError::Disconnect(e.to_string()))?;
 
-        let key = prefix.map_or_else(|| key.format(), |prefix| format!("{prefix}-{key}"));
+        pubsub
+            .subscribe(&self.channel)
+            .await
+            .map_err(|e| DbError::Disconnect(e.to_string()))?;
+
+        let mut messages = pubsub.into_on_message();
+
+        while let Some(message) = messages.next().await {
+            // anyone can publish on the channel, a malformed payload must not stop the service
+            let change = message
+                .get_payload::<String>()
+                .map_err(|e| DbError::Internal(e.to_string()))
+                .and_then(|payload| Ok(serde_json::from_str::<StateChange>(&payload)?));
+
+            match change {
+                Ok(change) => local.publish(&change).await?,
+                #[cfg(feature = "telemetry")]
+                Err(err) => tracing::warn!(channel = %self.channel, "skipped change : {err}"),
+                #[cfg(not(feature = "telemetry"))]
+                Err(_) => {}
+            }
+        }
+
+        Err(DbError::Disconnect(format!(
+            "subscription to {} dropped",
+            self.channel
+        )))
+    }
+}
 
-        connection
-            .set::<_, _, ()>(key, state)
-            .map_err(|err| DbError::Internal(err.to_string()))?;
+#[async_trait]
+impl ChangeNotifier for RedisNotifier {
+    async fn publish(&self, change: &StateChange) -> Result<(), DbError> {
+        let payload = serde_json::to_string(change)?;
 
-        Ok(())
+        // a retry could deliver the change twice
+        self.connection
+            .query_once(redis::cmd("PUBLISH").arg(&self.channel).arg(payload))
+            .await
     }
 }
@@ -0,0 +1,258 @@
+//! `SQLite` implementation of the `CacheDb`
+//!
+//! the models are kept in the `cache_models` table, keyed by prefix and `ModelKey`,
+//! the position has its own column so the cache can be queried without decoding the models.
+
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use sqlx::SqlitePool;
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+
+use crate::Dto;
+use crate::cache_db::{CacheDb, DbError, decode_cached, encode_cached};
+use crate::codec::Codec;
+use crate::model_key::ModelKey;
+use crate::repository::ModelWithPosition;
+
+const MIGRATION: &str = include_str!("./sqlite_migration.sql");
+
+/// open the db file, it is created if missing
+///
+/// # Errors
+///
+/// Will return `Err` if the db file cannot be opened
+pub async fn open(database_url: &str) -> Result<SqlitePool, DbError> {
+    let opts = SqliteConnectOptions::from_str(database_url)
+        .map_err(|e| DbError::Disconnect(e.to_string()))?
+        .create_if_missing(true);
+
+    SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect_with(opts)
+        .await
+        .map_err(|e| DbError::Disconnect(e.to_string()))
+}
+
+/// The `SqliteStateDb` is a container for the Type system and a `SqlitePool`
+#[derive(Clone)]
+pub struct SqliteStateDb<S> {
+    pool: SqlitePool,
+    state: PhantomData<S>,
+}
+
+impl<S> SqliteStateDb<S> {
+    /// simple constructor, `migrate` must be called once before using it
+    #[must_use]
+    pub const fn new(pool: SqlitePool) -> Self {
+        Self {
+            pool,
+            state: PhantomData,
+        }
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+impl<S> SqliteStateDb<S>
+where
+    S: Dto,
+{
+    /// create the `cache_models` table if needed
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a statement of the migration fail
+    pub async fn migrate(&self) -> Result<(), DbError> {
+        for stmt in MIGRATION
+            .split(';')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+        {
+            sqlx::query(stmt)
+                .execute(&self.pool)
+                .await
+                .map_err(|e| DbError::Internal(e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// the position of the cached model, without decoding it
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the query fail
+    pub async fn position(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<Option<u64>, DbError> {
+        let position: Option<Option<i64>> = sqlx::query_scalar(
+            "SELECT position FROM cache_models WHERE prefix = ? AND model_key = ?",
+        )
+        .bind(prefix.unwrap_or_default())
+        .bind(key.format())
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| DbError::Internal(e.to_string()))?;
+
+        position
+            .flatten()
+            .map(|position| u64::try_from(position).map_err(|e| DbError::Internal(e.to_string())))
+            .transpose()
+    }
+
+    async fn upsert(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+        position: Option<u64>,
+        state: String,
+    ) -> Result<(), DbError> {
+        let position = position
+            .map(i64::try_from)
+            .transpose()
+            .map_err(|e| DbError::Internal(e.to_string()))?;
+
+        sqlx::query(
+            "INSERT INTO cache_models (prefix, model_key, position, model) VALUES (?, ?, ?, ?)
+             ON CONFLICT (prefix, model_key)
+             DO UPDATE SET position = excluded.position, model = excluded.model, updated_at = CURRENT_TIMESTAMP",
+        )
+        .bind(prefix.unwrap_or_default())
+        .bind(key.format())
+        .bind(position)
+        .bind(state)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| DbError::Internal(e.to_string()))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<S> CacheDb<S> for SqliteStateDb<S>
+where
+    S: Dto,
+{
+    async fn get_from_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<Option<String>, DbError> {
+        sqlx::query_scalar("SELECT model FROM cache_models WHERE prefix = ? AND model_key = ?")
+            .bind(prefix.unwrap_or_default())
+            .bind(key.format())
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|e| DbError::Internal(e.to_string()))
+    }
+
+    /// a value written as is : the position is read from it
+    async fn set_in_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+        state: String,
+    ) -> Result<(), DbError> {
+        // `None` for a model cached with another fingerprint, it is a miss anyway
+        let position = decode_cached::<S>(&state)?.and_then(|cached| cached.position());
+
+        self.upsert(prefix, key, position, state).await
+    }
+
+    /// the position is taken from the model, it is not decoded again
+    async fn set_with_codec(
+        &self,
+        key: &ModelKey,
+        data: &ModelWithPosition<S>,
+        prefix: Option<&str>,
+        codec: Codec,
+    ) -> Result<(), DbError> {
+        let state = encode_cached(data, codec)?;
+
+        self.upsert(prefix, key, data.position(), state).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::{Event, EventName};
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    struct Increment;
+
+    impl Event for Increment {
+        fn event_name(&self) -> EventName {
+            "increment"
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Serialize, Deserialize)]
+    struct Counter {
+        value: u32,
+    }
+
+    impl Dto for Counter {
+        type Event = Increment;
+
+        fn play_event(&mut self, _event: &Self::Event) {
+            self.value += 1;
+        }
+    }
+
+    async fn cache() -> SqliteStateDb<Counter> {
+        // every connection to `:memory:` open its own db
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("open");
+
+        let cache = SqliteStateDb::new(pool);
+        cache.migrate().await.expect("migrate");
+        cache.migrate().await.expect("migrate twice");
+        cache
+    }
+
+    #[tokio::test]
+    async fn model_and_position_are_stored() {
+        let cache = cache().await;
+        let key = ModelKey::new("counter", Uuid::new_v4());
+
+        let empty = cache.get(None, &key).await.expect("get");
+        assert_eq!(empty.position(), None);
+        assert_eq!(cache.position(None, &key).await.expect("position"), None);
+
+        let mut model = ModelWithPosition::<Counter>::default();
+        model.play_event(&Increment, Some(3));
+        model.play_event(&Increment, Some(7));
+        cache.set(&key, &model, None).await.expect("set");
+
+        let cached = cache.get(None, &key).await.expect("get");
+        assert_eq!(cached.position(), Some(7));
+        assert_eq!(cached.state().value, 2);
+        assert_eq!(cache.position(None, &key).await.expect("position"), Some(7));
+
+        // the prefix is part of the key
+        assert_eq!(
+            cache.position(Some("dto"), &key).await.expect("position"),
+            None
+        );
+
+        model.play_event(&Increment, Some(8));
+        cache.set(&key, &model, None).await.expect("set again");
+        assert_eq!(cache.position(None, &key).await.expect("position"), Some(8));
+    }
+}
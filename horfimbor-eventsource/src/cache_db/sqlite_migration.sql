@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS cache_models (
+    prefix      TEXT        NOT NULL DEFAULT '',
+    model_key   TEXT        NOT NULL,
+    position    INTEGER,
+    model       TEXT        NOT NULL,
+    updated_at  TEXT        NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (prefix, model_key)
+);
+
+CREATE INDEX IF NOT EXISTS idx_cache_models_key
+    ON cache_models (model_key);
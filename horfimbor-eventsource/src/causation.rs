@@ -0,0 +1,299 @@
+//! the causation tree of a correlation, to debug and export the actions spanning several entities
+//!
+//! `causation_tree` read the `bc-{correlation_id}` stream, every command and event of every entity
+//! sharing the correlation, and link each entry to its `$causationId`.
+//! The payloads of the streams with a decoder registered in `CausationDecoders` are decoded
+//! with the types of their `State` (and its `Upcasters`), the other ones are kept as json.
+//! The tree is `Serialize`, `CausationTree::to_json` is the export for the tooling.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use kurrentdb::{Error, StreamPosition};
+use serde::Serialize;
+use serde_json::{Error as SerdeError, Value};
+use uuid::Uuid;
+
+use crate::event_store::{EventReader, EventStore, StoredEvent};
+use crate::metadata::Metadata;
+use crate::upcaster::Upcasters;
+use crate::{EventSourceError, State, Stream, StreamName};
+
+type Decoder = Arc<dyn Fn(&StoredEvent, &Metadata) -> Result<Value, SerdeError> + Send + Sync>;
+
+/// `CausationDecoders` is the registry of the typed decoders, by stream name, cheap to clone
+#[derive(Clone, Default)]
+pub struct CausationDecoders {
+    decoders: HashMap<String, Decoder>,
+}
+
+impl CausationDecoders {
+    /// an empty registry, every payload is kept as json
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the entries of the `stream_name` streams are decoded as `S::Command` or `S::Event`,
+    /// the events go through the `upcasters` first
+    #[must_use]
+    pub fn register<S>(mut self, stream_name: StreamName, upcasters: Upcasters) -> Self
+    where
+        S: State,
+    {
+        let decoder = move |stored: &StoredEvent, metadata: &Metadata| {
+            if metadata.is_event() {
+                let event: S::Event = upcasters.decode(stored, metadata)?;
+                serde_json::to_value(event)
+            } else {
+                let command: S::Command = stored.decode(metadata)?;
+                serde_json::to_value(command)
+            }
+        };
+
+        // same rule as the `ModelKey`
+        self.decoders
+            .insert(stream_name.replace('-', "_"), Arc::new(decoder));
+        self
+    }
+
+    fn decode(&self, stored: &StoredEvent, metadata: &Metadata) -> CausationPayload {
+        let stream_name = stored.stream_id().split('-').next().unwrap_or_default();
+
+        let Some(decoder) = self.decoders.get(stream_name) else {
+            return stored.decode(metadata).map_or_else(
+                |e| CausationPayload::Undecodable(e.to_string()),
+                CausationPayload::Raw,
+            );
+        };
+
+        decoder(stored, metadata).map_or_else(
+            |e| CausationPayload::Undecodable(e.to_string()),
+            CausationPayload::Typed,
+        )
+    }
+}
+
+/// `CausationPayload` is the payload of a node, as far as it could be read
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
+pub enum CausationPayload {
+    /// decoded by the decoder registered for the stream
+    Typed(Value),
+    /// no decoder is registered, the payload is read as json
+    Raw(Value),
+    /// the payload cannot be read, with the reason
+    Undecodable(String),
+}
+
+/// `CausationNode` is a command or an event with the entries it caused
+#[derive(Clone, Debug, Serialize)]
+pub struct CausationNode {
+    id: Uuid,
+    stream_id: String,
+    revision: u64,
+    event_type: String,
+    is_event: bool,
+    created: DateTime<Utc>,
+    metadata: Metadata,
+    payload: CausationPayload,
+    children: Vec<Self>,
+}
+
+impl CausationNode {
+    /// simple getter
+    #[must_use]
+    pub const fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// the stream of the entity, it can be parsed as a `ModelKey`
+    #[must_use]
+    pub fn stream_id(&self) -> &str {
+        &self.stream_id
+    }
+
+    /// the position of the entry in the stream of the entity
+    #[must_use]
+    pub const fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// the `EventName` or the `CommandName`
+    #[must_use]
+    pub fn event_type(&self) -> &str {
+        &self.event_type
+    }
+
+    /// `true` for an event, `false` for a command
+    #[must_use]
+    pub const fn is_event(&self) -> bool {
+        self.is_event
+    }
+
+    /// the date the store received the entry
+    #[must_use]
+    pub const fn created(&self) -> DateTime<Utc> {
+        self.created
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn payload(&self) -> &CausationPayload {
+        &self.payload
+    }
+
+    /// the entries caused by this one, in the order they were written
+    #[must_use]
+    pub fn children(&self) -> &[Self] {
+        &self.children
+    }
+
+    fn find(&self, id: Uuid) -> Option<&Self> {
+        if self.id == id {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(id))
+    }
+
+    fn len(&self) -> usize {
+        1 + self.children.iter().map(Self::len).sum::<usize>()
+    }
+}
+
+/// `CausationTree` is every entry of a correlation, the roots are the entries without a known cause
+#[derive(Clone, Debug, Serialize)]
+pub struct CausationTree {
+    correlation_id: Uuid,
+    roots: Vec<CausationNode>,
+}
+
+impl CausationTree {
+    /// simple getter
+    #[must_use]
+    pub const fn correlation_id(&self) -> Uuid {
+        self.correlation_id
+    }
+
+    /// usually the command starting the correlation,
+    /// an entry whose cause is not in the correlation is a root too
+    #[must_use]
+    pub fn roots(&self) -> &[CausationNode] {
+        &self.roots
+    }
+
+    /// look for a command or an event in the whole tree
+    #[must_use]
+    pub fn find(&self, id: Uuid) -> Option<&CausationNode> {
+        self.roots.iter().find_map(|root| root.find(id))
+    }
+
+    /// the number of entries in the tree
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.roots.iter().map(CausationNode::len).sum()
+    }
+
+    /// `true` when nothing was found for the correlation
+    #[must_use]
+    pub const fn is_empty(&self) -> bool {
+        self.roots.is_empty()
+    }
+
+    /// the tree as json, for the tooling
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the tree cannot be serialized
+    pub fn to_json(&self) -> Result<Value, SerdeError> {
+        serde_json::to_value(self)
+    }
+}
+
+/// read the `bc-{correlation_id}` stream and rebuild the causation tree,
+/// the entries without readable `Metadata` are skipped
+///
+/// # Errors
+///
+/// Will return `Err` if the correlation stream cannot be read
+pub async fn causation_tree<E>(
+    event_db: &E,
+    correlation_id: Uuid,
+    decoders: &CausationDecoders,
+) -> Result<CausationTree, EventSourceError>
+where
+    E: EventStore,
+{
+    let mut stream = event_db
+        .read_stream(
+            &Stream::Correlation(correlation_id).to_string(),
+            StreamPosition::Start,
+        )
+        .await
+        .map_err(EventSourceError::EventStore)?;
+
+    let mut nodes = Vec::new();
+    let mut causes = Vec::new();
+
+    loop {
+        let stored = match stream.next().await {
+            Ok(Some(stored)) => stored,
+            Ok(None) | Err(Error::ResourceNotFound) => break,
+            Err(e) => return Err(EventSourceError::EventStore(e)),
+        };
+
+        let Ok(metadata) = stored.metadata() else {
+            continue;
+        };
+
+        causes.push(metadata.causation_id());
+        nodes.push(CausationNode {
+            id: stored.id(),
+            stream_id: stored.stream_id().to_string(),
+            revision: stored.revision(),
+            event_type: stored.event_type().to_string(),
+            is_event: metadata.is_event(),
+            created: stored.created(),
+            payload: decoders.decode(&stored, &metadata),
+            metadata,
+            children: Vec::new(),
+        });
+    }
+
+    // a cause is always written before its consequences, it also keep the tree free of cycles
+    let index: HashMap<Uuid, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| (node.id, i))
+        .collect();
+    let parents: Vec<Option<usize>> = causes
+        .iter()
+        .enumerate()
+        .map(|(i, cause)| index.get(cause).copied().filter(|parent| *parent < i))
+        .collect();
+
+    // the children are attached from the last entry, each one is complete when its parent take it
+    let mut slots: Vec<Option<CausationNode>> = nodes.into_iter().map(Some).collect();
+    for i in (0..slots.len()).rev() {
+        let Some(parent) = parents[i] else {
+            continue;
+        };
+        if let Some(node) = slots[i].take()
+            && let Some(parent) = slots[parent].as_mut()
+        {
+            parent.children.insert(0, node);
+        }
+    }
+
+    Ok(CausationTree {
+        correlation_id,
+        roots: slots.into_iter().flatten().collect(),
+    })
+}
@@ -0,0 +1,368 @@
+//! how the events and the cached models are serialized
+//!
+//! JSON is the default, the binary formats and the compression are behind features :
+//! `codec-msgpack`, `codec-cbor`, `codec-postcard` and `compression-lz4`.
+//! The `Codec` is chosen per repository and its content type is written with each value,
+//! so values written with different codecs can be read during a migration.
+//!
+//! Postcard is not self-describing, the events written with it cannot go through `Upcasters`.
+//!
+//! The binary events can only be read with `StoredEvent::decode`, not with `StoredEvent::as_json`
+//! nor by the `KurrentDB` projections and the other consumers of the streams.
+
+use std::fmt::{Display, Formatter};
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+/// the serialization format, a format can be selected even when its feature is disabled
+/// but encoding or decoding with it fail
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    /// `serde_json`, the default
+    #[default]
+    Json,
+    /// `rmp-serde`, need the `codec-msgpack` feature
+    MessagePack,
+    /// `ciborium`, need the `codec-cbor` feature
+    Cbor,
+    /// `postcard`, need the `codec-postcard` feature
+    Postcard,
+}
+
+impl Format {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::MessagePack => "msgpack",
+            Self::Cbor => "cbor",
+            Self::Postcard => "postcard",
+        }
+    }
+}
+
+/// the compression applied after the serialization
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Compression {
+    /// written as serialized
+    #[default]
+    None,
+    /// `lz4_flex`, need the `compression-lz4` feature
+    Lz4,
+}
+
+/// error coming from a `Codec`
+#[derive(Error, Debug)]
+pub enum CodecError {
+    /// the feature of the format or of the compression is disabled
+    #[error("codec `{0}` is not enabled")]
+    Unavailable(String),
+
+    /// the recorded content type is not known
+    #[error("unknown codec `{0}`")]
+    Unknown(String),
+
+    /// the value cannot be serialized
+    #[error("encode `{0}`")]
+    Encode(String),
+
+    /// the payload does not match the type
+    #[error("decode `{0}`")]
+    Decode(String),
+}
+
+/// `Codec` is a `Format` with an optional `Compression`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Codec {
+    format: Format,
+    compression: Compression,
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.compression {
+            Compression::None => write!(f, "{}", self.format.name()),
+            Compression::Lz4 => write!(f, "{}+lz4", self.format.name()),
+        }
+    }
+}
+
+impl Codec {
+    /// uncompressed `format`
+    #[must_use]
+    pub const fn new(format: Format) -> Self {
+        Self {
+            format,
+            compression: Compression::None,
+        }
+    }
+
+    /// compress the serialized values
+    #[must_use]
+    pub const fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn format(&self) -> Format {
+        self.format
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// uncompressed json, the values are written as before the codecs
+    #[must_use]
+    pub const fn is_plain_json(&self) -> bool {
+        matches!(self.format, Format::Json) && matches!(self.compression, Compression::None)
+    }
+
+    /// the content type written with the values : `json`, `msgpack+lz4` ...
+    #[must_use]
+    pub fn content_type(&self) -> String {
+        self.to_string()
+    }
+
+    /// read back a content type
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the content type was not written by a `Codec`
+    pub fn from_content_type(content_type: &str) -> Result<Self, CodecError> {
+        let (format, compression) = match content_type.split_once('+') {
+            None => (content_type, Compression::None),
+            Some((format, "lz4")) => (format, Compression::Lz4),
+            Some(_) => return Err(CodecError::Unknown(content_type.to_string())),
+        };
+
+        let format = match format {
+            "json" => Format::Json,
+            "msgpack" => Format::MessagePack,
+            "cbor" => Format::Cbor,
+            "postcard" => Format::Postcard,
+            _ => return Err(CodecError::Unknown(content_type.to_string())),
+        };
+
+        Ok(Self::new(format).with_compression(compression))
+    }
+
+    /// serialize then compress
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the codec is not enabled or the value cannot be serialized
+    pub fn encode<T>(&self, value: &T) -> Result<Vec<u8>, CodecError>
+    where
+        T: Serialize + ?Sized,
+    {
+        let serialized = match self.format {
+            Format::Json => {
+                serde_json::to_vec(value).map_err(|e| CodecError::Encode(e.to_string()))?
+            }
+            Format::MessagePack => encode_msgpack(value)?,
+            Format::Cbor => encode_cbor(value)?,
+            Format::Postcard => encode_postcard(value)?,
+        };
+
+        match self.compression {
+            Compression::None => Ok(serialized),
+            Compression::Lz4 => compress_lz4(&serialized),
+        }
+    }
+
+    /// decompress then deserialize
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the codec is not enabled or the payload does not match `T`
+    pub fn decode<T>(&self, payload: &[u8]) -> Result<T, CodecError>
+    where
+        T: DeserializeOwned,
+    {
+        let decompressed;
+        let serialized = match self.compression {
+            Compression::None => payload,
+            Compression::Lz4 => {
+                decompressed = decompress_lz4(payload)?;
+                &decompressed
+            }
+        };
+
+        match self.format {
+            Format::Json => {
+                serde_json::from_slice(serialized).map_err(|e| CodecError::Decode(e.to_string()))
+            }
+            Format::MessagePack => decode_msgpack(serialized),
+            Format::Cbor => decode_cbor(serialized),
+            Format::Postcard => decode_postcard(serialized),
+        }
+    }
+}
+
+#[cfg(feature = "codec-msgpack")]
+fn encode_msgpack<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+    rmp_serde::to_vec_named(value).map_err(|e| CodecError::Encode(e.to_string()))
+}
+
+#[cfg(feature = "codec-msgpack")]
+fn decode_msgpack<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CodecError> {
+    rmp_serde::from_slice(payload).map_err(|e| CodecError::Decode(e.to_string()))
+}
+
+#[cfg(not(feature = "codec-msgpack"))]
+fn encode_msgpack<T: Serialize + ?Sized>(_value: &T) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::Unavailable("msgpack".to_string()))
+}
+
+#[cfg(not(feature = "codec-msgpack"))]
+fn decode_msgpack<T: DeserializeOwned>(_payload: &[u8]) -> Result<T, CodecError> {
+    Err(CodecError::Unavailable("msgpack".to_string()))
+}
+
+#[cfg(feature = "codec-cbor")]
+fn encode_cbor<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+    let mut serialized = Vec::new();
+    ciborium::into_writer(value, &mut serialized).map_err(|e| CodecError::Encode(e.to_string()))?;
+    Ok(serialized)
+}
+
+#[cfg(feature = "codec-cbor")]
+fn decode_cbor<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CodecError> {
+    ciborium::from_reader(payload).map_err(|e| CodecError::Decode(e.to_string()))
+}
+
+#[cfg(not(feature = "codec-cbor"))]
+fn encode_cbor<T: Serialize + ?Sized>(_value: &T) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::Unavailable("cbor".to_string()))
+}
+
+#[cfg(not(feature = "codec-cbor"))]
+fn decode_cbor<T: DeserializeOwned>(_payload: &[u8]) -> Result<T, CodecError> {
+    Err(CodecError::Unavailable("cbor".to_string()))
+}
+
+#[cfg(feature = "codec-postcard")]
+fn encode_postcard<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CodecError> {
+    postcard::to_stdvec(value).map_err(|e| CodecError::Encode(e.to_string()))
+}
+
+#[cfg(feature = "codec-postcard")]
+fn decode_postcard<T: DeserializeOwned>(payload: &[u8]) -> Result<T, CodecError> {
+    postcard::from_bytes(payload).map_err(|e| CodecError::Decode(e.to_string()))
+}
+
+#[cfg(not(feature = "codec-postcard"))]
+fn encode_postcard<T: Serialize + ?Sized>(_value: &T) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::Unavailable("postcard".to_string()))
+}
+
+#[cfg(not(feature = "codec-postcard"))]
+fn decode_postcard<T: DeserializeOwned>(_payload: &[u8]) -> Result<T, CodecError> {
+    Err(CodecError::Unavailable("postcard".to_string()))
+}
+
+#[cfg(feature = "compression-lz4")]
+#[allow(clippy::unnecessary_wraps)]
+fn compress_lz4(serialized: &[u8]) -> Result<Vec<u8>, CodecError> {
+    Ok(lz4_flex::compress_prepend_size(serialized))
+}
+
+#[cfg(feature = "compression-lz4")]
+fn decompress_lz4(payload: &[u8]) -> Result<Vec<u8>, CodecError> {
+    lz4_flex::decompress_size_prepended(payload).map_err(|e| CodecError::Decode(e.to_string()))
+}
+
+#[cfg(not(feature = "compression-lz4"))]
+fn compress_lz4(_serialized: &[u8]) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::Unavailable("lz4".to_string()))
+}
+
+#[cfg(not(feature = "compression-lz4"))]
+fn decompress_lz4(_payload: &[u8]) -> Result<Vec<u8>, CodecError> {
+    Err(CodecError::Unavailable("lz4".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Board {
+        name: String,
+        cells: Vec<Option<u8>>,
+    }
+
+    fn board() -> Board {
+        Board {
+            name: "ttt".to_string(),
+            cells: vec![Some(1), None, Some(2)],
+        }
+    }
+
+    #[test]
+    fn content_type_round_trip() {
+        for codec in [
+            Codec::default(),
+            Codec::new(Format::MessagePack),
+            Codec::new(Format::Cbor).with_compression(Compression::Lz4),
+            Codec::new(Format::Postcard),
+        ] {
+            assert_eq!(
+                Codec::from_content_type(&codec.content_type()).expect("content type"),
+                codec
+            );
+        }
+
+        assert!(Codec::from_content_type("yaml").is_err());
+        assert!(Codec::from_content_type("json+gzip").is_err());
+    }
+
+    #[test]
+    fn json_is_plain() {
+        let codec = Codec::default();
+        let encoded = codec.encode(&board()).expect("encode");
+
+        assert_eq!(encoded, serde_json::to_vec(&board()).expect("json"));
+        assert_eq!(codec.decode::<Board>(&encoded).expect("decode"), board());
+    }
+
+    #[test]
+    fn every_codec_round_trip() {
+        let formats = [
+            (Format::MessagePack, cfg!(feature = "codec-msgpack")),
+            (Format::Cbor, cfg!(feature = "codec-cbor")),
+            (Format::Postcard, cfg!(feature = "codec-postcard")),
+        ];
+
+        for (format, enabled) in formats {
+            let codec = Codec::new(format);
+            match codec.encode(&board()) {
+                Ok(encoded) => {
+                    assert!(enabled);
+                    assert_eq!(codec.decode::<Board>(&encoded).expect("decode"), board());
+                }
+                Err(e) => {
+                    assert!(!enabled);
+                    assert!(matches!(e, CodecError::Unavailable(_)));
+                }
+            }
+        }
+
+        let compressed = Codec::default().with_compression(Compression::Lz4);
+        match compressed.encode(&board()) {
+            Ok(encoded) => assert_eq!(
+                compressed.decode::<Board>(&encoded).expect("decode"),
+                board()
+            ),
+            Err(e) => assert!(matches!(e, CodecError::Unavailable(_))),
+        }
+    }
+}
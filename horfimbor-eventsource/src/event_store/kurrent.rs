@@ -0,0 +1,250 @@
+//! `KurrentDB` implementation of the `EventStore`
+
+use async_trait::async_trait;
+use kurrentdb::{
+    AppendToStreamOptions, Client, Error as EventStoreError, NakAction, PersistentSubscription,
+    PersistentSubscriptionOptions, PersistentSubscriptionToAllOptions, Position, ReadStream,
+    ReadStreamOptions, RecordedEvent, ResolvedEvent, RetryOptions, StreamMetadata, StreamPosition,
+    StreamState, SubscribeToAllOptions, SubscribeToPersistentSubscriptionOptions,
+    SubscribeToStreamOptions, Subscription, SubscriptionFilter,
+};
+
+use crate::Stream;
+use crate::event_store::{
+    EventReader, EventStore, EventSubscription, PersistentEventSubscription, ReceivedEvent,
+    StoredEvent,
+};
+use crate::metadata::CompleteEvent;
+
+#[async_trait]
+impl EventStore for Client {
+    type Reader = ReadStream;
+    type Subscription = KurrentSubscription;
+    type PersistentSubscription = PersistentSubscription;
+
+    async fn append_to_stream(
+        &self,
+        stream_id: &str,
+        expected: StreamState,
+        events: Vec<CompleteEvent>,
+    ) -> Result<u64, EventStoreError> {
+        let events = events
+            .iter()
+            .map(CompleteEvent::full_event_data)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| EventStoreError::InternalParsingError(e.to_string()))?;
+
+        let options = AppendToStreamOptions::default().stream_state(expected);
+
+        let result = Self::append_to_stream(self, stream_id, &options, events).await?;
+
+        // the next expected version is the revision of the last appended event
+        Ok(result.next_expected_version)
+    }
+
+    async fn read_stream(
+        &self,
+        stream_id: &str,
+        from: StreamPosition<u64>,
+    ) -> Result<Self::Reader, EventStoreError> {
+        // the projected streams (`$ce-`, `$et-`, `bc-`) are made of links
+        let options = ReadStreamOptions::default()
+            .position(from)
+            .resolve_link_tos();
+
+        Self::read_stream(self, stream_id, &options).await
+    }
+
+    async fn read_last_event(
+        &self,
+        stream_id: &str,
+    ) -> Result<Option<StoredEvent>, EventStoreError> {
+        let options = ReadStreamOptions::default()
+            .backwards()
+            .position(StreamPosition::End)
+            .max_count(1);
+
+        let mut stream = Self::read_stream(self, stream_id, &options).await?;
+
+        match stream.next().await {
+            Ok(event) => Ok(event.map(|e| e.get_original_event().into())),
+            Err(EventStoreError::ResourceNotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_max_count(&self, stream_id: &str, max_count: u64) -> Result<(), EventStoreError> {
+        let metadata = StreamMetadata::builder().max_count(max_count).build();
+
+        Self::set_stream_metadata(
+            self,
+            stream_id,
+            &AppendToStreamOptions::default(),
+            &metadata,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn subscribe_to_stream(
+        &self,
+        stream: &Stream,
+        from: StreamPosition<u64>,
+    ) -> Self::Subscription {
+        if matches!(stream, Stream::All) {
+            let options = SubscribeToAllOptions::default()
+                .retry_options(RetryOptions::default())
+                .resolve_link_tos()
+                .filter(SubscriptionFilter::on_event_type().exclude_system_events())
+                .position(all_position(from));
+
+            return KurrentSubscription {
+                subscription: Self::subscribe_to_all(self, &options).await,
+                all: true,
+            };
+        }
+
+        // the linked events of the projected streams, like the persistent subscriptions
+        let options = SubscribeToStreamOptions::default()
+            .retry_options(RetryOptions::default())
+            .resolve_link_tos()
+            .start_from(from);
+
+        KurrentSubscription {
+            subscription: Self::subscribe_to_stream(self, stream.to_string(), &options).await,
+            all: false,
+        }
+    }
+
+    async fn create_persistent_subscription(
+        &self,
+        stream: &Stream,
+        group_name: &str,
+    ) -> Result<(), EventStoreError> {
+        if matches!(stream, Stream::All) {
+            let options = PersistentSubscriptionToAllOptions::default()
+                .resolve_link_tos(true)
+                .filter(SubscriptionFilter::on_event_type().exclude_system_events());
+
+            return Self::create_persistent_subscription_to_all(self, group_name, &options).await;
+        }
+
+        let options = PersistentSubscriptionOptions::default().resolve_link_tos(true);
+
+        Self::create_persistent_subscription(self, stream.to_string(), group_name, &options).await
+    }
+
+    async fn subscribe_to_persistent_subscription(
+        &self,
+        stream: &Stream,
+        group_name: &str,
+    ) -> Result<Self::PersistentSubscription, EventStoreError> {
+        let options = SubscribeToPersistentSubscriptionOptions::default().buffer_size(1);
+
+        if matches!(stream, Stream::All) {
+            return Self::subscribe_to_persistent_subscription_to_all(self, group_name, &options)
+                .await;
+        }
+
+        Self::subscribe_to_persistent_subscription(self, stream.to_string(), group_name, &options)
+            .await
+    }
+}
+
+#[async_trait]
+impl EventReader for ReadStream {
+    async fn next(&mut self) -> Result<Option<StoredEvent>, EventStoreError> {
+        let event = Self::next(self).await?;
+
+        Ok(event.map(|e| {
+            let link = e.get_original_event();
+            e.event.as_ref().map_or_else(
+                || link.into(),
+                |event| StoredEvent::from(event).with_position(link.revision),
+            )
+        }))
+    }
+}
+
+/// temporary subscription, `$all` is positioned by commit position
+pub struct KurrentSubscription {
+    subscription: Subscription,
+    all: bool,
+}
+
+#[async_trait]
+impl EventSubscription for KurrentSubscription {
+    async fn next(&mut self) -> Result<StoredEvent, EventStoreError> {
+        loop {
+            let resolved = self.subscription.next().await?;
+
+            if let Some(event) = resolved.event.as_ref() {
+                let position = if self.all {
+                    resolved.commit_position.unwrap_or(event.position.commit)
+                } else {
+                    resolved.get_original_event().revision
+                };
+
+                return Ok(StoredEvent::from(event).with_position(position));
+            }
+        }
+    }
+}
+
+/// the positions of `$all` are commit positions
+const fn all_position(from: StreamPosition<u64>) -> StreamPosition<Position> {
+    match from {
+        StreamPosition::Start => StreamPosition::Start,
+        StreamPosition::End => StreamPosition::End,
+        StreamPosition::Position(commit) => StreamPosition::Position(Position {
+            commit,
+            prepare: commit,
+        }),
+    }
+}
+
+#[async_trait]
+impl PersistentEventSubscription for PersistentSubscription {
+    async fn next(&mut self) -> Result<ReceivedEvent, EventStoreError> {
+        let resolved = Self::next(self).await?;
+
+        Ok(resolved.into())
+    }
+
+    async fn ack(&mut self, event: &ReceivedEvent) -> Result<(), EventStoreError> {
+        self.ack_ids(vec![event.ack_id()]).await
+    }
+
+    async fn nack(
+        &mut self,
+        event: &ReceivedEvent,
+        action: NakAction,
+        reason: &str,
+    ) -> Result<(), EventStoreError> {
+        self.nack_ids(vec![event.ack_id()], action, reason).await
+    }
+}
+
+impl From<&RecordedEvent> for StoredEvent {
+    fn from(event: &RecordedEvent) -> Self {
+        Self::new(
+            event.stream_id().to_string(),
+            event.id,
+            event.revision,
+            event.event_type.clone(),
+            event.data.to_vec(),
+            event.custom_metadata.to_vec(),
+            event.created,
+        )
+    }
+}
+
+impl From<ResolvedEvent> for ReceivedEvent {
+    fn from(resolved: ResolvedEvent) -> Self {
+        Self::new(
+            resolved.get_original_event().id,
+            resolved.event.as_ref().map(StoredEvent::from),
+        )
+    }
+}
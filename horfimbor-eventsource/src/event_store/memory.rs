@@ -0,0 +1,435 @@
+//! In memory implementation of the `EventStore`
+//!
+//! it emulates the standard projections of `KurrentDB` :
+//! `$ce-` (by category), `$et-` (by event type), `bc-` (by correlation id) and `$all`,
+//! the `$maxCount` of a stream is applied when it is read.
+//! Everything is lost when the last clone is dropped,
+//! not recommended for production usage.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use kurrentdb::{
+    CurrentRevision, Error as EventStoreError, NakAction, StreamPosition, StreamState,
+};
+use tokio::sync::watch;
+use uuid::Uuid;
+
+use crate::Stream;
+use crate::event_store::{
+    EventReader, EventStore, EventSubscription, PersistentEventSubscription, ReceivedEvent,
+    StoredEvent,
+};
+use crate::metadata::{CompleteEvent, Metadata};
+
+/// The `InMemoryEventStore` can be cloned, all the clones share the same events
+#[derive(Clone)]
+pub struct InMemoryEventStore {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    inner: Mutex<Inner>,
+    // bumped every time something new can be delivered to a subscription
+    version: watch::Sender<u64>,
+}
+
+#[derive(Default)]
+struct Inner {
+    streams: HashMap<String, Vec<StoredEvent>>,
+    all: Vec<StoredEvent>,
+    groups: HashMap<(String, String), Group>,
+    max_counts: HashMap<String, u64>,
+}
+
+#[derive(Default)]
+struct Group {
+    position: usize,
+    retry: VecDeque<StoredEvent>,
+    in_flight: HashMap<Uuid, StoredEvent>,
+    parked: Vec<StoredEvent>,
+}
+
+impl InMemoryEventStore {
+    /// create an empty store
+    #[must_use]
+    pub fn new() -> Self {
+        let (version, _) = watch::channel(0);
+        Self {
+            shared: Arc::new(Shared {
+                inner: Mutex::new(Inner::default()),
+                version,
+            }),
+        }
+    }
+
+    /// the events a group parked with `NakAction::Park`
+    #[must_use]
+    pub fn parked_events(&self, stream: &Stream, group_name: &str) -> Vec<StoredEvent> {
+        self.lock()
+            .groups
+            .get(&(stream.to_string(), group_name.to_string()))
+            .map(|group| group.parked.clone())
+            .unwrap_or_default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.shared
+            .inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn notify(&self) {
+        self.shared.version.send_modify(|v| *v += 1);
+    }
+}
+
+impl Default for InMemoryEventStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Inner {
+    /// the content of a stream, projected streams are computed from the `$all` log
+    fn stream_events(&self, stream_id: &str) -> Vec<StoredEvent> {
+        if stream_id == "$all" {
+            return self.all.clone();
+        }
+
+        let filter: Box<dyn Fn(&StoredEvent) -> bool> =
+            if let Some(category) = stream_id.strip_prefix("$ce-") {
+                let category = category.to_string();
+                Box::new(move |e| e.stream_id().split('-').next() == Some(category.as_str()))
+            } else if let Some(event_type) = stream_id.strip_prefix("$et-") {
+                let event_type = event_type.to_string();
+                Box::new(move |e| e.event_type() == event_type)
+            } else if let Some(correlation_id) = stream_id.strip_prefix("bc-") {
+                let correlation_id = correlation_id.to_string();
+                Box::new(move |e| {
+                    e.metadata()
+                        .is_ok_and(|m| m.correlation_id().to_string() == correlation_id)
+                })
+            } else {
+                return self.streams.get(stream_id).cloned().unwrap_or_default();
+            };
+
+        self.all.iter().filter(|e| filter(e)).cloned().collect()
+    }
+}
+
+#[async_trait]
+impl EventStore for InMemoryEventStore {
+    type Reader = InMemoryReader;
+    type Subscription = InMemorySubscription;
+    type PersistentSubscription = InMemoryPersistentSubscription;
+
+    async fn append_to_stream(
+        &self,
+        stream_id: &str,
+        expected: StreamState,
+        events: Vec<CompleteEvent>,
+    ) -> Result<u64, EventStoreError> {
+        let mut stored = Vec::with_capacity(events.len());
+        for event in &events {
+            let metadata = serde_json::to_vec::<Metadata>(event.metadata())
+                .map_err(|e| EventStoreError::InternalParsingError(e.to_string()))?;
+            stored.push((event, metadata));
+        }
+
+        let mut inner = self.lock();
+
+        let stream = inner.streams.entry(stream_id.to_string()).or_default();
+        let current = stream.len().checked_sub(1).map(|r| r as u64);
+
+        let allowed = match expected {
+            StreamState::Any => true,
+            StreamState::StreamExists => current.is_some(),
+            StreamState::NoStream => current.is_none(),
+            StreamState::StreamRevision(revision) => current == Some(revision),
+        };
+
+        if !allowed {
+            return Err(EventStoreError::WrongExpectedVersion {
+                expected,
+                current: current.map_or(CurrentRevision::NoStream, CurrentRevision::Current),
+            });
+        }
+
+        let created = Utc::now();
+        let mut revision = stream.len() as u64;
+        let mut appended = Vec::with_capacity(stored.len());
+        for (event, metadata) in stored {
+            appended.push(StoredEvent::new(
+                stream_id.to_string(),
+                event.metadata().id().unwrap_or_else(Uuid::now_v7),
+                revision,
+                event.event_type().to_string(),
+                event.data().to_vec(),
+                metadata,
+                created,
+            ));
+            revision += 1;
+        }
+
+        stream.extend(appended.iter().cloned());
+        inner.all.extend(appended);
+        drop(inner);
+
+        self.notify();
+
+        Ok(revision.saturating_sub(1))
+    }
+
+    async fn read_stream(
+        &self,
+        stream_id: &str,
+        from: StreamPosition<u64>,
+    ) -> Result<Self::Reader, EventStoreError> {
+        let inner = self.lock();
+        let events = inner.stream_events(stream_id);
+        // the revisions before the `$maxCount` last events cannot be read anymore
+        let truncated = inner.max_counts.get(stream_id).map_or(0, |max_count| {
+            (events.len() as u64).saturating_sub(*max_count)
+        });
+        drop(inner);
+        // like KurrentDB, reading a stream without any event fail on the first `next`
+        let missing = events.is_empty();
+
+        let skip = match from {
+            StreamPosition::Start => 0,
+            StreamPosition::End => events.len(),
+            StreamPosition::Position(position) => usize::try_from(position).unwrap_or(usize::MAX),
+        };
+
+        Ok(InMemoryReader {
+            events: events
+                .into_iter()
+                .skip(skip)
+                .filter(|e| e.revision() >= truncated)
+                .collect(),
+            missing,
+        })
+    }
+
+    async fn read_last_event(
+        &self,
+        stream_id: &str,
+    ) -> Result<Option<StoredEvent>, EventStoreError> {
+        Ok(self.lock().stream_events(stream_id).pop())
+    }
+
+    async fn set_max_count(&self, stream_id: &str, max_count: u64) -> Result<(), EventStoreError> {
+        self.lock()
+            .max_counts
+            .insert(stream_id.to_string(), max_count);
+
+        Ok(())
+    }
+
+    async fn subscribe_to_stream(
+        &self,
+        stream: &Stream,
+        from: StreamPosition<u64>,
+    ) -> Self::Subscription {
+        let next = match from {
+            StreamPosition::Start => 0,
+            StreamPosition::End => self.lock().stream_events(&stream.to_string()).len(),
+            // the subscription start after the position
+            StreamPosition::Position(position) => {
+                usize::try_from(position).map_or(usize::MAX, |p| p.saturating_add(1))
+            }
+        };
+
+        InMemorySubscription {
+            store: self.clone(),
+            stream_id: stream.to_string(),
+            next,
+            version: self.shared.version.subscribe(),
+        }
+    }
+
+    async fn create_persistent_subscription(
+        &self,
+        stream: &Stream,
+        group_name: &str,
+    ) -> Result<(), EventStoreError> {
+        let mut inner = self.lock();
+
+        let key = (stream.to_string(), group_name.to_string());
+        if inner.groups.contains_key(&key) {
+            return Err(EventStoreError::ResourceAlreadyExists);
+        }
+
+        // like the default settings of `KurrentDB`, the group start at the end of the stream
+        let position = inner.stream_events(&key.0).len();
+        inner.groups.insert(
+            key,
+            Group {
+                position,
+                ..Group::default()
+            },
+        );
+        drop(inner);
+
+        Ok(())
+    }
+
+    async fn subscribe_to_persistent_subscription(
+        &self,
+        stream: &Stream,
+        group_name: &str,
+    ) -> Result<Self::PersistentSubscription, EventStoreError> {
+        let key = (stream.to_string(), group_name.to_string());
+
+        if !self.lock().groups.contains_key(&key) {
+            return Err(EventStoreError::ResourceNotFound);
+        }
+
+        Ok(InMemoryPersistentSubscription {
+            store: self.clone(),
+            key,
+            version: self.shared.version.subscribe(),
+        })
+    }
+}
+
+/// reader over a copy of the stream taken when `read_stream` was called
+pub struct InMemoryReader {
+    events: VecDeque<StoredEvent>,
+    missing: bool,
+}
+
+#[async_trait]
+impl EventReader for InMemoryReader {
+    async fn next(&mut self) -> Result<Option<StoredEvent>, EventStoreError> {
+        if self.missing {
+            return Err(EventStoreError::ResourceNotFound);
+        }
+
+        Ok(self.events.pop_front())
+    }
+}
+
+/// temporary subscription, nothing is kept once dropped
+pub struct InMemorySubscription {
+    store: InMemoryEventStore,
+    stream_id: String,
+    next: usize,
+    version: watch::Receiver<u64>,
+}
+
+#[async_trait]
+impl EventSubscription for InMemorySubscription {
+    async fn next(&mut self) -> Result<StoredEvent, EventStoreError> {
+        loop {
+            self.version.borrow_and_update();
+
+            let event = self
+                .store
+                .lock()
+                .stream_events(&self.stream_id)
+                .into_iter()
+                .nth(self.next);
+
+            if let Some(event) = event {
+                let position = self.next as u64;
+                self.next += 1;
+                return Ok(event.with_position(position));
+            }
+
+            self.version
+                .changed()
+                .await
+                .map_err(|_| EventStoreError::ConnectionClosed)?;
+        }
+    }
+}
+
+/// subscription to a group, the clones of the store share the groups
+pub struct InMemoryPersistentSubscription {
+    store: InMemoryEventStore,
+    key: (String, String),
+    version: watch::Receiver<u64>,
+}
+
+#[async_trait]
+impl PersistentEventSubscription for InMemoryPersistentSubscription {
+    async fn next(&mut self) -> Result<ReceivedEvent, EventStoreError> {
+        loop {
+            self.version.borrow_and_update();
+
+            {
+                let mut inner = self.store.lock();
+                let events = inner.stream_events(&self.key.0);
+
+                let Some(group) = inner.groups.get_mut(&self.key) else {
+                    return Err(EventStoreError::ResourceNotFound);
+                };
+
+                let event = group.retry.pop_front().or_else(|| {
+                    let event = events.into_iter().nth(group.position);
+                    if event.is_some() {
+                        group.position += 1;
+                    }
+                    event
+                });
+
+                if let Some(event) = event {
+                    group.in_flight.insert(event.id(), event.clone());
+                    drop(inner);
+
+                    return Ok(ReceivedEvent::new(event.id(), Some(event)));
+                }
+            }
+
+            self.version
+                .changed()
+                .await
+                .map_err(|_| EventStoreError::ConnectionClosed)?;
+        }
+    }
+
+    async fn ack(&mut self, event: &ReceivedEvent) -> Result<(), EventStoreError> {
+        let mut inner = self.store.lock();
+
+        let Some(group) = inner.groups.get_mut(&self.key) else {
+            return Err(EventStoreError::ResourceNotFound);
+        };
+        group.in_flight.remove(&event.ack_id());
+        drop(inner);
+
+        Ok(())
+    }
+
+    async fn nack(
+        &mut self,
+        event: &ReceivedEvent,
+        action: NakAction,
+        _reason: &str,
+    ) -> Result<(), EventStoreError> {
+        let mut inner = self.store.lock();
+
+        let Some(group) = inner.groups.get_mut(&self.key) else {
+            return Err(EventStoreError::ResourceNotFound);
+        };
+
+        let Some(nacked) = group.in_flight.remove(&event.ack_id()) else {
+            return Ok(());
+        };
+
+        match action {
+            NakAction::Park => group.parked.push(nacked),
+            NakAction::Unknown | NakAction::Retry => group.retry.push_back(nacked),
+            NakAction::Skip | NakAction::Stop => {}
+        }
+        drop(inner);
+
+        self.store.notify();
+
+        Ok(())
+    }
+}
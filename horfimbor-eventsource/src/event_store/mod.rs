@@ -0,0 +1,320 @@
+//! abstraction over the event storage
+//!
+//! the repositories only need a few operations from the event store :
+//! append with an expected revision, read a stream from a position and subscribe.
+//! `kurrentdb::Client` is the production implementation,
+//! `memory::InMemoryEventStore` allow to run everything without any outside service.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use kurrentdb::{Error as EventStoreError, NakAction, StreamPosition, StreamState};
+use serde::Deserialize;
+use serde::de::{DeserializeOwned, Error as _};
+use serde_json::Error as SerdeError;
+use uuid::Uuid;
+
+use crate::Stream;
+use crate::codec::Codec;
+use crate::metadata::{CompleteEvent, Metadata};
+
+pub mod kurrent;
+pub mod memory;
+
+/// `EventStore` is everything the repositories need from the event storage
+#[async_trait]
+pub trait EventStore: Clone + Send + Sync {
+    /// the reader returned by `read_stream`
+    type Reader: EventReader;
+
+    /// the temporary subscription returned by `subscribe_to_stream`
+    type Subscription: EventSubscription;
+
+    /// the subscription returned by `subscribe_to_persistent_subscription`
+    type PersistentSubscription: PersistentEventSubscription;
+
+    /// append the events at the end of the stream and return the new revision of the stream
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(WrongExpectedVersion)` if the stream does not match `expected`
+    /// or any other `Err` if the store cannot be reached.
+    async fn append_to_stream(
+        &self,
+        stream_id: &str,
+        expected: StreamState,
+        events: Vec<CompleteEvent>,
+    ) -> Result<u64, EventStoreError>;
+
+    /// read the stream forward, starting at the position (included)
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached.
+    async fn read_stream(
+        &self,
+        stream_id: &str,
+        from: StreamPosition<u64>,
+    ) -> Result<Self::Reader, EventStoreError>;
+
+    /// the last event of the stream, `None` if the stream does not exist
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached.
+    async fn read_last_event(
+        &self,
+        stream_id: &str,
+    ) -> Result<Option<StoredEvent>, EventStoreError>;
+
+    /// set `$maxCount` on the stream : only its last `max_count` events can be read
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached.
+    async fn set_max_count(&self, stream_id: &str, max_count: u64) -> Result<(), EventStoreError>;
+
+    /// create a temporary subscription starting after the position,
+    /// links are always resolved.
+    async fn subscribe_to_stream(
+        &self,
+        stream: &Stream,
+        from: StreamPosition<u64>,
+    ) -> Self::Subscription;
+
+    /// create a persistent subscription for the group, starting at the end of the stream
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err(ResourceAlreadyExists)` if the group already exists.
+    async fn create_persistent_subscription(
+        &self,
+        stream: &Stream,
+        group_name: &str,
+    ) -> Result<(), EventStoreError>;
+
+    /// connect to an existing persistent subscription
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the subscription does not exist or the store cannot be reached.
+    async fn subscribe_to_persistent_subscription(
+        &self,
+        stream: &Stream,
+        group_name: &str,
+    ) -> Result<Self::PersistentSubscription, EventStoreError>;
+}
+
+/// `EventReader` give the events of a stream one by one
+#[async_trait]
+pub trait EventReader: Send {
+    /// `None` when the end of the stream is reached
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached or the stream does not exist.
+    async fn next(&mut self) -> Result<Option<StoredEvent>, EventStoreError>;
+}
+
+/// `EventSubscription` wait for the next event of the stream
+#[async_trait]
+pub trait EventSubscription: Send {
+    /// wait until the next event is available
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the subscription is dropped.
+    async fn next(&mut self) -> Result<StoredEvent, EventStoreError>;
+}
+
+/// `PersistentEventSubscription` deliver the events of a group,
+/// each event must be acked or nacked once processed.
+#[async_trait]
+pub trait PersistentEventSubscription: Send {
+    /// wait until the next event is available
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the subscription is dropped.
+    async fn next(&mut self) -> Result<ReceivedEvent, EventStoreError>;
+
+    /// the event is processed and will not be delivered again
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the subscription is dropped.
+    async fn ack(&mut self, event: &ReceivedEvent) -> Result<(), EventStoreError>;
+
+    /// the event cannot be processed, the action tell the store what to do with it
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the subscription is dropped.
+    async fn nack(
+        &mut self,
+        event: &ReceivedEvent,
+        action: NakAction,
+        reason: &str,
+    ) -> Result<(), EventStoreError>;
+}
+
+/// `StoredEvent` is an event (or a command) as it was written in the store
+#[derive(Clone, Debug)]
+pub struct StoredEvent {
+    stream_id: String,
+    id: Uuid,
+    revision: u64,
+    event_type: String,
+    data: Vec<u8>,
+    custom_metadata: Vec<u8>,
+    created: DateTime<Utc>,
+    position: u64,
+}
+
+impl StoredEvent {
+    /// straight forward constructor, the position is the revision
+    #[must_use]
+    pub const fn new(
+        stream_id: String,
+        id: Uuid,
+        revision: u64,
+        event_type: String,
+        data: Vec<u8>,
+        custom_metadata: Vec<u8>,
+        created: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            stream_id,
+            id,
+            revision,
+            event_type,
+            data,
+            custom_metadata,
+            created,
+            position: revision,
+        }
+    }
+
+    /// the position of the event in the subscribed stream
+    #[must_use]
+    pub const fn with_position(mut self, position: u64) -> Self {
+        self.position = position;
+        self
+    }
+
+    /// the stream the event was written to, it can be parsed as a `ModelKey`
+    #[must_use]
+    pub fn stream_id(&self) -> &str {
+        &self.stream_id
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// the position of the event in its own stream
+    #[must_use]
+    pub const fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// the position in the subscribed stream, to start a subscription after it :
+    /// the revision of the link in a projected stream, the commit position in `$all`
+    #[must_use]
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// the `EventName` or the `CommandName`
+    #[must_use]
+    pub fn event_type(&self) -> &str {
+        &self.event_type
+    }
+
+    /// raw payload
+    #[must_use]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// raw `Metadata`
+    #[must_use]
+    pub fn custom_metadata(&self) -> &[u8] {
+        &self.custom_metadata
+    }
+
+    /// the date the store received the event
+    #[must_use]
+    pub const fn created(&self) -> DateTime<Utc> {
+        self.created
+    }
+
+    /// decode the payload as json, the events written with another `Codec` must be read with `decode`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the payload does not match `T`
+    pub fn as_json<'a, T>(&'a self) -> Result<T, SerdeError>
+    where
+        T: Deserialize<'a>,
+    {
+        serde_json::from_slice(&self.data)
+    }
+
+    /// decode the payload with the `Codec` recorded in its `Metadata`, json when there is none
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the codec is unknown or not enabled, or the payload does not match `T`
+    pub fn decode<T>(&self, metadata: &Metadata) -> Result<T, SerdeError>
+    where
+        T: DeserializeOwned,
+    {
+        let Some(content_type) = metadata.codec() else {
+            return serde_json::from_slice(&self.data);
+        };
+
+        Codec::from_content_type(content_type)
+            .and_then(|codec| codec.decode(&self.data))
+            .map_err(SerdeError::custom)
+    }
+
+    /// decode the `Metadata`, the id is set from the stored event
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the metadata were not written by this library
+    pub fn metadata(&self) -> Result<Metadata, SerdeError> {
+        let mut metadata: Metadata = serde_json::from_slice(&self.custom_metadata)?;
+        metadata.set_id(Some(self.id));
+        Ok(metadata)
+    }
+}
+
+/// `ReceivedEvent` is an event delivered by a persistent subscription
+#[derive(Clone, Debug)]
+pub struct ReceivedEvent {
+    ack_id: Uuid,
+    event: Option<StoredEvent>,
+}
+
+impl ReceivedEvent {
+    /// `ack_id` is the id of the link when the event is resolved from a projection
+    #[must_use]
+    pub const fn new(ack_id: Uuid, event: Option<StoredEvent>) -> Self {
+        Self { ack_id, event }
+    }
+
+    /// the id to use to ack or nack the event
+    #[must_use]
+    pub const fn ack_id(&self) -> Uuid {
+        self.ack_id
+    }
+
+    /// `None` when the link point to a deleted event
+    #[must_use]
+    pub const fn event(&self) -> Option<&StoredEvent> {
+        self.event.as_ref()
+    }
+}
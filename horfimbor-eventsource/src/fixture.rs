@@ -0,0 +1,198 @@
+//! Given / When / Then fixture to test a `State` without any repository
+//!
+//! the events and the command go through the same json round-trip as in the event store,
+//! so a payload that cannot be read back fail the test too.
+
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+use crate::metadata::{CompleteEvent, Metadata};
+use crate::{AsyncState, Event, State};
+
+/// `StateFixture` hold the state built from the given events
+pub struct StateFixture<S>
+where
+    S: State,
+{
+    state: S,
+    metadata: Metadata,
+}
+
+/// `FixtureResult` is the outcome of the command, checked with `then_*`
+pub struct FixtureResult<S>
+where
+    S: State,
+{
+    state: S,
+    result: Result<Vec<S::Event>, S::Error>,
+}
+
+impl<S> StateFixture<S>
+where
+    S: State,
+{
+    /// the events are played on the default state
+    ///
+    /// # Panics
+    ///
+    /// Will panic if an event cannot be serialized and deserialized back
+    #[must_use]
+    pub fn given<I>(events: I) -> Self
+    where
+        I: IntoIterator<Item = S::Event>,
+    {
+        let id = Uuid::now_v7();
+        let mut fixture = Self {
+            state: S::default(),
+            metadata: Metadata::new(Some(id), id, id, false),
+        };
+
+        for event in events {
+            let event = fixture.round_trip_event(&event);
+            fixture.state.play_event(&event);
+        }
+
+        fixture
+    }
+
+    /// the command is tried on the current state
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the command or the produced events cannot be serialized and deserialized back
+    #[must_use]
+    // the command played is the one read back from its json
+    #[allow(clippy::needless_pass_by_value)]
+    pub fn when(mut self, command: S::Command) -> FixtureResult<S> {
+        let command = self.round_trip_command(&command);
+
+        let result = self.state.try_command(command);
+
+        self.into_result(result)
+    }
+
+    /// same as `when` for an `AsyncState`, with the context given to the command
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the command or the produced events cannot be serialized and deserialized back
+    #[allow(clippy::needless_pass_by_value)]
+    pub async fn when_with_context(
+        mut self,
+        command: S::Command,
+        context: &S::Context,
+    ) -> FixtureResult<S>
+    where
+        S: AsyncState,
+    {
+        let command = self.round_trip_command(&command);
+
+        let result = self.state.try_command_with_context(command, context).await;
+
+        self.into_result(result)
+    }
+
+    fn round_trip_command(&mut self, command: &S::Command) -> S::Command {
+        let complete = CompleteEvent::from_command(command, Some(&self.metadata))
+            .unwrap_or_else(|e| panic!("command {command:?} cannot be serialized : {e}"));
+        complete.metadata().clone_into(&mut self.metadata);
+
+        round_trip(&complete)
+    }
+
+    fn into_result(mut self, result: Result<Vec<S::Event>, S::Error>) -> FixtureResult<S> {
+        let result = result.map(|events| {
+            events
+                .iter()
+                .map(|event| self.round_trip_event(event))
+                .collect()
+        });
+
+        FixtureResult {
+            state: self.state,
+            result,
+        }
+    }
+
+    fn round_trip_event(&mut self, event: &S::Event) -> S::Event {
+        let complete = CompleteEvent::from_event(event, &self.metadata)
+            .unwrap_or_else(|e| panic!("event {event:?} cannot be serialized : {e}"));
+        complete.metadata().clone_into(&mut self.metadata);
+
+        round_trip(&complete)
+    }
+}
+
+impl<S> FixtureResult<S>
+where
+    S: State,
+{
+    /// the command must succeed with exactly these events,
+    /// the state with the events played is returned for further checks
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the command failed or produced other events
+    pub fn then_expect<I>(self, expected: I) -> S
+    where
+        I: IntoIterator<Item = S::Event>,
+    {
+        let events = match self.result {
+            Ok(events) => events,
+            Err(e) => panic!("the command failed : {e}"),
+        };
+        let expected: Vec<S::Event> = expected.into_iter().collect();
+
+        assert!(
+            events.len() == expected.len()
+                && events
+                    .iter()
+                    .zip(&expected)
+                    .all(|(event, expected)| same_event(event, expected)),
+            "expected events {expected:?}, got {events:?}"
+        );
+
+        let mut state = self.state;
+        for event in &events {
+            state.play_event(event);
+        }
+        state
+    }
+
+    /// the command must fail with an error accepted by the matcher
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the command succeed or the matcher reject the error
+    pub fn then_error<F>(self, matcher: F)
+    where
+        F: FnOnce(&S::Error) -> bool,
+    {
+        match self.result {
+            Ok(events) => panic!("expected an error, got the events {events:?}"),
+            Err(e) => assert!(matcher(&e), "unexpected error : {e:?}"),
+        }
+    }
+}
+
+/// decode the payload the same way the repositories read it
+fn round_trip<T>(complete: &CompleteEvent) -> T
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_slice(complete.data()).unwrap_or_else(|e| {
+        panic!(
+            "{} cannot be deserialized from its own json : {e}",
+            complete.event_type()
+        )
+    })
+}
+
+/// events are not `PartialEq`, they are compared by name and payload
+fn same_event<E>(event: &E, expected: &E) -> bool
+where
+    E: Event,
+{
+    event.event_name() == expected.event_name()
+        && serde_json::to_value(event).ok() == serde_json::to_value(expected).ok()
+}
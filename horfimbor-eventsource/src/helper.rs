@@ -1,25 +1,25 @@
 //! helper to create subscription
 
-use crate::Stream;
+use crate::event_store::EventStore;
+use crate::subscription::{TypedPersistentSubscription, TypedSubscription};
+use crate::{Event, Stream};
 use kurrentdb::Error as EventStoreError;
-use kurrentdb::{
-    Client as EventDb, Error, PersistentSubscription, PersistentSubscriptionOptions, RetryOptions,
-    StreamPosition, SubscribeToPersistentSubscriptionOptions, SubscribeToStreamOptions,
-    Subscription,
-};
+use kurrentdb::{Error, StreamPosition};
+use serde::de::DeserializeOwned;
 
 /// # Errors
 ///
 /// Will return an `Err` if the subscription cannot be created but not when it already exists.
-pub async fn create_subscription(
-    event_db: &EventDb,
+pub async fn create_subscription<E>(
+    event_db: &E,
     stream: &Stream,
     group_name: &str,
-) -> Result<(), EventStoreError> {
-    let opt = PersistentSubscriptionOptions::default().resolve_link_tos(true);
-
+) -> Result<(), EventStoreError>
+where
+    E: EventStore,
+{
     let created = event_db
-        .create_persistent_subscription(stream.to_string(), group_name, &opt)
+        .create_persistent_subscription(stream, group_name)
         .await;
 
     match created {
@@ -35,21 +35,21 @@ pub async fn create_subscription(
 
 /// create a temporary subscription
 /// the typical usage is to create a websocket.
-pub async fn get_subscription(
-    event_db: &EventDb,
+///
+/// the links of the projected streams (`$ce-`, `$et-`, `bc-`) are resolved :
+/// the subscription deliver the linked events, not the link records,
+/// and a link to a deleted event is skipped.
+pub async fn get_subscription<E>(
+    event_db: &E,
     stream: &Stream,
     position: Option<u64>,
-) -> Subscription {
-    let mut options = SubscribeToStreamOptions::default().retry_options(RetryOptions::default());
-
-    options = match position {
-        None => options.start_from(StreamPosition::Start),
-        Some(n) => options.start_from(StreamPosition::Position(n)),
-    };
+) -> E::Subscription
+where
+    E: EventStore,
+{
+    let from = position.map_or(StreamPosition::Start, StreamPosition::Position);
 
-    event_db
-        .subscribe_to_stream(stream.to_string(), &options)
-        .await
+    event_db.subscribe_to_stream(stream, from).await
 }
 
 /// create a persistent subscription,
@@ -61,16 +61,57 @@ pub async fn get_subscription(
 /// # Errors
 ///
 /// Will return `Err` if the subscription cannot be created.
-pub async fn get_persistent_subscription(
-    event_db: &EventDb,
+pub async fn get_persistent_subscription<E>(
+    event_db: &E,
     stream: &Stream,
     group_name: &str,
-) -> Result<PersistentSubscription, EventStoreError> {
+) -> Result<E::PersistentSubscription, EventStoreError>
+where
+    E: EventStore,
+{
     create_subscription(event_db, stream, group_name).await?;
 
-    let options = SubscribeToPersistentSubscriptionOptions::default().buffer_size(1);
-
     event_db
-        .subscribe_to_persistent_subscription(stream.to_string(), group_name, &options)
+        .subscribe_to_persistent_subscription(stream, group_name)
         .await
 }
+
+/// create a temporary subscription decoding the events as `Ev`,
+/// the `Upcasters` and the accepted event names are set on the returned `TypedSubscription`
+pub async fn get_typed_subscription<E, Ev>(
+    event_db: &E,
+    stream: &Stream,
+    position: Option<u64>,
+) -> TypedSubscription<E::Subscription, Ev>
+where
+    E: EventStore,
+    E::Subscription: 'static,
+    Ev: Event + DeserializeOwned + 'static,
+{
+    TypedSubscription::new(get_subscription(event_db, stream, position).await)
+}
+
+/// create a persistent subscription decoding the events as `Ev`,
+/// every envelope must be acked or nacked
+///
+/// # Errors
+///
+/// Will return `Err` if the subscription cannot be created.
+///
+/// # Panics
+///
+/// Will panic if called outside of a tokio runtime
+pub async fn get_typed_persistent_subscription<E, Ev>(
+    event_db: &E,
+    stream: &Stream,
+    group_name: &str,
+) -> Result<TypedPersistentSubscription<Ev>, EventStoreError>
+where
+    E: EventStore,
+    E::PersistentSubscription: 'static,
+    Ev: Event + DeserializeOwned + 'static,
+{
+    let subscription = get_persistent_subscription(event_db, stream, group_name).await?;
+
+    Ok(TypedPersistentSubscription::new(subscription))
+}
@@ -0,0 +1,123 @@
+//! the history of an entity, for the audit views and the activity logs
+//!
+//! `StateRepository::history` read a range of revisions of the stream,
+//! each entry is the typed command or event with its genealogy and its creation date.
+//! The next page start at `HistoryPage::next`.
+
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::metadata::Metadata;
+
+/// `HistoryPayload` is what was written at a revision
+#[derive(Clone, Debug)]
+pub enum HistoryPayload<C, E> {
+    /// the command sent to the state
+    Command(C),
+    /// an event produced by a command
+    Event(E),
+}
+
+/// `HistoryEntry` is one revision of the stream
+#[derive(Clone, Debug)]
+pub struct HistoryEntry<C, E> {
+    revision: u64,
+    metadata: Metadata,
+    created: DateTime<Utc>,
+    payload: HistoryPayload<C, E>,
+}
+
+impl<C, E> HistoryEntry<C, E> {
+    pub(crate) const fn new(
+        revision: u64,
+        metadata: Metadata,
+        created: DateTime<Utc>,
+        payload: HistoryPayload<C, E>,
+    ) -> Self {
+        Self {
+            revision,
+            metadata,
+            created,
+            payload,
+        }
+    }
+
+    /// the position of the entry in the stream
+    #[must_use]
+    pub const fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// the id of the command or of the event
+    #[must_use]
+    pub fn id(&self) -> Uuid {
+        self.metadata.id().unwrap_or_default()
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn correlation_id(&self) -> Uuid {
+        self.metadata.correlation_id()
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn causation_id(&self) -> Uuid {
+        self.metadata.causation_id()
+    }
+
+    /// the date the store received the entry
+    #[must_use]
+    pub const fn created(&self) -> DateTime<Utc> {
+        self.created
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn payload(&self) -> &HistoryPayload<C, E> {
+        &self.payload
+    }
+
+    /// `true` for an event, `false` for a command
+    #[must_use]
+    pub const fn is_event(&self) -> bool {
+        matches!(self.payload, HistoryPayload::Event(_))
+    }
+}
+
+/// `HistoryPage` is a range of the history
+#[derive(Clone, Debug)]
+pub struct HistoryPage<C, E> {
+    entries: Vec<HistoryEntry<C, E>>,
+    next: Option<u64>,
+}
+
+impl<C, E> HistoryPage<C, E> {
+    pub(crate) const fn new(entries: Vec<HistoryEntry<C, E>>, next: Option<u64>) -> Self {
+        Self { entries, next }
+    }
+
+    /// the entries, oldest first
+    #[must_use]
+    pub fn entries(&self) -> &[HistoryEntry<C, E>] {
+        &self.entries
+    }
+
+    /// drop the pagination
+    #[must_use]
+    pub fn into_entries(self) -> Vec<HistoryEntry<C, E>> {
+        self.entries
+    }
+
+    /// the first revision of the next page, `None` when the end of the stream is reached
+    #[must_use]
+    pub const fn next(&self) -> Option<u64> {
+        self.next
+    }
+}
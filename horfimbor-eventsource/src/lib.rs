@@ -6,6 +6,8 @@ use std::fmt::{Debug, Display, Formatter};
 
 /// re-export import :
 pub use horfimbor_eventsource_derive;
+
+use async_trait::async_trait;
 use kurrentdb::Error as EventStoreError;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
@@ -15,12 +17,32 @@ use uuid::Uuid;
 
 use crate::cache_db::DbError;
 use crate::model_key::{ModelKey, ModelKeyError};
+use crate::snapshot::SnapshotPolicy;
 
 pub mod cache_db;
+pub mod causation;
+pub mod codec;
+pub mod event_store;
+pub mod fixture;
 pub mod helper;
+pub mod history;
 pub mod metadata;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod model_key;
+pub mod outcome;
+pub mod projection;
 pub mod repository;
+pub mod retry;
+pub mod saga;
+pub mod snapshot;
+#[cfg(feature = "sse")]
+pub mod sse;
+pub mod subscription;
+#[cfg(feature = "telemetry")]
+pub mod telemetry;
+pub mod upcaster;
+pub mod worker;
 
 /// str wrapper
 pub type StreamName = &'static str;
@@ -35,6 +57,8 @@ pub enum Stream {
     Event(EventName),
     /// subscribe to all the descendant of a specific event
     Correlation(Uuid),
+    /// subscribe to every stream, the system events excluded
+    All,
 }
 
 impl Display for Stream {
@@ -53,6 +77,7 @@ impl Display for Stream {
             Self::Correlation(u) => {
                 write!(f, "bc-{u}")
             }
+            Self::All => f.write_str("$all"),
         }
     }
 }
@@ -80,6 +105,17 @@ pub enum EventSourceError {
     /// Error when converting uuid
     #[error("ModelKey error")]
     ModelKey(#[from] ModelKeyError),
+
+    /// the `RetryPolicy` is exhausted, the stream kept changing under the command
+    #[error(
+        "Command not appended after {attempts} attempts, last seen revision : {last_revision:?}"
+    )]
+    RetryExhausted {
+        /// number of `try_append`
+        attempts: u32,
+        /// the revision of the stream seen by the last attempt
+        last_revision: Option<u64>,
+    },
 }
 
 /// error coming from the `StateRepository`
@@ -111,6 +147,11 @@ pub trait Command: Serialize + DeserializeOwned + Debug + Send + Clone {
 pub trait Event: Serialize + DeserializeOwned + Debug + Send + Clone {
     /// the `EventName` must be unique for each variant of the enum
     fn event_name(&self) -> EventName;
+
+    /// the schema version of the payload, see `upcaster` to read the older ones
+    fn event_version(&self) -> u32 {
+        0
+    }
 }
 
 /// the `Dto` trait provide a reader on the database
@@ -120,6 +161,31 @@ pub trait Dto: Default + Serialize + DeserializeOwned + Debug + Send + Clone + S
 
     /// events are played one by one
     fn play_event(&mut self, event: &Self::Event);
+
+    /// the stable name of the cached model, empty by default :
+    /// the cached models are already keyed by `ModelKey` and repository prefix.
+    /// It must not change with the module or the compiler, unlike `std::any::type_name`
+    #[must_use]
+    fn cache_name() -> &'static str {
+        ""
+    }
+
+    /// the version of the cached model, to bump when a field is added or changed
+    #[must_use]
+    fn cache_version() -> u32 {
+        0
+    }
+
+    /// written with the cached model, a cached model with another fingerprint is a miss.
+    ///
+    /// `{cache_name}.v{cache_version}` by default, `v{cache_version}` without name
+    #[must_use]
+    fn cache_fingerprint() -> String {
+        match Self::cache_name() {
+            "" => format!("v{}", Self::cache_version()),
+            name => format!("{name}.v{}", Self::cache_version()),
+        }
+    }
 }
 
 /// `StateNamed` just provide a getter for the `StateName`
@@ -142,4 +208,38 @@ pub trait State: Dto + StateNamed {
     ///
     /// Will return `Err` if Command cannot currently occur OR something is wrong with DB
     fn try_command(&self, command: Self::Command) -> Result<Vec<Self::Event>, Self::Error>;
+
+    /// long-lived states can be snapshotted, disabled by default
+    #[must_use]
+    fn snapshot_policy() -> SnapshotPolicy {
+        SnapshotPolicy::Never
+    }
+
+    /// bump it when the serialized state change, older snapshots are then ignored
+    #[must_use]
+    fn snapshot_version() -> u32 {
+        0
+    }
+}
+
+/// the `AsyncState` trait allow commands to look outside of the state
+///
+/// the context can hold other `DtoRepository`, the current `HfTime`, the claims of the caller ...
+/// `StateRepository::add_command_with_context` use it instead of `try_command`.
+#[async_trait]
+pub trait AsyncState: State {
+    /// everything the command handler need, given on each command
+    type Context: Send + Sync;
+
+    /// same as `try_command` with the context,
+    /// it is called again when the stream changed before the events were appended
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if Command cannot currently occur OR something is wrong with DB
+    async fn try_command_with_context(
+        &self,
+        command: Self::Command,
+        context: &Self::Context,
+    ) -> Result<Vec<Self::Event>, Self::Error>;
 }
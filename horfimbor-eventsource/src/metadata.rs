@@ -1,10 +1,17 @@
 //! common metadata for all the events and command
 
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
 use kurrentdb::EventData;
+use serde::ser::Error as _;
 use serde::{Deserialize, Serialize};
 use serde_json::Error as SerdeError;
+use serde_json::value::RawValue;
 use uuid::Uuid;
 
+use crate::codec::Codec;
+use crate::model_key::ModelKey;
 use crate::{Command, Event};
 
 /// `Metadata` must be serialized a certain way to allow build-in projections
@@ -12,17 +19,45 @@ use crate::{Command, Event};
 pub struct Metadata {
     #[serde(skip_serializing)]
     id: Option<Uuid>,
-    #[serde(rename = "$correlationId")]
+    #[serde(
+        rename = "$correlationId",
+        default,
+        skip_serializing_if = "Uuid::is_nil"
+    )]
     correlation_id: Uuid,
-    #[serde(rename = "$causationId")]
+    #[serde(rename = "$causationId", default, skip_serializing_if = "Uuid::is_nil")]
     causation_id: Uuid,
     #[serde(rename = "is_event")]
     is_event: bool,
+    #[serde(rename = "schema_version", default)]
+    schema_version: u32,
+    #[serde(
+        rename = "idempotency_key",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    idempotency_key: Option<String>,
+    #[serde(rename = "codec", default, skip_serializing_if = "Option::is_none")]
+    codec: Option<String>,
+    #[serde(flatten)]
+    extensions: MetadataExtensions,
+    #[serde(
+        rename = "traceparent",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    traceparent: Option<String>,
+    #[serde(
+        rename = "tracestate",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    tracestate: Option<String>,
 }
 
 /// `Metadata` provide genealogy of the events
 impl Metadata {
-    /// the `correlation_id` is the oldest event in the genealogy
+    /// the `correlation_id` is the oldest event in the genealogy, nil for a checkpoint
     #[must_use]
     pub const fn correlation_id(&self) -> Uuid {
         self.correlation_id
@@ -58,20 +93,210 @@ impl Metadata {
             correlation_id,
             causation_id,
             is_event,
+            schema_version: 0,
+            idempotency_key: None,
+            codec: None,
+            extensions: MetadataExtensions::new(),
+            traceparent: None,
+            tracestate: None,
         }
     }
 
+    /// replace the `MetadataExtensions`
+    #[must_use]
+    pub fn with_extensions(mut self, extensions: MetadataExtensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
     /// the event in the database can be an event or a command
     #[must_use]
     pub const fn is_event(&self) -> bool {
         self.is_event
     }
+
+    /// the `Event::event_version` of the payload, `0` for the events written before versioning
+    #[must_use]
+    pub const fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// the key given by the client to send a command only once
+    #[must_use]
+    pub fn idempotency_key(&self) -> Option<&str> {
+        self.idempotency_key.as_deref()
+    }
+
+    /// the content type of the `Codec` of the payload, `None` for plain json
+    #[must_use]
+    pub fn codec(&self) -> Option<&str> {
+        self.codec.as_deref()
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn extensions(&self) -> &MetadataExtensions {
+        &self.extensions
+    }
+
+    /// the user or the account who issued the command
+    #[must_use]
+    pub const fn actor(&self) -> Option<&ModelKey> {
+        self.extensions.actor()
+    }
+
+    /// the service who issued the command
+    #[must_use]
+    pub fn service(&self) -> Option<&str> {
+        self.extensions.service()
+    }
+
+    /// a user-defined header
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.extensions.header(name)
+    }
+
+    /// the in-game time of the command, in milliseconds
+    #[must_use]
+    pub const fn hf_time(&self) -> Option<i64> {
+        self.extensions.hf_time()
+    }
+
+    /// the W3C `traceparent` of the span that wrote the entry, with the `telemetry` feature
+    #[must_use]
+    pub fn traceparent(&self) -> Option<&str> {
+        self.traceparent.as_deref()
+    }
+
+    /// the W3C `tracestate` going with the `traceparent`
+    #[must_use]
+    pub fn tracestate(&self) -> Option<&str> {
+        self.tracestate.as_deref()
+    }
+
+    #[cfg(feature = "telemetry")]
+    pub(crate) fn set_trace_context(
+        &mut self,
+        traceparent: Option<String>,
+        tracestate: Option<String>,
+    ) {
+        self.traceparent = traceparent;
+        self.tracestate = tracestate;
+    }
+}
+
+/// `MetadataExtensions` record who issued a command, from where and when in game.
+///
+/// They are given to `add_command_with_extensions` and inherited from the previous metadata,
+/// so the events of a command and the commands sent by a saga carry them too.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MetadataExtensions {
+    #[serde(rename = "actor", default, skip_serializing_if = "Option::is_none")]
+    actor: Option<ModelKey>,
+    #[serde(rename = "service", default, skip_serializing_if = "Option::is_none")]
+    service: Option<String>,
+    #[serde(
+        rename = "headers",
+        default,
+        skip_serializing_if = "BTreeMap::is_empty"
+    )]
+    headers: BTreeMap<String, String>,
+    #[serde(rename = "hf_time", default, skip_serializing_if = "Option::is_none")]
+    hf_time: Option<i64>,
+}
+
+impl MetadataExtensions {
+    /// nothing recorded
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            actor: None,
+            service: None,
+            headers: BTreeMap::new(),
+            hf_time: None,
+        }
+    }
+
+    /// the user or the account issuing the command
+    #[must_use]
+    pub fn with_actor(mut self, actor: ModelKey) -> Self {
+        self.actor = Some(actor);
+        self
+    }
+
+    /// the service issuing the command
+    #[must_use]
+    pub fn with_service(mut self, service: &str) -> Self {
+        self.service = Some(service.to_string());
+        self
+    }
+
+    /// add a user-defined header, replacing the previous value
+    #[must_use]
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// the in-game time, `HfTime::as_hf_duration().as_milliseconds()` with `horfimbor-time`
+    #[must_use]
+    pub const fn with_hf_time(mut self, milliseconds: i64) -> Self {
+        self.hf_time = Some(milliseconds);
+        self
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn actor(&self) -> Option<&ModelKey> {
+        self.actor.as_ref()
+    }
+
+    /// simple getter
+    #[must_use]
+    pub fn service(&self) -> Option<&str> {
+        self.service.as_deref()
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+
+    /// simple getter
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn hf_time(&self) -> Option<i64> {
+        self.hf_time
+    }
+
+    /// the values set in `other` replace the inherited ones
+    fn merge(&mut self, other: &Self) {
+        if other.actor.is_some() {
+            self.actor.clone_from(&other.actor);
+        }
+        if other.service.is_some() {
+            self.service.clone_from(&other.service);
+        }
+        self.headers.extend(other.headers.clone());
+        if other.hf_time.is_some() {
+            self.hf_time = other.hf_time;
+        }
+    }
 }
 
 /// event in the db are composed of the `EventData` and the `Metadata`
 #[derive(Clone)]
 pub struct CompleteEvent {
     event_data: EventData,
+    event_type: String,
+    data: Bytes,
     metadata: Metadata,
 }
 
@@ -86,6 +311,16 @@ impl CompleteEvent {
         &self.metadata
     }
 
+    /// the `CommandName` or the `EventName`
+    pub fn event_type(&self) -> &str {
+        &self.event_type
+    }
+
+    /// the payload, json unless written with another `Codec`
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
     /// # Errors
     ///
     /// Will return `Err` if `Metadata` cannot be de into json
@@ -103,9 +338,16 @@ impl CompleteEvent {
     where
         C: Command,
     {
-        let event_data = EventData::json(command.command_name(), &command)?;
+        let data = Bytes::from(serde_json::to_vec(&command)?);
+        let event_data = json_event_data(command.command_name(), &data)?;
 
-        Ok(Self::from_event_data(event_data, previous_metadata, false))
+        Ok(Self::from_event_data(
+            event_data,
+            command.command_name(),
+            data,
+            previous_metadata,
+            false,
+        ))
     }
 
     /// # Errors
@@ -115,17 +357,75 @@ impl CompleteEvent {
     where
         E: Event,
     {
-        let event_data = EventData::json(event.event_name(), &event)?;
+        Self::from_event_with_codec(event, previous_metadata, Codec::default())
+    }
 
-        Ok(Self::from_event_data(
+    /// the payload is serialized with the `codec`, recorded in the `Metadata`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the event cannot be serialized with the `codec`
+    pub fn from_event_with_codec<E>(
+        event: &E,
+        previous_metadata: &Metadata,
+        codec: Codec,
+    ) -> Result<Self, SerdeError>
+    where
+        E: Event,
+    {
+        let (event_data, data) = if codec.is_plain_json() {
+            let data = Bytes::from(serde_json::to_vec(&event)?);
+            (json_event_data(event.event_name(), &data)?, data)
+        } else {
+            let data = Bytes::from(codec.encode(event).map_err(SerdeError::custom)?);
+            (EventData::binary(event.event_name(), data.clone()), data)
+        };
+
+        let mut complete = Self::from_event_data(
             event_data,
+            event.event_name(),
+            data,
             Some(previous_metadata),
             true,
-        ))
+        );
+        complete.metadata.schema_version = event.event_version();
+        if !codec.is_plain_json() {
+            complete.metadata.codec = Some(codec.content_type());
+        }
+
+        Ok(complete)
+    }
+
+    /// the extensions given with the command, over the inherited ones
+    pub(crate) fn merge_extensions(&mut self, extensions: &MetadataExtensions) {
+        self.metadata.extensions.merge(extensions);
+    }
+
+    /// only the command carry the key, not its events
+    pub(crate) fn set_idempotency_key(&mut self, idempotency_key: Option<&str>) {
+        self.metadata.idempotency_key = idempotency_key.map(ToString::to_string);
+    }
+
+    /// a checkpoint or a snapshot is outside of any genealogy : without correlation id,
+    /// it is not linked into a new `bc-` stream each time it is written
+    pub(crate) fn checkpoint<T>(event_type: &str, checkpoint: &T) -> Result<Self, SerdeError>
+    where
+        T: Serialize,
+    {
+        let data = Bytes::from(serde_json::to_vec(checkpoint)?);
+        let event_data = json_event_data(event_type, &data)?;
+
+        let mut complete = Self::from_event_data(event_data, event_type, data, None, false);
+        complete.metadata.correlation_id = Uuid::nil();
+        complete.metadata.causation_id = Uuid::nil();
+
+        Ok(complete)
     }
 
     fn from_event_data(
         mut event_data: EventData,
+        event_type: &str,
+        data: Bytes,
         previous_metadata: Option<&Metadata>,
         is_event: bool,
     ) -> Self {
@@ -139,18 +439,42 @@ impl CompleteEvent {
                 correlation_id: id,
                 causation_id: id,
                 is_event,
+                schema_version: 0,
+                idempotency_key: None,
+                codec: None,
+                extensions: MetadataExtensions::new(),
+                traceparent: None,
+                tracestate: None,
             },
             |previous| Metadata {
                 id: Some(id),
                 correlation_id: previous.correlation_id,
                 causation_id: previous.id.unwrap_or(id),
                 is_event,
+                schema_version: 0,
+                idempotency_key: None,
+                codec: None,
+                extensions: previous.extensions.clone(),
+                traceparent: None,
+                tracestate: None,
             },
         );
 
+        #[cfg(feature = "telemetry")]
+        let metadata = crate::telemetry::with_current_context(metadata);
+
         Self {
             event_data,
+            event_type: event_type.to_string(),
+            data,
             metadata,
         }
     }
 }
+
+/// the json `EventData` of a payload already serialized, it is only copied
+fn json_event_data(event_type: &str, data: &[u8]) -> Result<EventData, SerdeError> {
+    let raw: &RawValue = serde_json::from_slice(data)?;
+
+    EventData::json(event_type, &raw)
+}
@@ -0,0 +1,166 @@
+//! Prometheus metrics of the repositories, behind the `metrics` feature
+//!
+//! a `Metrics` is given to the repositories with `with_metrics`, every metric has a `state` label :
+//! the `StateName` of a `StateRepository`, the prefix of a `DtoRepository` (else its `Dto::cache_name`).
+//! The clones share the same metrics, `render` write them in the Prometheus text format.
+
+use prometheus::{
+    Error, HistogramOpts, HistogramVec, IntCounterVec, Opts, Registry, TextEncoder,
+    exponential_buckets,
+};
+
+/// `Metrics` hold the counters and the histograms, cheap to clone
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    commands: IntCounterVec,
+    rejections: IntCounterVec,
+    retries: IntCounterVec,
+    replay_length: HistogramVec,
+    cache: IntCounterVec,
+    cache_lag: HistogramVec,
+    snapshot_failures: IntCounterVec,
+}
+
+impl Metrics {
+    /// the metrics in their own `Registry`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a metric cannot be registered
+    pub fn new() -> Result<Self, Error> {
+        Self::with_registry(Registry::new())
+    }
+
+    /// the metrics are added to the `registry` of the application
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the metrics are already in the registry
+    pub fn with_registry(registry: Registry) -> Result<Self, Error> {
+        let commands = IntCounterVec::new(
+            Opts::new("horfimbor_commands_total", "commands sent to add_command"),
+            &["state"],
+        )?;
+        let rejections = IntCounterVec::new(
+            Opts::new(
+                "horfimbor_command_rejections_total",
+                "commands refused by the state or given up by the retry policy",
+            ),
+            &["state", "error"],
+        )?;
+        let retries = IntCounterVec::new(
+            Opts::new(
+                "horfimbor_command_retries_total",
+                "appends refused because the stream changed",
+            ),
+            &["state"],
+        )?;
+        let replay_length = HistogramVec::new(
+            HistogramOpts::new(
+                "horfimbor_replay_length",
+                "entries read from the event store to complete a model",
+            )
+            .buckets(exponential_buckets(1.0, 4.0, 8)?),
+            &["state"],
+        )?;
+        let cache = IntCounterVec::new(
+            Opts::new(
+                "horfimbor_cache_requests_total",
+                "models read from the cache",
+            ),
+            &["state", "result"],
+        )?;
+        let cache_lag = HistogramVec::new(
+            HistogramOpts::new(
+                "horfimbor_cache_dto_lag_seconds",
+                "delay between the creation of an event and its model being cached by cache_dto",
+            ),
+            &["state"],
+        )?;
+        let snapshot_failures = IntCounterVec::new(
+            Opts::new(
+                "horfimbor_snapshot_failures_total",
+                "snapshots not written after a command, the command itself is stored",
+            ),
+            &["state"],
+        )?;
+
+        registry.register(Box::new(commands.clone()))?;
+        registry.register(Box::new(rejections.clone()))?;
+        registry.register(Box::new(retries.clone()))?;
+        registry.register(Box::new(replay_length.clone()))?;
+        registry.register(Box::new(cache.clone()))?;
+        registry.register(Box::new(cache_lag.clone()))?;
+        registry.register(Box::new(snapshot_failures.clone()))?;
+
+        Ok(Self {
+            registry,
+            commands,
+            rejections,
+            retries,
+            replay_length,
+            cache,
+            cache_lag,
+            snapshot_failures,
+        })
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// every metric of the registry in the Prometheus text format
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a metric cannot be encoded
+    pub fn render(&self) -> Result<String, Error> {
+        TextEncoder::new().encode_to_string(&self.registry.gather())
+    }
+
+    pub(crate) fn command(&self, state: &str) {
+        self.commands.with_label_values(&[state]).inc();
+    }
+
+    pub(crate) fn rejection(&self, state: &str, error: &str) {
+        self.rejections.with_label_values(&[state, error]).inc();
+    }
+
+    pub(crate) fn retry(&self, state: &str) {
+        self.retries.with_label_values(&[state]).inc();
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    pub(crate) fn replay(&self, state: &str, length: u64) {
+        self.replay_length
+            .with_label_values(&[state])
+            .observe(length as f64);
+    }
+
+    pub(crate) fn cache(&self, state: &str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache.with_label_values(&[state, result]).inc();
+    }
+
+    pub(crate) fn cache_lag(&self, state: &str, seconds: f64) {
+        self.cache_lag.with_label_values(&[state]).observe(seconds);
+    }
+
+    pub(crate) fn snapshot_failure(&self, state: &str) {
+        self.snapshot_failures.with_label_values(&[state]).inc();
+    }
+}
+
+/// the variant of a `State::Error` : `NotEnough` for `NotEnough(3)` or `NotEnough { .. }`
+pub(crate) fn error_label<E>(error: &E) -> String
+where
+    E: std::fmt::Debug,
+{
+    format!("{error:?}")
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect()
+}
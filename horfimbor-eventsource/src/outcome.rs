@@ -0,0 +1,122 @@
+//! everything known once a command is appended
+//!
+//! `StateRepository::add_command_detailed` return a `CommandOutcome`,
+//! the `Metadata` of the last event can be given as `previous_metadata` to the next command
+//! to keep the correlation chain, the revision can be used as an `ETag`.
+
+use uuid::Uuid;
+
+use crate::State;
+use crate::metadata::Metadata;
+
+/// `CommandOutcome` is the result of a command appended to the stream
+#[derive(Clone, Debug)]
+pub struct CommandOutcome<S>
+where
+    S: State,
+{
+    state: S,
+    command_metadata: Metadata,
+    events: Vec<AppendedEvent<S::Event>>,
+    revision: u64,
+}
+
+/// `AppendedEvent` is a typed event with its place in the stream
+#[derive(Clone, Debug)]
+pub struct AppendedEvent<E> {
+    event: E,
+    position: u64,
+    metadata: Metadata,
+}
+
+impl<S> CommandOutcome<S>
+where
+    S: State,
+{
+    pub(crate) const fn new(
+        state: S,
+        command_metadata: Metadata,
+        events: Vec<AppendedEvent<S::Event>>,
+        revision: u64,
+    ) -> Self {
+        Self {
+            state,
+            command_metadata,
+            events,
+            revision,
+        }
+    }
+
+    /// the state once the events are played
+    #[must_use]
+    pub const fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// drop everything but the state
+    #[must_use]
+    pub fn into_state(self) -> S {
+        self.state
+    }
+
+    /// the id of the command in the stream
+    #[must_use]
+    pub fn command_id(&self) -> Uuid {
+        self.command_metadata.id().unwrap_or_default()
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn command_metadata(&self) -> &Metadata {
+        &self.command_metadata
+    }
+
+    /// the events produced by the command, in the stream order
+    #[must_use]
+    pub fn events(&self) -> &[AppendedEvent<S::Event>] {
+        &self.events
+    }
+
+    /// the `Metadata` to give to a follow-up command :
+    /// the one of the last event, or of the command when there is no event
+    #[must_use]
+    pub fn last_metadata(&self) -> &Metadata {
+        self.events
+            .last()
+            .map_or(&self.command_metadata, |event| &event.metadata)
+    }
+
+    /// the revision of the stream after the command
+    #[must_use]
+    pub const fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+impl<E> AppendedEvent<E> {
+    pub(crate) const fn new(event: E, position: u64, metadata: Metadata) -> Self {
+        Self {
+            event,
+            position,
+            metadata,
+        }
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn event(&self) -> &E {
+        &self.event
+    }
+
+    /// the revision of the event in its stream
+    #[must_use]
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+}
@@ -0,0 +1,260 @@
+//! read models built across entities : leaderboards, counters, indexes ...
+//!
+//! a `Projection` receive the accepted events of a `Stream`, the `ProjectionRunner` feed it
+//! with a temporary subscription and write its position in the `projection_{name}` stream
+//! every few applied events, or shortly after the last one.
+//! Only the last checkpoint is kept (`$maxCount` is 1). On restart the subscription start after it.
+//!
+//! The events applied after the last checkpoint are applied again when the runner restart.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use kurrentdb::StreamState;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::time::{Instant, sleep_until};
+
+use crate::event_store::{EventStore, EventSubscription, StoredEvent};
+use crate::helper::get_subscription;
+use crate::metadata::{CompleteEvent, Metadata};
+use crate::model_key::ModelKey;
+use crate::upcaster::Upcasters;
+use crate::{Event, EventSourceError, Stream};
+
+/// `Projection` is a read model fed by the events of a `Stream`
+#[async_trait]
+pub trait Projection: Send + Sync {
+    /// the events applied, the other ones are skipped
+    type Event: Event + Sync;
+
+    /// the name of the projection, it name the checkpoint stream
+    fn projection_name(&self) -> &'static str;
+
+    /// the stream listened to, `Stream::All` for every entity
+    fn stream(&self) -> Stream;
+
+    /// only the events with an accepted `EventName` are decoded,
+    /// the commands are never given to the projection
+    fn accept(&self, event_type: &str) -> bool;
+
+    /// update the read model with the event
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the read model cannot be updated, the runner stop
+    async fn apply(
+        &self,
+        key: &ModelKey,
+        metadata: &Metadata,
+        event: &Self::Event,
+    ) -> Result<(), ProjectionError>;
+
+    /// empty the read model before a rebuild
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the read model cannot be emptied
+    async fn reset(&self) -> Result<(), ProjectionError>;
+}
+
+/// error coming from the `ProjectionRunner`
+#[derive(Error, Debug)]
+pub enum ProjectionError {
+    /// error from `EventSourceError`
+    #[error("Event source error")]
+    EventSourceError(#[from] EventSourceError),
+
+    /// error depending on the `Projection`
+    #[error("Projection error : {0}")]
+    Projection(String),
+}
+
+/// the position of the last applied event written, `None` until the first one
+#[derive(Serialize, Deserialize)]
+struct ProjectionCheckpoint {
+    position: Option<u64>,
+}
+
+/// `ProjectionRunner` feed the `Projection` and keep its checkpoint
+#[derive(Clone)]
+pub struct ProjectionRunner<P, E>
+where
+    P: Projection,
+    E: EventStore,
+{
+    projection: P,
+    event_db: E,
+    upcasters: Upcasters,
+    checkpoint_every: u32,
+    checkpoint_delay: Duration,
+}
+
+impl<P, E> ProjectionRunner<P, E>
+where
+    P: Projection,
+    E: EventStore,
+{
+    /// straight forward constructor
+    #[must_use]
+    pub fn new(event_db: E, projection: P) -> Self {
+        Self {
+            projection,
+            event_db,
+            upcasters: Upcasters::default(),
+            checkpoint_every: 100,
+            checkpoint_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// the `Upcasters` are applied to the old events before decoding them
+    #[must_use]
+    pub fn with_upcasters(mut self, upcasters: Upcasters) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// the checkpoint is written after `every` applied events (at least one),
+    /// or `delay` after the first event it does not cover yet. 100 events and 1 second by default
+    #[must_use]
+    pub fn with_checkpoint(mut self, every: u32, delay: Duration) -> Self {
+        self.checkpoint_every = every.max(1);
+        self.checkpoint_delay = delay;
+        self
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn projection(&self) -> &P {
+        &self.projection
+    }
+
+    /// the position of the last applied event
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached or the checkpoint cannot be decoded
+    pub async fn checkpoint(&self) -> Result<Option<u64>, EventSourceError> {
+        let Some(last) = self
+            .event_db
+            .read_last_event(&self.checkpoint_stream())
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let checkpoint: ProjectionCheckpoint = last.as_json()?;
+
+        Ok(checkpoint.position)
+    }
+
+    /// apply the events after the checkpoint, then the new ones until an error occur
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached, an event cannot be decoded
+    /// or the projection fail to apply it
+    pub async fn run(&self) -> Result<(), ProjectionError> {
+        let checkpoint = self.checkpoint().await?;
+
+        self.run_from(checkpoint).await
+    }
+
+    /// reset the projection and apply every event of the stream again,
+    /// then the new ones like `run`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` like `run` or if the projection cannot be reset
+    pub async fn rebuild(&self) -> Result<(), ProjectionError> {
+        // a restart during the rebuild must not resume from the old position
+        self.write_checkpoint(None).await?;
+        self.projection.reset().await?;
+
+        self.run_from(None).await
+    }
+
+    async fn run_from(&self, checkpoint: Option<u64>) -> Result<(), ProjectionError> {
+        self.event_db
+            .set_max_count(&self.checkpoint_stream(), 1)
+            .await
+            .map_err(EventSourceError::EventStore)?;
+
+        let stream = self.projection.stream();
+        let mut sub = get_subscription(&self.event_db, &stream, checkpoint).await;
+
+        // the last applied position not written yet, and how many events it covers
+        let mut pending = None;
+        let mut applied = 0;
+        let mut deadline = Instant::now();
+
+        loop {
+            let event = tokio::select! {
+                event = sub.next() => event.map_err(EventSourceError::EventStore)?,
+                () = sleep_until(deadline), if pending.is_some() => {
+                    self.write_checkpoint(pending.take()).await?;
+                    applied = 0;
+                    continue;
+                }
+            };
+
+            if !self.apply(&event).await? {
+                continue;
+            }
+
+            if pending.is_none() {
+                deadline = Instant::now() + self.checkpoint_delay;
+            }
+            pending = Some(event.position());
+            applied += 1;
+
+            if applied >= self.checkpoint_every {
+                self.write_checkpoint(pending.take()).await?;
+                applied = 0;
+            }
+        }
+    }
+
+    /// `false` when the event is skipped
+    async fn apply(&self, stored: &StoredEvent) -> Result<bool, ProjectionError> {
+        let Ok(metadata) = stored.metadata() else {
+            return Ok(false);
+        };
+
+        if !metadata.is_event() || !self.projection.accept(stored.event_type()) {
+            return Ok(false);
+        }
+
+        let key: ModelKey = stored
+            .stream_id()
+            .try_into()
+            .map_err(EventSourceError::ModelKey)?;
+
+        let event = self
+            .upcasters
+            .decode::<P::Event>(stored, &metadata)
+            .map_err(EventSourceError::Serde)?;
+
+        self.projection.apply(&key, &metadata, &event).await?;
+
+        Ok(true)
+    }
+
+    async fn write_checkpoint(&self, position: Option<u64>) -> Result<(), EventSourceError> {
+        let event = CompleteEvent::checkpoint(
+            &format!("{}.checkpoint", self.projection.projection_name()),
+            &ProjectionCheckpoint { position },
+        )?;
+
+        self.event_db
+            .append_to_stream(&self.checkpoint_stream(), StreamState::Any, vec![event])
+            .await?;
+
+        Ok(())
+    }
+
+    fn checkpoint_stream(&self) -> String {
+        let name = self.projection.projection_name().replace(['-', '.'], "_");
+        format!("projection_{name}")
+    }
+}
@@ -4,35 +4,49 @@
 use std::cmp::Ordering;
 use std::fmt::Debug;
 use std::marker::PhantomData;
+use std::ops::Range;
+use std::time::Instant;
 
 use async_trait::async_trait;
-use kurrentdb::{
-    AppendToStreamOptions, Client as EventDb, Error, EventData, ReadStreamOptions, StreamPosition,
-    StreamState, SubscribeToPersistentSubscriptionOptions,
-};
+use chrono::{DateTime, Utc};
+use kurrentdb::{Client as EventDb, Error, StreamPosition, StreamState};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
 use crate::cache_db::CacheDb;
-use crate::helper::create_subscription;
-use crate::metadata::{CompleteEvent, Metadata};
+use crate::codec::Codec;
+use crate::event_store::{EventReader, EventStore, PersistentEventSubscription, StoredEvent};
+use crate::helper::get_persistent_subscription;
+use crate::history::{HistoryEntry, HistoryPage, HistoryPayload};
+use crate::metadata::{CompleteEvent, Metadata, MetadataExtensions};
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Metrics};
 use crate::model_key::ModelKey;
+use crate::outcome::{AppendedEvent, CommandOutcome};
+use crate::retry::{self, RetryPolicy};
+use crate::snapshot;
+use crate::upcaster::Upcasters;
+use crate::{AsyncState, State, Stream};
 use crate::{Dto, EventSourceError, EventSourceStateError};
-use crate::{State, Stream};
 
 /// the `DtoRepository` is the reading part of the event storage
 /// multiple `DtoRepository` can listen to the event stream but produce
 /// different model.
 #[derive(Clone)]
 #[allow(clippy::module_name_repetitions)]
-pub struct DtoRepository<D, C>
+pub struct DtoRepository<D, C, E = EventDb>
 where
     D: Dto,
     C: CacheDb<D>,
+    E: EventStore,
 {
-    event_db: EventDb,
+    event_db: E,
     cache_db: C,
     repository_kind: RepositoryKind,
+    upcasters: Upcasters,
+    codec: Codec,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Metrics>,
     dto: PhantomData<D>,
 }
 
@@ -41,14 +55,21 @@ where
 /// and the query are done by recomputing all event or reading the `CacheDB`
 #[derive(Clone)]
 #[allow(clippy::module_name_repetitions)]
-pub struct StateRepository<S, C>
+pub struct StateRepository<S, C, E = EventDb>
 where
     S: State,
     C: CacheDb<S>,
+    E: EventStore,
 {
-    event_db: EventDb,
+    event_db: E,
     state_db: C,
     repository_kind: RepositoryKind,
+    upcasters: Upcasters,
+    codec: Codec,
+    retry_policy: RetryPolicy,
+    idempotency_window: u64,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Metrics>,
     state: PhantomData<S>,
 }
 
@@ -104,42 +125,47 @@ impl RepositoryKind {
 #[allow(missing_docs)]
 #[async_trait]
 /// the trait `Repository` allow to reconstruct the `Dto` from the `EventDb`
-pub trait DtoRepositoryConstructor<D, C>: Clone + Send
+pub trait DtoRepositoryConstructor<D, C, E = EventDb>: Clone + Send
 where
     D: Dto,
     C: CacheDb<D>,
+    E: EventStore,
 {
     /// A repository need the `EventDb`, a cache system and a kind,
+    /// the `EventDb` can be any `EventStore`, `kurrentdb::Client` or the `InMemoryEventStore`,
     /// the cache system can be the `NoCache` provided in `cache_db`,
     /// the kind is used only to avoid cache collision,
     /// the type system take care of everything else.
-    fn new(event_db: EventDb, cache_db: C, repository_kind: RepositoryKind) -> Self;
+    fn new(event_db: E, cache_db: C, repository_kind: RepositoryKind) -> Self;
 }
 
 #[allow(missing_docs)]
 #[async_trait]
 /// the trait `Repository` allow to reconstruct the `Dto` from the `EventDb`
-pub trait StateRepositoryConstructor<D, C>: Clone + Send
+pub trait StateRepositoryConstructor<D, C, E = EventDb>: Clone + Send
 where
     D: Dto,
     C: CacheDb<D>,
+    E: EventStore,
 {
     /// A repository need the `EventDb`, a cache system and a kind,
+    /// the `EventDb` can be any `EventStore`, `kurrentdb::Client` or the `InMemoryEventStore`,
     /// the cache system can be the `NoCache` provided in `cache_db`,
     /// the type system take care of everything else.
-    fn new(event_db: EventDb, cache_db: C) -> Self;
+    fn new(event_db: E, cache_db: C) -> Self;
 }
 
 #[allow(missing_docs)]
 #[async_trait]
 /// the trait `Repository` allow to reconstruct the `Dto` from the `EventDb`
-pub trait Repository<D, C>: Clone + Send
+pub trait Repository<D, C, E = EventDb>: Clone + Send
 where
     D: Dto,
     C: CacheDb<D>,
+    E: EventStore,
 {
     /// Getter for the `EventDb`
-    fn event_db(&self) -> &EventDb;
+    fn event_db(&self) -> &E;
 
     /// Getter for the cache
     fn cache_db(&self) -> &C;
@@ -147,18 +173,91 @@ where
     /// Getter for the cache
     fn repository_kind(&self) -> &RepositoryKind;
 
+    /// Getter for the `Upcasters` applied before decoding the events
+    fn upcasters(&self) -> &Upcasters;
+
+    /// Getter for the `Codec` of the written events and cached models
+    fn codec(&self) -> Codec;
+
+    /// Getter for the `Metrics`, `None` when nothing is recorded
+    #[cfg(feature = "metrics")]
+    fn metrics(&self) -> Option<&Metrics> {
+        None
+    }
+
+    /// the `state` label of the metrics : the cache prefix, else `Dto::cache_name`,
+    /// both stay the same across builds
+    #[cfg(feature = "metrics")]
+    fn metrics_label(&self) -> &'static str {
+        self.repository_kind()
+            .to_cache_prefix()
+            .unwrap_or_else(D::cache_name)
+    }
+
     async fn get_model(&self, key: &ModelKey) -> Result<ModelWithPosition<D>, EventSourceError>
     where
         D: Dto + DeserializeOwned,
     {
-        let value = self
+        let mut value = self
             .cache_db()
             .get(self.repository_kind().to_cache_prefix(), key)
+            .await
             .map_err(EventSourceError::CacheDbError)?;
 
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics() {
+            metrics.cache(self.metrics_label(), value.position.is_some());
+        }
+
+        if let Some(snapshot) = self.get_snapshot(key).await?
+            && snapshot.position > value.position
+        {
+            value = snapshot;
+        }
+
         self.complete_from_es(key, &value).await
     }
 
+    /// the model as it was after the entry at `revision` of its stream,
+    /// the cache and the snapshot are used only when they are not after it
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the stores cannot be reached or an event cannot be decoded
+    async fn get_model_at_revision(
+        &self,
+        key: &ModelKey,
+        revision: u64,
+    ) -> Result<ModelWithPosition<D>, EventSourceError> {
+        replay_until(self, key, Bound::Revision(revision)).await
+    }
+
+    /// the model as it was at `at`, the entries created after are not played,
+    /// the cache and the snapshot are used only when they are older
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the stores cannot be reached or an event cannot be decoded
+    async fn get_model_at(
+        &self,
+        key: &ModelKey,
+        at: DateTime<Utc>,
+    ) -> Result<ModelWithPosition<D>, EventSourceError> {
+        replay_until(self, key, Bound::Time(at)).await
+    }
+
+    /// the latest usable snapshot, only a `StateRepository` can have some
+    async fn get_snapshot(
+        &self,
+        _key: &ModelKey,
+    ) -> Result<Option<ModelWithPosition<D>>, EventSourceError> {
+        Ok(None)
+    }
+
+    #[cfg_attr(
+        feature = "telemetry",
+        tracing::instrument(skip_all, fields(key = %key, from = ?value.position))
+    )]
     async fn complete_from_es(
         &self,
         key: &ModelKey,
@@ -167,35 +266,39 @@ where
         let mut dto: D = value.model.clone();
         let mut position = value.position;
 
-        let options = ReadStreamOptions::default();
-        let options = if let Some(position) = value.position {
-            options.position(StreamPosition::Position(position + 1))
-        } else {
-            options.position(StreamPosition::Start)
-        };
+        let from = value.position.map_or(StreamPosition::Start, |position| {
+            StreamPosition::Position(position + 1)
+        });
 
         let mut stream = self
             .event_db()
-            .read_stream(key.format(), &options)
+            .read_stream(&key.format(), from)
             .await
             .map_err(EventSourceError::EventStore)?;
 
-        while let Ok(Some(json_event)) = stream.next().await {
-            let original_event = json_event.get_original_event();
-
-            let metadata: Metadata =
-                serde_json::from_slice(original_event.custom_metadata.as_ref())
-                    .map_err(EventSourceError::Serde)?;
+        while let Ok(Some(original_event)) = stream.next().await {
+            let metadata: Metadata = original_event.metadata().map_err(EventSourceError::Serde)?;
 
             if metadata.is_event() {
-                let event = original_event
-                    .as_json::<D::Event>()
+                let event = self
+                    .upcasters()
+                    .decode::<D::Event>(&original_event, &metadata)
                     .map_err(EventSourceError::Serde)?;
 
                 dto.play_event(&event);
             }
 
-            position = Some(original_event.revision);
+            position = Some(original_event.revision());
+        }
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics() {
+            let replayed = match (value.position, position) {
+                (_, None) => 0,
+                (None, Some(last)) => last + 1,
+                (Some(first), Some(last)) => last - first,
+            };
+            metrics.replay(self.metrics_label(), replayed);
         }
 
         let result = ModelWithPosition {
@@ -206,63 +309,92 @@ where
         Ok(result)
     }
 
+    #[cfg_attr(
+        feature = "telemetry",
+        tracing::instrument(skip(self), fields(stream = %stream), err(Display))
+    )]
     async fn cache_dto(&self, stream: &Stream, group_name: &str) -> Result<(), EventSourceError> {
-        create_subscription(self.event_db(), stream, group_name)
-            .await
-            .map_err(EventSourceError::EventStore)?;
-
-        let options = SubscribeToPersistentSubscriptionOptions::default().buffer_size(1);
-
-        let mut sub = self
-            .event_db()
-            .subscribe_to_persistent_subscription(stream.to_string(), group_name, &options)
+        let mut sub = get_persistent_subscription(self.event_db(), stream, group_name)
             .await
             .map_err(EventSourceError::EventStore)?;
 
         loop {
             let rcv_event = sub.next().await.map_err(EventSourceError::EventStore)?;
 
-            let Some(event) = rcv_event.event.as_ref() else {
+            let Some(event) = rcv_event.event() else {
                 continue;
             };
 
-            let model_key: ModelKey = event
-                .stream_id()
-                .try_into()
-                .map_err(EventSourceError::ModelKey)?;
-
-            let mut model = self
-                .cache_db()
-                .get(self.repository_kind().to_cache_prefix(), &model_key)
-                .map_err(EventSourceError::CacheDbError)?;
-
-            let ordering = if event.revision == 0 {
-                if model.position.is_some() {
-                    Ordering::Greater
-                } else {
-                    Ordering::Equal
-                }
-            } else {
-                model
-                    .position
-                    .map_or(Ordering::Less, |pos| pos.cmp(&(event.revision)))
-            };
+            #[cfg(feature = "telemetry")]
+            tracing::Instrument::instrument(
+                self.cache_event(event),
+                crate::telemetry::event_span("cache_event", event),
+            )
+            .await?;
+            #[cfg(not(feature = "telemetry"))]
+            self.cache_event(event).await?;
 
-            match ordering {
-                Ordering::Less => {
-                    model = self.complete_from_es(&model_key, &model).await?;
+            sub.ack(&rcv_event)
+                .await
+                .map_err(EventSourceError::EventStore)?;
+        }
+    }
+
+    /// update the cached model of the entity the event belong to, if it is behind
+    async fn cache_event(&self, event: &StoredEvent) -> Result<(), EventSourceError> {
+        let model_key: ModelKey = event
+            .stream_id()
+            .try_into()
+            .map_err(EventSourceError::ModelKey)?;
 
-                    self.cache_db()
-                        .set(&model_key, model, self.repository_kind().to_cache_prefix())
-                        .map_err(EventSourceError::CacheDbError)?;
+        let mut model = self
+            .cache_db()
+            .get(self.repository_kind().to_cache_prefix(), &model_key)
+            .await
+            .map_err(EventSourceError::CacheDbError)?;
+
+        let ordering = if event.revision() == 0 {
+            if model.position.is_some() {
+                Ordering::Greater
+            } else {
+                Ordering::Equal
+            }
+        } else {
+            model
+                .position
+                .map_or(Ordering::Less, |pos| pos.cmp(&(event.revision())))
+        };
+
+        match ordering {
+            Ordering::Less => {
+                if let Some(snapshot) = self.get_snapshot(&model_key).await?
+                    && snapshot.position > model.position
+                {
+                    model = snapshot;
                 }
-                Ordering::Equal | Ordering::Greater => {}
+
+                model = self.complete_from_es(&model_key, &model).await?;
+
+                self.cache_db()
+                    .set_with_codec(
+                        &model_key,
+                        &model,
+                        self.repository_kind().to_cache_prefix(),
+                        self.codec(),
+                    )
+                    .await
+                    .map_err(EventSourceError::CacheDbError)?;
             }
+            Ordering::Equal | Ordering::Greater => {}
+        }
 
-            sub.ack(&rcv_event)
-                .await
-                .map_err(EventSourceError::EventStore)?;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics() {
+            let lag = Utc::now() - event.created();
+            metrics.cache_lag(self.metrics_label(), lag.as_seconds_f64().max(0.0));
         }
+
+        Ok(())
     }
 
     /// # Errors
@@ -281,27 +413,171 @@ where
     }
 }
 
-impl<D, C> DtoRepositoryConstructor<D, C> for DtoRepository<D, C>
+/// where a time travel stop
+#[derive(Clone, Copy)]
+enum Bound {
+    Revision(u64),
+    Time(DateTime<Utc>),
+}
+
+impl Bound {
+    fn includes(self, event: &StoredEvent) -> bool {
+        match self {
+            Self::Revision(revision) => event.revision() <= revision,
+            Self::Time(at) => event.created() <= at,
+        }
+    }
+}
+
+/// replay from the most advanced of the cache and the snapshot that is not after the bound
+async fn replay_until<R, D, C, E>(
+    repository: &R,
+    key: &ModelKey,
+    bound: Bound,
+) -> Result<ModelWithPosition<D>, EventSourceError>
+where
+    R: Repository<D, C, E> + Sync,
+    D: Dto,
+    C: CacheDb<D>,
+    E: EventStore,
+{
+    let cached = repository
+        .cache_db()
+        .get(repository.repository_kind().to_cache_prefix(), key)
+        .await
+        .map_err(EventSourceError::CacheDbError)?;
+
+    let mut candidates: Vec<ModelWithPosition<D>> = repository
+        .get_snapshot(key)
+        .await?
+        .into_iter()
+        .chain([cached])
+        .collect();
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.position));
+
+    let mut start = ModelWithPosition::default();
+    for candidate in candidates {
+        let Some(position) = candidate.position else {
+            break;
+        };
+
+        let mut at_position = repository
+            .event_db()
+            .read_stream(&key.format(), StreamPosition::Position(position))
+            .await
+            .map_err(EventSourceError::EventStore)?;
+
+        let event = match at_position.next().await {
+            Ok(event) => event,
+            // a cached model of a stream that is gone
+            Err(Error::ResourceNotFound) => break,
+            Err(e) => return Err(EventSourceError::EventStore(e)),
+        };
+
+        if let Some(event) = event
+            && bound.includes(&event)
+        {
+            start = candidate;
+            break;
+        }
+    }
+
+    let from = start.position.map_or(StreamPosition::Start, |position| {
+        StreamPosition::Position(position + 1)
+    });
+
+    let mut stream = repository
+        .event_db()
+        .read_stream(&key.format(), from)
+        .await
+        .map_err(EventSourceError::EventStore)?;
+
+    let mut model = start;
+    loop {
+        let original_event = match stream.next().await {
+            Ok(Some(original_event)) => original_event,
+            // an unknown entity has the default model, as with `get_model`
+            Ok(None) | Err(Error::ResourceNotFound) => break,
+            Err(e) => return Err(EventSourceError::EventStore(e)),
+        };
+
+        if !bound.includes(&original_event) {
+            break;
+        }
+
+        let metadata: Metadata = original_event.metadata().map_err(EventSourceError::Serde)?;
+
+        if metadata.is_event() {
+            let event = repository
+                .upcasters()
+                .decode::<D::Event>(&original_event, &metadata)
+                .map_err(EventSourceError::Serde)?;
+
+            model.model.play_event(&event);
+        }
+
+        model.position = Some(original_event.revision());
+    }
+
+    Ok(model)
+}
+
+impl<D, C, E> DtoRepositoryConstructor<D, C, E> for DtoRepository<D, C, E>
 where
     D: Dto,
     C: CacheDb<D>,
+    E: EventStore,
 {
-    fn new(event_db: EventDb, cache_db: C, repository_kind: RepositoryKind) -> Self {
+    fn new(event_db: E, cache_db: C, repository_kind: RepositoryKind) -> Self {
         Self {
             event_db,
             cache_db,
             repository_kind,
+            upcasters: Upcasters::default(),
+            codec: Codec::default(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
             dto: PhantomData,
         }
     }
 }
 
-impl<D, C> Repository<D, C> for DtoRepository<D, C>
+impl<D, C, E> DtoRepository<D, C, E>
 where
     D: Dto,
     C: CacheDb<D>,
+    E: EventStore,
 {
-    fn event_db(&self) -> &EventDb {
+    /// the `Upcasters` are applied to the old events before decoding them
+    #[must_use]
+    pub fn with_upcasters(mut self, upcasters: Upcasters) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// the `Codec` of the cached models, json by default
+    #[must_use]
+    pub const fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// record the replays and the cache requests in the `Metrics`
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+}
+
+impl<D, C, E> Repository<D, C, E> for DtoRepository<D, C, E>
+where
+    D: Dto,
+    C: CacheDb<D>,
+    E: EventStore,
+{
+    fn event_db(&self) -> &E {
         &self.event_db
     }
     fn cache_db(&self) -> &C {
@@ -311,29 +587,51 @@ where
     fn repository_kind(&self) -> &RepositoryKind {
         &self.repository_kind
     }
+
+    fn upcasters(&self) -> &Upcasters {
+        &self.upcasters
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    #[cfg(feature = "metrics")]
+    fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
 }
 
-impl<S, C> StateRepositoryConstructor<S, C> for StateRepository<S, C>
+impl<S, C, E> StateRepositoryConstructor<S, C, E> for StateRepository<S, C, E>
 where
     S: State,
     C: CacheDb<S>,
+    E: EventStore,
 {
-    fn new(event_db: EventDb, state_db: C) -> Self {
+    fn new(event_db: E, state_db: C) -> Self {
         Self {
             event_db,
             state_db,
             repository_kind: RepositoryKind::State,
+            upcasters: Upcasters::default(),
+            codec: Codec::default(),
+            retry_policy: RetryPolicy::default(),
+            idempotency_window: 100,
+            #[cfg(feature = "metrics")]
+            metrics: None,
             state: PhantomData,
         }
     }
 }
 
-impl<S, C> Repository<S, C> for StateRepository<S, C>
+#[async_trait]
+impl<S, C, E> Repository<S, C, E> for StateRepository<S, C, E>
 where
     S: State,
     C: CacheDb<S>,
+    E: EventStore,
 {
-    fn event_db(&self) -> &EventDb {
+    fn event_db(&self) -> &E {
         &self.event_db
     }
     fn cache_db(&self) -> &C {
@@ -343,6 +641,44 @@ where
     fn repository_kind(&self) -> &RepositoryKind {
         &self.repository_kind
     }
+
+    fn upcasters(&self) -> &Upcasters {
+        &self.upcasters
+    }
+
+    fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    #[cfg(feature = "metrics")]
+    fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    #[cfg(feature = "metrics")]
+    fn metrics_label(&self) -> &'static str {
+        S::state_name()
+    }
+
+    async fn get_snapshot(
+        &self,
+        key: &ModelKey,
+    ) -> Result<Option<ModelWithPosition<S>>, EventSourceError> {
+        if !S::snapshot_policy().is_enabled() {
+            return Ok(None);
+        }
+
+        let Some(event) = self
+            .event_db
+            .read_last_event(&snapshot::snapshot_stream(key))
+            .await
+            .map_err(EventSourceError::EventStore)?
+        else {
+            return Ok(None);
+        };
+
+        snapshot::from_event(&event, S::snapshot_version()).map_err(EventSourceError::Serde)
+    }
 }
 
 /// Appending event can resolve with multiple correct behavior
@@ -355,14 +691,54 @@ pub enum AddedEvent {
     NeedRetry,
 }
 
-impl<S, C> StateRepository<S, C>
+impl<S, C, E> StateRepository<S, C, E>
 where
     S: State,
     C: CacheDb<S>,
+    E: EventStore,
 {
+    /// the `Upcasters` are applied to the old events before decoding them
+    #[must_use]
+    pub fn with_upcasters(mut self, upcasters: Upcasters) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// the `Codec` of the new events and of the cached models, json by default.
+    /// The events written with a binary codec are only readable with `StoredEvent::decode`
+    #[must_use]
+    pub const fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    /// the `RetryPolicy` used when the stream changed during `add_command`
+    #[must_use]
+    pub const fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+
+    /// `add_idempotent_command` look for the key in the last `window` entries of the stream only
+    /// (at least one), 100 by default : an older command is appended again
+    #[must_use]
+    pub fn with_idempotency_window(mut self, window: u64) -> Self {
+        self.idempotency_window = window.max(1);
+        self
+    }
+
+    /// record the commands, the replays and the cache requests in the `Metrics`
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
     /// # Errors
     ///
     /// Will return `Err` if events cannot be added to the eventstore
+    /// or `RetryExhausted` if the stream kept changing until the `RetryPolicy` gave up
     pub async fn add_command(
         &self,
         key: &ModelKey,
@@ -372,106 +748,511 @@ where
     where
         S: State,
     {
-        let mut model: S;
-        let events: Vec<S::Event>;
+        self.append_command(key, command, previous_metadata, None, None, &SyncHandler)
+            .await
+            .map(CommandOutcome::into_state)
+    }
 
-        loop {
-            let (l_model, l_events, retry) = self
-                .try_append(key, command.clone(), previous_metadata)
+    /// same as `add_command`, the `MetadataExtensions` are recorded with the command and its events,
+    /// over the ones inherited from the previous metadata
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if events cannot be added to the eventstore
+    /// or `RetryExhausted` if the stream kept changing until the `RetryPolicy` gave up
+    pub async fn add_command_with_extensions(
+        &self,
+        key: &ModelKey,
+        command: S::Command,
+        extensions: &MetadataExtensions,
+        previous_metadata: Option<&Metadata>,
+    ) -> Result<S, EventSourceStateError> {
+        self.append_command(
+            key,
+            command,
+            previous_metadata,
+            None,
+            Some(extensions),
+            &SyncHandler,
+        )
+        .await
+        .map(CommandOutcome::into_state)
+    }
+
+    /// same as `add_command`, with the appended events, their metadata and the new revision
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if events cannot be added to the eventstore
+    /// or `RetryExhausted` if the stream kept changing until the `RetryPolicy` gave up
+    pub async fn add_command_detailed(
+        &self,
+        key: &ModelKey,
+        command: S::Command,
+        previous_metadata: Option<&Metadata>,
+    ) -> Result<CommandOutcome<S>, EventSourceStateError> {
+        self.append_command(key, command, previous_metadata, None, None, &SyncHandler)
+            .await
+    }
+
+    /// same as `add_command` for an `AsyncState`, the handler receive the context
+    /// and is run again on each retry
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if events cannot be added to the eventstore
+    /// or `RetryExhausted` if the stream kept changing until the `RetryPolicy` gave up
+    pub async fn add_command_with_context(
+        &self,
+        key: &ModelKey,
+        command: S::Command,
+        context: &S::Context,
+        previous_metadata: Option<&Metadata>,
+    ) -> Result<S, EventSourceStateError>
+    where
+        S: AsyncState,
+    {
+        self.append_command(
+            key,
+            command,
+            previous_metadata,
+            None,
+            None,
+            &ContextHandler(context),
+        )
+        .await
+        .map(CommandOutcome::into_state)
+    }
+
+    /// the command is appended only once per `idempotency_key`,
+    /// when the key is in the last entries of the stream (see `with_idempotency_window`)
+    /// nothing is appended and the state right after the original command is returned
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if events cannot be added to the eventstore
+    /// or `RetryExhausted` if the stream kept changing until the `RetryPolicy` gave up
+    pub async fn add_idempotent_command(
+        &self,
+        key: &ModelKey,
+        command: S::Command,
+        idempotency_key: &str,
+        previous_metadata: Option<&Metadata>,
+    ) -> Result<S, EventSourceStateError> {
+        self.append_command(
+            key,
+            command,
+            previous_metadata,
+            Some(idempotency_key),
+            None,
+            &SyncHandler,
+        )
+        .await
+        .map(CommandOutcome::into_state)
+    }
+
+    #[cfg_attr(
+        feature = "telemetry",
+        tracing::instrument(
+            name = "add_command",
+            skip_all,
+            fields(key = %key, command = crate::Command::command_name(&command)),
+            err(Display)
+        )
+    )]
+    async fn append_command<H>(
+        &self,
+        key: &ModelKey,
+        command: S::Command,
+        previous_metadata: Option<&Metadata>,
+        idempotency_key: Option<&str>,
+        extensions: Option<&MetadataExtensions>,
+        handler: &H,
+    ) -> Result<CommandOutcome<S>, EventSourceStateError>
+    where
+        H: CommandHandler<S>,
+    {
+        let started = Instant::now();
+        let mut attempts = 0;
+
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.metrics {
+            metrics.command(S::state_name());
+        }
+
+        // the last entry searched for the idempotency key
+        let mut scanned = None;
+
+        let attempt = loop {
+            // checked on each attempt : the concurrent command may be the same one
+            if let Some(idempotency_key) = idempotency_key
+                && let Some(outcome) = self
+                    .find_outcome(key, idempotency_key, &mut scanned)
+                    .await?
+            {
+                return Ok(outcome);
+            }
+
+            let attempt = self
+                .try_append(
+                    key,
+                    command.clone(),
+                    previous_metadata,
+                    idempotency_key,
+                    extensions,
+                    handler,
+                )
                 .await?;
-            if retry == AddedEvent::NeedRetry {
+            attempts += 1;
+
+            if attempt.added == AddedEvent::Success {
+                break attempt;
+            }
+
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &self.metrics {
+                metrics.retry(S::state_name());
+            }
+
+            let Some(backoff) = self.retry_policy.next_backoff(attempts, started) else {
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &self.metrics {
+                    metrics.rejection(S::state_name(), "retry_exhausted");
+                }
+
+                return Err(EventSourceError::RetryExhausted {
+                    attempts,
+                    last_revision: attempt.model.position,
+                }
+                .into());
+            };
+            retry::wait(backoff).await;
+        };
+
+        let Attempt {
+            mut model,
+            events,
+            command_metadata,
+            appended_metadata,
+            ..
+        } = attempt;
+
+        let previous = model.position;
+
+        for event in &events {
+            model.model.play_event(event);
+        }
+
+        // the command then the events follow the previous position
+        let command_position = previous.map_or(0, |p| p + 1);
+        let position = command_position + events.len() as u64;
+
+        if S::snapshot_policy().is_due(previous, position) {
+            model.position = Some(position);
+            // the command is stored, a missing snapshot only make the next replay longer :
+            // the error is not returned, only traced and counted
+            if let Err(e) = self.write_snapshot(key, &model).await {
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &self.metrics {
+                    metrics.snapshot_failure(S::state_name());
+                }
+
+                #[cfg(feature = "telemetry")]
+                tracing::warn!(key = %key, "snapshot not written : {e}");
+                #[cfg(not(feature = "telemetry"))]
+                let _ = e;
+            }
+        }
+
+        let appended = events
+            .into_iter()
+            .zip(appended_metadata)
+            .zip(command_position + 1..)
+            .map(|((event, metadata), position)| AppendedEvent::new(event, position, metadata))
+            .collect();
+
+        Ok(CommandOutcome::new(
+            model.model,
+            command_metadata,
+            appended,
+            position,
+        ))
+    }
+
+    /// look for the command sent with the key, `None` if no command was sent with it
+    ///
+    /// only the metadata of the last `idempotency_window` entries are read,
+    /// then from the entry after `scanned` : a retry read the new entries only.
+    /// When the command is found, the state after its events start from the snapshot or the cache.
+    async fn find_outcome(
+        &self,
+        key: &ModelKey,
+        idempotency_key: &str,
+        scanned: &mut Option<u64>,
+    ) -> Result<Option<CommandOutcome<S>>, EventSourceError> {
+        let from = if let Some(revision) = scanned {
+            *revision + 1
+        } else {
+            let Some(last) = self.event_db.read_last_event(&key.format()).await? else {
+                return Ok(None);
+            };
+            (last.revision() + 1).saturating_sub(self.idempotency_window)
+        };
+
+        let mut stream = self
+            .event_db
+            .read_stream(&key.format(), StreamPosition::Position(from))
+            .await?;
+
+        let mut found: Option<(Metadata, u64)> = None;
+        let mut appended = Vec::new();
+
+        loop {
+            let original_event = match stream.next().await {
+                Ok(Some(original_event)) => original_event,
+                Ok(None) | Err(Error::ResourceNotFound) => break,
+                Err(e) => return Err(e.into()),
+            };
+            let metadata = original_event.metadata()?;
+
+            if metadata.is_event() {
+                if found.is_some() {
+                    appended.push((original_event, metadata));
+                    continue;
+                }
+            } else if found.is_some() {
+                // the events of a command are appended right after it
+                break;
+            } else if metadata.idempotency_key() == Some(idempotency_key) {
+                found = Some((metadata, original_event.revision()));
                 continue;
             }
 
-            model = l_model;
-            events = l_events;
+            *scanned = Some(original_event.revision());
+        }
 
-            break;
+        let Some((command_metadata, command_position)) = found else {
+            return Ok(None);
+        };
+
+        let revision = appended
+            .last()
+            .map_or(command_position, |(original_event, _)| {
+                original_event.revision()
+            });
+
+        let model = self.get_model_at_revision(key, revision).await?;
+
+        let appended = appended
+            .into_iter()
+            .map(|(original_event, metadata)| {
+                let event = self
+                    .upcasters
+                    .decode::<S::Event>(&original_event, &metadata)?;
+
+                Ok(AppendedEvent::new(
+                    event,
+                    original_event.revision(),
+                    metadata,
+                ))
+            })
+            .collect::<Result<Vec<_>, EventSourceError>>()?;
+
+        Ok(Some(CommandOutcome::new(
+            model.model,
+            command_metadata,
+            appended,
+            revision,
+        )))
+    }
+
+    /// the commands and the events of the stream within `revisions`, oldest first,
+    /// an unknown entity has an empty history
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached or an entry cannot be decoded
+    pub async fn history(
+        &self,
+        key: &ModelKey,
+        revisions: Range<u64>,
+    ) -> Result<HistoryPage<S::Command, S::Event>, EventSourceError> {
+        let mut stream = self
+            .event_db
+            .read_stream(&key.format(), StreamPosition::Position(revisions.start))
+            .await
+            .map_err(EventSourceError::EventStore)?;
+
+        let mut entries = Vec::new();
+        let mut next = None;
+
+        loop {
+            let stored = match stream.next().await {
+                Ok(Some(stored)) => stored,
+                Ok(None) | Err(Error::ResourceNotFound) => break,
+                Err(e) => return Err(EventSourceError::EventStore(e)),
+            };
+
+            if stored.revision() >= revisions.end {
+                next = Some(stored.revision());
+                break;
+            }
+
+            let metadata = stored.metadata().map_err(EventSourceError::Serde)?;
+
+            let payload = if metadata.is_event() {
+                HistoryPayload::Event(
+                    self.upcasters
+                        .decode(&stored, &metadata)
+                        .map_err(EventSourceError::Serde)?,
+                )
+            } else {
+                HistoryPayload::Command(stored.decode(&metadata).map_err(EventSourceError::Serde)?)
+            };
+
+            entries.push(HistoryEntry::new(
+                stored.revision(),
+                metadata,
+                stored.created(),
+                payload,
+            ));
         }
 
-        for event in &events {
-            model.play_event(event);
+        Ok(HistoryPage::new(entries, next))
+    }
+
+    /// write a snapshot of the current model in the companion stream,
+    /// return the position of the snapshot.
+    /// Nothing is written when the `SnapshotPolicy` is `Never` or the stream is empty.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the model cannot be computed or the snapshot cannot be written
+    pub async fn snapshot(&self, key: &ModelKey) -> Result<Option<u64>, EventSourceError> {
+        if !S::snapshot_policy().is_enabled() {
+            return Ok(None);
+        }
+
+        let model = self.get_model(key).await?;
+
+        if model.position.is_none() {
+            return Ok(None);
         }
 
-        Ok(model)
+        self.write_snapshot(key, &model).await?;
+
+        Ok(model.position)
+    }
+
+    #[cfg_attr(
+        feature = "telemetry",
+        tracing::instrument(skip_all, fields(key = %key))
+    )]
+    async fn write_snapshot(
+        &self,
+        key: &ModelKey,
+        model: &ModelWithPosition<S>,
+    ) -> Result<(), EventSourceError> {
+        let event = snapshot::to_event(S::state_name(), S::snapshot_version(), model)?;
+        let stream = snapshot::snapshot_stream(key);
+
+        self.event_db
+            .append_to_stream(&stream, StreamState::Any, vec![event])
+            .await?;
+
+        // only the latest snapshot is read, set on every write
+        // so the streams written before the `$maxCount` was set are bounded too
+        self.event_db.set_max_count(&stream, 1).await?;
+
+        Ok(())
     }
 
-    async fn try_append(
+    #[cfg_attr(
+        feature = "telemetry",
+        tracing::instrument(skip_all, fields(key = %key), err(Display))
+    )]
+    async fn try_append<H>(
         &self,
         key: &ModelKey,
         command: S::Command,
         previous_metadata: Option<&Metadata>,
-    ) -> Result<(S, Vec<S::Event>, AddedEvent), EventSourceStateError>
+        idempotency_key: Option<&str>,
+        extensions: Option<&MetadataExtensions>,
+        handler: &H,
+    ) -> Result<Attempt<S>, EventSourceStateError>
     where
         S: State + Sync,
+        H: CommandHandler<S>,
     {
         let model: ModelWithPosition<S> = self
             .get_model(key)
             .await
             .map_err(EventSourceStateError::EventSourceError)?;
 
-        let state = model.model;
-
-        let events = state
-            .try_command(command.clone())
-            .map_err(|e| EventSourceStateError::State(format!("{e}")))?;
+        let events = handler
+            .handle(&model.model, command.clone())
+            .await
+            .map_err(|e| {
+                #[cfg(feature = "metrics")]
+                if let Some(metrics) = &self.metrics {
+                    metrics.rejection(S::state_name(), &metrics::error_label(&e));
+                }
+                EventSourceStateError::State(format!("{e}"))
+            })?;
 
-        let options = model.position.map_or_else(
-            || AppendToStreamOptions::default().stream_state(StreamState::NoStream),
-            |position| {
-                AppendToStreamOptions::default().stream_state(StreamState::StreamRevision(position))
-            },
-        );
+        let expected = model
+            .position
+            .map_or(StreamState::NoStream, StreamState::StreamRevision);
 
-        let command_metadata = CompleteEvent::from_command(&command, previous_metadata)
+        let mut command_metadata = CompleteEvent::from_command(&command, previous_metadata)
             .map_err(|e| EventSourceStateError::EventSourceError(EventSourceError::Serde(e)))?;
+        command_metadata.set_idempotency_key(idempotency_key);
+        if let Some(extensions) = extensions {
+            command_metadata.merge_extensions(extensions);
+        }
 
         let mut events_data = vec![command_metadata.clone()];
+        let mut appended_metadata = Vec::with_capacity(events.len());
 
         let mut previous_metadata = command_metadata.metadata().to_owned();
 
-        let res_events = events.clone();
-
-        for event in events {
-            let event_metadata = CompleteEvent::from_event(&event, &previous_metadata)
-                .map_err(|e| EventSourceStateError::EventSourceError(EventSourceError::Serde(e)))?;
+        for event in &events {
+            let event_metadata =
+                CompleteEvent::from_event_with_codec(event, &previous_metadata, self.codec)
+                    .map_err(|e| {
+                        EventSourceStateError::EventSourceError(EventSourceError::Serde(e))
+                    })?;
 
             events_data.push(event_metadata.clone());
+            appended_metadata.push(event_metadata.metadata().clone());
             event_metadata.metadata().clone_into(&mut previous_metadata);
         }
 
-        let retry = self
-            .try_append_event_data(key, &options, events_data)
+        let added = self
+            .try_append_event_data(key, expected, events_data)
             .await?;
 
-        Ok((state, res_events, retry))
+        Ok(Attempt {
+            model,
+            events,
+            command_metadata: command_metadata.metadata().clone(),
+            appended_metadata,
+            added,
+        })
     }
 
     async fn try_append_event_data(
         &self,
         key: &ModelKey,
-        options: &AppendToStreamOptions,
+        expected: StreamState,
         events_with_data: Vec<CompleteEvent>,
     ) -> Result<AddedEvent, EventSourceStateError>
     where
         S: State,
     {
-        let mut err = Ok(());
-        let events: Vec<EventData> = events_with_data
-            .into_iter()
-            .filter_map(|e| match e.full_event_data() {
-                Ok(event) => Some(event),
-                Err(e) => {
-                    err = Err(EventSourceError::Serde(e));
-                    None
-                }
-            })
-            .collect();
-        err.map_err(EventSourceStateError::EventSourceError)?;
-
         let appended = self
             .event_db
-            .append_to_stream(key.format(), options, events)
+            .append_to_stream(&key.format(), expected, events_with_data)
             .await;
 
         match appended {
@@ -483,3 +1264,48 @@ where
         }
     }
 }
+
+/// one `try_append`, the model is the one the command was tried on
+struct Attempt<S>
+where
+    S: State,
+{
+    model: ModelWithPosition<S>,
+    events: Vec<S::Event>,
+    command_metadata: Metadata,
+    appended_metadata: Vec<Metadata>,
+    added: AddedEvent,
+}
+
+/// the part of `add_command` that differ between `State` and `AsyncState`
+#[async_trait]
+trait CommandHandler<S>: Sync
+where
+    S: State,
+{
+    async fn handle(&self, state: &S, command: S::Command) -> Result<Vec<S::Event>, S::Error>;
+}
+
+struct SyncHandler;
+
+#[async_trait]
+impl<S> CommandHandler<S> for SyncHandler
+where
+    S: State,
+{
+    async fn handle(&self, state: &S, command: S::Command) -> Result<Vec<S::Event>, S::Error> {
+        state.try_command(command)
+    }
+}
+
+struct ContextHandler<'c, C>(&'c C);
+
+#[async_trait]
+impl<S> CommandHandler<S> for ContextHandler<'_, S::Context>
+where
+    S: AsyncState,
+{
+    async fn handle(&self, state: &S, command: S::Command) -> Result<Vec<S::Event>, S::Error> {
+        state.try_command_with_context(command, self.0).await
+    }
+}
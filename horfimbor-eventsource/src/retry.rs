@@ -0,0 +1,122 @@
+//! bounded retries of `StateRepository::add_command`
+//!
+//! a command is retried when another one was appended to the stream in between,
+//! under heavy contention the attempts are spread with an exponential backoff and jitter.
+
+use std::time::{Duration, Instant};
+
+/// `RetryPolicy` bound the attempts of a command on a concurrently modified stream
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    deadline: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// 10 attempts, the backoff start at 5ms and is capped at 500ms, no deadline
+    fn default() -> Self {
+        Self {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(5),
+            max_backoff: Duration::from_millis(500),
+            deadline: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// the default backoff with another number of attempts, at least one
+    #[must_use]
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: max_attempts.max(1),
+            ..Self::default()
+        }
+    }
+
+    /// the wait before the n-th retry is `initial * 2^(n-1)` capped at `max`, half of it is random
+    #[must_use]
+    pub const fn with_backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// no attempt is started once the deadline is reached, counted from the first attempt
+    #[must_use]
+    pub const fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn deadline(&self) -> Option<Duration> {
+        self.deadline
+    }
+
+    /// the backoff before the retry following `attempts` failed attempts,
+    /// `None` when the policy is exhausted
+    pub(crate) fn next_backoff(&self, attempts: u32, started: Instant) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(self.max_backoff);
+
+        let half = exponential / 2;
+        let backoff = half + half.mul_f64(rand::random::<f64>());
+
+        match self.deadline {
+            Some(deadline) if started.elapsed() + backoff >= deadline => None,
+            _ => Some(backoff),
+        }
+    }
+}
+
+/// wait on the tokio timer, the commands are sent from a tokio runtime
+pub(crate) async fn wait(backoff: Duration) {
+    if backoff.is_zero() {
+        return;
+    }
+
+    tokio::time::sleep(backoff).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_is_bounded() {
+        let policy =
+            RetryPolicy::new(4).with_backoff(Duration::from_millis(10), Duration::from_millis(30));
+        let started = Instant::now();
+
+        let first = policy.next_backoff(1, started).expect("first retry");
+        assert!(first >= Duration::from_millis(5) && first <= Duration::from_millis(10));
+
+        let third = policy.next_backoff(3, started).expect("third retry");
+        assert!(third >= Duration::from_millis(15) && third <= Duration::from_millis(30));
+
+        assert_eq!(policy.next_backoff(4, started), None);
+    }
+
+    #[test]
+    fn deadline_stop_the_retries() {
+        let policy = RetryPolicy::new(100).with_deadline(Duration::ZERO);
+
+        assert_eq!(policy.next_backoff(1, Instant::now()), None);
+    }
+}
@@ -0,0 +1,379 @@
+//! process managers : events of some entities become commands on other ones
+//!
+//! a `Saga` listen to a `Stream` with a persistent subscription,
+//! each event is handled with the state of its saga instance
+//! and the commands are sent with the event as `previous_metadata`.
+//! The saga state and the last handled revision of each source stream are written
+//! in the `saga_{name}-{id}` stream, keeping only the last one (`$maxCount` is 1),
+//! the event is acked only after that.
+//!
+//! The commands are idempotent on the handled event :
+//! when the event is delivered again after a crash, the commands already appended are not sent twice.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use kurrentdb::{Error, NakAction, StreamState};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::cache_db::CacheDb;
+use crate::event_store::{EventStore, PersistentEventSubscription, ReceivedEvent, StoredEvent};
+use crate::helper::get_persistent_subscription;
+use crate::metadata::{CompleteEvent, Metadata};
+use crate::model_key::ModelKey;
+use crate::repository::StateRepository;
+use crate::upcaster::Upcasters;
+use crate::{Event, EventSourceError, EventSourceStateError, State, Stream};
+
+/// `Saga` map the events of a `Stream` to commands on other `StateRepository`
+#[async_trait]
+pub trait Saga: Send + Sync {
+    /// the state of one saga instance, kept between its events
+    type State: Default + Serialize + DeserializeOwned + Debug + Clone + Send + Sync;
+
+    /// the events listened to, the other ones are acked without being handled
+    type Event: Event + Sync;
+
+    /// the name of the saga, it is the group name of the subscription
+    fn saga_name(&self) -> &'static str;
+
+    /// the stream listened to
+    fn stream(&self) -> Stream;
+
+    /// only the events with an accepted `EventName` are decoded,
+    /// the commands are never given to the saga
+    fn accept(&self, event_type: &str) -> bool;
+
+    /// the saga instance handling the event, `None` to ignore it,
+    /// by default there is one instance per correlation id
+    fn saga_id(&self, _key: &ModelKey, _event: &Self::Event, metadata: &Metadata) -> Option<Uuid> {
+        Some(metadata.correlation_id())
+    }
+
+    /// the event is not acked when an `Err` is returned and will be delivered again,
+    /// it is parked after `SagaRunner::with_max_failures` failures.
+    /// The state changes are kept only on success
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if a command cannot be appended or the saga reject the event
+    async fn handle(
+        &self,
+        state: &mut Self::State,
+        key: &ModelKey,
+        event: &Self::Event,
+        commands: &mut SagaCommands,
+    ) -> Result<(), EventSourceStateError>;
+}
+
+/// `SagaCommands` send the commands caused by the handled event
+pub struct SagaCommands {
+    saga_name: &'static str,
+    metadata: Metadata,
+    sent: usize,
+}
+
+impl SagaCommands {
+    const fn new(saga_name: &'static str, metadata: Metadata) -> Self {
+        Self {
+            saga_name,
+            metadata,
+            sent: 0,
+        }
+    }
+
+    /// the `Metadata` of the handled event, given as `previous_metadata` to every command
+    #[must_use]
+    pub const fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// the command is idempotent on the handled event and its order in `handle`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the command is rejected or cannot be added to the eventstore
+    pub async fn send<S, C, E>(
+        &mut self,
+        repository: &StateRepository<S, C, E>,
+        key: &ModelKey,
+        command: S::Command,
+    ) -> Result<S, EventSourceStateError>
+    where
+        S: State,
+        C: CacheDb<S> + Sync,
+        E: EventStore,
+    {
+        let idempotency_key = format!(
+            "{}-{}-{}",
+            self.saga_name,
+            self.metadata.id().unwrap_or_default(),
+            self.sent
+        );
+        self.sent += 1;
+
+        repository
+            .add_idempotent_command(key, command, &idempotency_key, Some(&self.metadata))
+            .await
+    }
+}
+
+/// what is written after each handled event, only the last one is kept.
+/// `positions` hold one entry per source stream the instance handled an event of,
+/// it is never pruned : an instance should span a handful of entities, not a whole category
+#[derive(Serialize, Deserialize)]
+struct SagaCheckpoint<T> {
+    state: T,
+    positions: HashMap<String, u64>,
+}
+
+/// the fate of a received event
+enum Handled {
+    Ack,
+    Retry(String),
+    Failed(String),
+    Park(String),
+}
+
+/// `SagaRunner` deliver the events of the subscription to the `Saga`
+#[derive(Clone)]
+pub struct SagaRunner<P, E>
+where
+    P: Saga,
+    E: EventStore,
+{
+    saga: P,
+    event_db: E,
+    upcasters: Upcasters,
+    max_failures: u32,
+}
+
+impl<P, E> SagaRunner<P, E>
+where
+    P: Saga,
+    E: EventStore,
+{
+    /// straight forward constructor
+    #[must_use]
+    pub fn new(event_db: E, saga: P) -> Self {
+        Self {
+            saga,
+            event_db,
+            upcasters: Upcasters::default(),
+            max_failures: 3,
+        }
+    }
+
+    /// the `Upcasters` are applied to the old events before decoding them
+    #[must_use]
+    pub fn with_upcasters(mut self, upcasters: Upcasters) -> Self {
+        self.upcasters = upcasters;
+        self
+    }
+
+    /// the number of failures of `Saga::handle` on an event before it is parked, at least one,
+    /// 3 by default
+    #[must_use]
+    pub fn with_max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn saga(&self) -> &P {
+        &self.saga
+    }
+
+    /// handle the events until the subscription is dropped,
+    /// a failed event is retried until `max_failures`, then parked like an event that cannot be decoded
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the subscription cannot be created or the store cannot be reached
+    pub async fn run(&self) -> Result<(), EventSourceError> {
+        let stream = self.saga.stream();
+        let mut sub =
+            get_persistent_subscription(&self.event_db, &stream, self.saga.saga_name()).await?;
+
+        let mut failures: HashMap<Uuid, u32> = HashMap::new();
+
+        loop {
+            let rcv_event = sub.next().await?;
+
+            #[cfg(feature = "telemetry")]
+            let handled = match rcv_event.event() {
+                Some(stored) => {
+                    let span = crate::telemetry::event_span(self.saga.saga_name(), stored);
+                    tracing::Instrument::instrument(self.handle(&rcv_event), span).await?
+                }
+                None => self.handle(&rcv_event).await?,
+            };
+            #[cfg(not(feature = "telemetry"))]
+            let handled = self.handle(&rcv_event).await?;
+
+            match handled {
+                Handled::Ack => {
+                    failures.remove(&rcv_event.ack_id());
+                    sub.ack(&rcv_event).await?;
+                }
+                Handled::Retry(reason) => {
+                    sub.nack(&rcv_event, NakAction::Retry, &reason).await?;
+                }
+                Handled::Failed(reason) => {
+                    let failed = failures.entry(rcv_event.ack_id()).or_default();
+                    *failed += 1;
+
+                    if *failed >= self.max_failures {
+                        failures.remove(&rcv_event.ack_id());
+                        sub.nack(&rcv_event, NakAction::Park, &reason).await?;
+                    } else {
+                        sub.nack(&rcv_event, NakAction::Retry, &reason).await?;
+                    }
+                }
+                Handled::Park(reason) => {
+                    sub.nack(&rcv_event, NakAction::Park, &reason).await?;
+                }
+            }
+        }
+    }
+
+    /// the state of a saga instance, `None` before its first event
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the store cannot be reached or the state cannot be decoded
+    pub async fn saga_state(&self, saga_id: Uuid) -> Result<Option<P::State>, EventSourceError> {
+        Ok(self
+            .read_checkpoint(saga_id)
+            .await?
+            .map(|(checkpoint, _)| checkpoint.state))
+    }
+
+    async fn handle(&self, rcv_event: &ReceivedEvent) -> Result<Handled, EventSourceError> {
+        let Some(stored) = rcv_event.event() else {
+            return Ok(Handled::Ack);
+        };
+
+        let metadata = match stored.metadata() {
+            Ok(metadata) => metadata,
+            Err(e) => return Ok(Handled::Park(e.to_string())),
+        };
+
+        if !metadata.is_event() || !self.saga.accept(stored.event_type()) {
+            return Ok(Handled::Ack);
+        }
+
+        let (key, event) = match self.decode(stored, &metadata) {
+            Ok(decoded) => decoded,
+            Err(e) => return Ok(Handled::Park(e.to_string())),
+        };
+
+        let Some(saga_id) = self.saga.saga_id(&key, &event, &metadata) else {
+            return Ok(Handled::Ack);
+        };
+
+        let (mut checkpoint, revision) =
+            self.read_checkpoint(saga_id).await?.unwrap_or_else(|| {
+                (
+                    SagaCheckpoint {
+                        state: P::State::default(),
+                        positions: HashMap::new(),
+                    },
+                    None,
+                )
+            });
+
+        if checkpoint
+            .positions
+            .get(stored.stream_id())
+            .is_some_and(|position| *position >= stored.revision())
+        {
+            return Ok(Handled::Ack);
+        }
+
+        let mut commands = SagaCommands::new(self.saga.saga_name(), metadata);
+
+        if let Err(e) = self
+            .saga
+            .handle(&mut checkpoint.state, &key, &event, &mut commands)
+            .await
+        {
+            return Ok(Handled::Failed(e.to_string()));
+        }
+
+        checkpoint
+            .positions
+            .insert(stored.stream_id().to_string(), stored.revision());
+
+        self.write_checkpoint(saga_id, &checkpoint, revision).await
+    }
+
+    fn decode(
+        &self,
+        stored: &StoredEvent,
+        metadata: &Metadata,
+    ) -> Result<(ModelKey, P::Event), EventSourceError> {
+        let key: ModelKey = stored.stream_id().try_into()?;
+        let event = self.upcasters.decode::<P::Event>(stored, metadata)?;
+
+        Ok((key, event))
+    }
+
+    async fn read_checkpoint(
+        &self,
+        saga_id: Uuid,
+    ) -> Result<Option<(SagaCheckpoint<P::State>, Option<u64>)>, EventSourceError> {
+        let Some(last) = self
+            .event_db
+            .read_last_event(&self.saga_stream(saga_id))
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        let checkpoint = last.as_json()?;
+
+        Ok(Some((checkpoint, Some(last.revision()))))
+    }
+
+    async fn write_checkpoint(
+        &self,
+        saga_id: Uuid,
+        checkpoint: &SagaCheckpoint<P::State>,
+        revision: Option<u64>,
+    ) -> Result<Handled, EventSourceError> {
+        let event =
+            CompleteEvent::checkpoint(&format!("{}.saga", self.saga.saga_name()), checkpoint)?;
+
+        let expected = revision.map_or(StreamState::NoStream, StreamState::StreamRevision);
+
+        let appended = self
+            .event_db
+            .append_to_stream(&self.saga_stream(saga_id), expected, vec![event])
+            .await;
+
+        match appended {
+            Ok(_) if revision.is_none() => {
+                self.event_db
+                    .set_max_count(&self.saga_stream(saga_id), 1)
+                    .await?;
+                Ok(Handled::Ack)
+            }
+            Ok(_) => Ok(Handled::Ack),
+            // another event of the same instance was handled in between, the commands are idempotent
+            Err(Error::WrongExpectedVersion { .. }) => Ok(Handled::Retry(format!(
+                "saga {saga_id} changed while handling the event"
+            ))),
+            Err(e) => Err(EventSourceError::EventStore(e)),
+        }
+    }
+
+    fn saga_stream(&self, saga_id: Uuid) -> String {
+        let name = self.saga.saga_name().replace(['-', '.'], "_");
+        format!("saga_{name}-{saga_id}")
+    }
+}
@@ -0,0 +1,119 @@
+//! snapshots avoid replaying the whole stream of long-lived `State`
+//!
+//! a snapshot is the serialized `ModelWithPosition` written in a companion stream keeping only the last one,
+//! `get_model` start from the latest one and only replay the events after it.
+//! A snapshot written with another `State::snapshot_version` is ignored.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Error as SerdeError;
+
+use crate::StateName;
+use crate::event_store::StoredEvent;
+use crate::metadata::CompleteEvent;
+use crate::model_key::ModelKey;
+use crate::repository::ModelWithPosition;
+
+/// `SnapshotPolicy` tell the `StateRepository` when a snapshot must be written
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SnapshotPolicy {
+    /// snapshots are neither written nor read
+    #[default]
+    Never,
+    /// snapshots are only written by `StateRepository::snapshot`
+    OnDemand,
+    /// a snapshot is written by `add_command` every time the stream grow by this amount,
+    /// `StateRepository::snapshot` can still be called in between
+    Every(u64),
+}
+
+impl SnapshotPolicy {
+    /// snapshots must be read before replaying the stream
+    #[must_use]
+    pub const fn is_enabled(self) -> bool {
+        !matches!(self, Self::Never)
+    }
+
+    /// a stream going from `previous` to `current` crossed a multiple of `Every(n)`
+    pub(crate) const fn is_due(self, previous: Option<u64>, current: u64) -> bool {
+        match self {
+            Self::Never | Self::OnDemand | Self::Every(0) => false,
+            Self::Every(n) => {
+                let before = match previous {
+                    Some(p) => (p + 1) / n,
+                    None => 0,
+                };
+                (current + 1) / n > before
+            }
+        }
+    }
+}
+
+/// snapshots are not part of the category of the state : `snapshot_{name}-{uuid}`
+pub(crate) fn snapshot_stream(key: &ModelKey) -> String {
+    format!("snapshot_{}", key.format())
+}
+
+#[derive(Serialize)]
+struct SnapshotRef<'a, M> {
+    version: u32,
+    #[serde(flatten)]
+    model: &'a ModelWithPosition<M>,
+}
+
+#[derive(Deserialize)]
+struct SnapshotVersion {
+    version: u32,
+}
+
+pub(crate) fn to_event<M>(
+    state_name: StateName,
+    version: u32,
+    model: &ModelWithPosition<M>,
+) -> Result<CompleteEvent, SerdeError>
+where
+    M: Serialize,
+{
+    CompleteEvent::checkpoint(
+        &format!("{state_name}.snapshot"),
+        &SnapshotRef { version, model },
+    )
+}
+
+/// `None` if the snapshot was written with another version
+pub(crate) fn from_event<M>(
+    event: &StoredEvent,
+    version: u32,
+) -> Result<Option<ModelWithPosition<M>>, SerdeError>
+where
+    M: DeserializeOwned,
+{
+    let stored: SnapshotVersion = event.as_json()?;
+    if stored.version != version {
+        return Ok(None);
+    }
+
+    // the version is just an unknown field for the `ModelWithPosition`
+    event.as_json().map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_is_due_when_crossing_a_multiple() {
+        let policy = SnapshotPolicy::Every(5);
+
+        // first command with 3 events : revisions 0 to 3
+        assert!(!policy.is_due(None, 3));
+        // 5 entries in the stream
+        assert!(policy.is_due(None, 4));
+        assert!(policy.is_due(Some(3), 6));
+        assert!(!policy.is_due(Some(4), 8));
+        assert!(policy.is_due(Some(8), 9));
+
+        assert!(!SnapshotPolicy::Every(0).is_due(None, 10));
+        assert!(!SnapshotPolicy::OnDemand.is_due(None, 10));
+    }
+}
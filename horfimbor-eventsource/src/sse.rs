@@ -0,0 +1,123 @@
+//! server side of the `EventStoreState` of `horfimbor-client`, behind the `sse` feature
+//!
+//! the client open `{endpoint}/{path}/{id}/{jwt}` and expect a `Dto`, then the `Event`s, or an `Error`.
+//! `model_messages` is that sequence for one `ModelKey` : the model from `get_model`
+//! followed by the live events after its position, it ends after the first `Error`.
+//! `event_stream` send it as a Rocket `EventStream` with keep-alives ;
+//! checking the jwt and parsing the id stay in the route.
+
+use std::time::Duration;
+
+use futures::{Stream, StreamExt, stream};
+use rocket::response::stream::{Event as SseEvent, EventStream};
+use serde::Serialize;
+
+use crate::Dto;
+use crate::cache_db::CacheDb;
+use crate::event_store::EventStore;
+use crate::helper::get_typed_subscription;
+use crate::model_key::ModelKey;
+use crate::repository::Repository;
+use crate::subscription::TypedSubscription;
+
+/// delay between two keep-alive comments when no event is sent
+pub const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// the messages expected by `horfimbor-client`, untagged : the json of the `Dto`, of the `Event` or the error
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum EventStoreMessage<D>
+where
+    D: Dto,
+{
+    /// the whole model, always the first message
+    Dto(D),
+    /// an event to play on the model
+    Event(D::Event),
+    /// the last message, the client reconnect later
+    Error(String),
+}
+
+enum Step<R, E, D>
+where
+    E: EventStore,
+    D: Dto,
+{
+    Model(R, ModelKey),
+    Live(TypedSubscription<E::Subscription, D::Event>),
+    Done,
+}
+
+/// the model of `key` then its events, as they are appended
+pub fn model_messages<D, C, E, R>(
+    repository: R,
+    key: ModelKey,
+) -> impl Stream<Item = EventStoreMessage<D>> + Send + 'static
+where
+    D: Dto + 'static,
+    C: CacheDb<D> + 'static,
+    E: EventStore + 'static,
+    E::Subscription: 'static,
+    R: Repository<D, C, E> + Sync + 'static,
+{
+    stream::unfold(Step::<R, E, D>::Model(repository, key), |step| async move {
+        match step {
+            Step::Model(repository, key) => match repository.get_model(&key).await {
+                Ok(model) => {
+                    let live = get_typed_subscription::<E, D::Event>(
+                        repository.event_db(),
+                        &crate::Stream::Model(key),
+                        model.position(),
+                    )
+                    .await
+                    .with_upcasters(repository.upcasters().clone());
+
+                    Some((
+                        EventStoreMessage::Dto(model.state().clone()),
+                        Step::Live(live),
+                    ))
+                }
+                Err(e) => Some((EventStoreMessage::Error(e.to_string()), Step::Done)),
+            },
+            Step::Live(mut live) => match live.next().await? {
+                Ok(envelope) => Some((
+                    EventStoreMessage::Event(envelope.into_event()),
+                    Step::Live(live),
+                )),
+                Err(e) => Some((EventStoreMessage::Error(e.to_string()), Step::Done)),
+            },
+            Step::Done => None,
+        }
+    })
+}
+
+/// `model_messages` as server-sent events, a keep-alive comment is sent every `KEEP_ALIVE`
+///
+/// ```rust,ignore
+/// #[get("/counter/<id>/<jwt>")]
+/// fn counter(id: &str, jwt: &str, repo: &State<CounterRepository>) -> Result<EventStream![], Status> {
+///     check_jwt(jwt)?;
+///     let key = ModelKey::new("counter", id.parse().map_err(|_| Status::BadRequest)?);
+///     Ok(sse::event_stream(repo.inner().clone(), key))
+/// }
+/// ```
+pub fn event_stream<D, C, E, R>(
+    repository: R,
+    key: ModelKey,
+) -> EventStream<impl Stream<Item = SseEvent> + Send + 'static>
+where
+    D: Dto + 'static,
+    C: CacheDb<D> + 'static,
+    E: EventStore + 'static,
+    E::Subscription: 'static,
+    R: Repository<D, C, E> + Sync + 'static,
+{
+    let events = model_messages::<D, C, E, R>(repository, key).map(|message| {
+        // a model that cannot be serialized is sent as an error, the json of a string
+        let json = serde_json::to_string(&message)
+            .unwrap_or_else(|e| serde_json::Value::from(e.to_string()).to_string());
+        SseEvent::data(json)
+    });
+
+    EventStream::from(events).heartbeat(KEEP_ALIVE)
+}
@@ -0,0 +1,559 @@
+//! typed subscriptions, the events come decoded with their `ModelKey` and `Metadata`
+//!
+//! `TypedSubscription` and `TypedPersistentSubscription` are `futures::Stream` of `TypedEnvelope` :
+//! the commands, the entries without `Metadata` and the refused event types are skipped,
+//! the events go through the `Upcasters` before being decoded.
+//! With a persistent subscription the skipped events are acked, the undecodable ones are parked,
+//! and every envelope must be acked or nacked once processed.
+//! Dropping a `TypedPersistentSubscription` stop its task : the envelopes not settled yet
+//! cannot be acked anymore and are delivered again.
+
+use std::fmt::{Debug, Formatter};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::Stream;
+use futures::future::BoxFuture;
+use kurrentdb::{Error as EventStoreError, NakAction};
+use serde::de::DeserializeOwned;
+use tokio::sync::mpsc::{self, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::event_store::{
+    EventSubscription, PersistentEventSubscription, ReceivedEvent, StoredEvent,
+};
+use crate::metadata::Metadata;
+use crate::model_key::ModelKey;
+use crate::upcaster::Upcasters;
+use crate::{Event, EventSourceError};
+
+type Accept = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// `TypedEnvelope` is a decoded event with where it come from
+pub struct TypedEnvelope<E> {
+    key: ModelKey,
+    revision: u64,
+    position: u64,
+    metadata: Metadata,
+    event: E,
+    ack: Option<AckHandle>,
+}
+
+impl<E> TypedEnvelope<E> {
+    /// the entity the event belong to
+    #[must_use]
+    pub const fn key(&self) -> &ModelKey {
+        &self.key
+    }
+
+    /// the position of the event in the stream of the entity
+    #[must_use]
+    pub const fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// the position in the subscribed stream, to start a subscription after it
+    #[must_use]
+    pub const fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn event(&self) -> &E {
+        &self.event
+    }
+
+    /// drop the envelope, it cannot be acked anymore
+    #[must_use]
+    pub fn into_event(self) -> E {
+        self.event
+    }
+
+    /// `true` when the envelope come from a persistent subscription and must be acked
+    #[must_use]
+    pub const fn is_persistent(&self) -> bool {
+        self.ack.is_some()
+    }
+
+    /// the event is processed and will not be delivered again,
+    /// nothing to do for a temporary subscription
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the subscription is dropped.
+    pub fn ack(&self) -> impl Future<Output = Result<(), EventStoreError>> + Send + '_ {
+        // only the handle is borrowed, the future stay `Send` whatever the event
+        AckHandle::ack(self.ack.as_ref())
+    }
+
+    /// the event cannot be processed, the action tell the store what to do with it,
+    /// nothing to do for a temporary subscription
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the subscription is dropped.
+    pub fn nack<'a>(
+        &'a self,
+        action: NakAction,
+        reason: &'a str,
+    ) -> impl Future<Output = Result<(), EventStoreError>> + Send + 'a {
+        AckHandle::nack(self.ack.as_ref(), action, reason)
+    }
+}
+
+impl<E> Debug for TypedEnvelope<E>
+where
+    E: Debug,
+{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TypedEnvelope")
+            .field("key", &self.key)
+            .field("revision", &self.revision)
+            .field("position", &self.position)
+            .field("metadata", &self.metadata)
+            .field("event", &self.event)
+            .finish_non_exhaustive()
+    }
+}
+
+/// what the envelope need to ack its event
+struct AckHandle {
+    settle: UnboundedSender<Settlement>,
+    received: ReceivedEvent,
+}
+
+impl AckHandle {
+    async fn ack(handle: Option<&Self>) -> Result<(), EventStoreError> {
+        match handle {
+            Some(handle) => settle(&handle.settle, handle.received.clone(), None).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn nack(
+        handle: Option<&Self>,
+        action: NakAction,
+        reason: &str,
+    ) -> Result<(), EventStoreError> {
+        match handle {
+            Some(handle) => {
+                settle(
+                    &handle.settle,
+                    handle.received.clone(),
+                    Some((action, reason.to_string())),
+                )
+                .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// an ack, or a nack with its action and reason, sent to the driver of the persistent subscription
+struct Settlement {
+    received: ReceivedEvent,
+    nack: Option<(NakAction, String)>,
+    done: oneshot::Sender<Result<(), EventStoreError>>,
+}
+
+async fn settle(
+    sender: &UnboundedSender<Settlement>,
+    received: ReceivedEvent,
+    nack: Option<(NakAction, String)>,
+) -> Result<(), EventStoreError> {
+    let (done, settled) = oneshot::channel();
+
+    sender
+        .send(Settlement {
+            received,
+            nack,
+            done,
+        })
+        .map_err(|_| EventStoreError::ConnectionClosed)?;
+
+    settled
+        .await
+        .unwrap_or(Err(EventStoreError::ConnectionClosed))
+}
+
+/// how the stored events are turned into envelopes
+struct Decoder<E> {
+    upcasters: Upcasters,
+    accept: Option<Accept>,
+    event: PhantomData<fn() -> E>,
+}
+
+impl<E> Clone for Decoder<E> {
+    fn clone(&self) -> Self {
+        Self {
+            upcasters: self.upcasters.clone(),
+            accept: self.accept.clone(),
+            event: PhantomData,
+        }
+    }
+}
+
+impl<E> Decoder<E>
+where
+    E: Event + DeserializeOwned,
+{
+    fn new() -> Self {
+        Self {
+            upcasters: Upcasters::default(),
+            accept: None,
+            event: PhantomData,
+        }
+    }
+
+    /// `None` for the skipped entries
+    fn decode(&self, stored: &StoredEvent) -> Option<Result<TypedEnvelope<E>, EventSourceError>> {
+        let metadata = stored.metadata().ok()?;
+
+        if !metadata.is_event()
+            || !self
+                .accept
+                .as_ref()
+                .is_none_or(|accept| accept(stored.event_type()))
+        {
+            return None;
+        }
+
+        Some(self.envelope(stored, metadata))
+    }
+
+    fn envelope(
+        &self,
+        stored: &StoredEvent,
+        metadata: Metadata,
+    ) -> Result<TypedEnvelope<E>, EventSourceError> {
+        let key: ModelKey = stored
+            .stream_id()
+            .try_into()
+            .map_err(EventSourceError::ModelKey)?;
+
+        let event = self
+            .upcasters
+            .decode(stored, &metadata)
+            .map_err(EventSourceError::Serde)?;
+
+        Ok(TypedEnvelope {
+            key,
+            revision: stored.revision(),
+            position: stored.position(),
+            metadata,
+            event,
+            ack: None,
+        })
+    }
+}
+
+type NextTemporary<S, E> = BoxFuture<'static, (S, Result<TypedEnvelope<E>, EventSourceError>)>;
+
+/// `TypedSubscription` wrap a temporary subscription
+pub struct TypedSubscription<S, E> {
+    subscription: Option<S>,
+    next: Option<NextTemporary<S, E>>,
+    decoder: Decoder<E>,
+}
+
+// the subscription is moved in and out of the boxed future, it is never pinned
+impl<S, E> Unpin for TypedSubscription<S, E> {}
+
+impl<S, E> TypedSubscription<S, E>
+where
+    S: EventSubscription + 'static,
+    E: Event + DeserializeOwned + 'static,
+{
+    /// every event is decoded as `E`
+    #[must_use]
+    pub fn new(subscription: S) -> Self {
+        Self {
+            subscription: Some(subscription),
+            next: None,
+            decoder: Decoder::new(),
+        }
+    }
+
+    /// the `Upcasters` are applied to the old events before decoding them
+    #[must_use]
+    pub fn with_upcasters(mut self, upcasters: Upcasters) -> Self {
+        self.decoder.upcasters = upcasters;
+        self
+    }
+
+    /// only the events with an accepted `EventName` are decoded, the other ones are skipped
+    #[must_use]
+    pub fn accept<F>(mut self, accept: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.decoder.accept = Some(Arc::new(accept));
+        self
+    }
+
+    fn next_envelope(mut subscription: S, decoder: Decoder<E>) -> NextTemporary<S, E> {
+        Box::pin(async move {
+            loop {
+                let stored = match subscription.next().await {
+                    Ok(stored) => stored,
+                    Err(e) => return (subscription, Err(EventSourceError::EventStore(e))),
+                };
+
+                if let Some(envelope) = decoder.decode(&stored) {
+                    return (subscription, envelope);
+                }
+            }
+        })
+    }
+}
+
+impl<S, E> Stream for TypedSubscription<S, E>
+where
+    S: EventSubscription + 'static,
+    E: Event + DeserializeOwned + 'static,
+{
+    type Item = Result<TypedEnvelope<E>, EventSourceError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.next.is_none() {
+            let Some(subscription) = this.subscription.take() else {
+                return Poll::Ready(None);
+            };
+            this.next = Some(Self::next_envelope(subscription, this.decoder.clone()));
+        }
+
+        let Some(next) = this.next.as_mut() else {
+            return Poll::Ready(None);
+        };
+
+        match next.as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready((subscription, envelope)) => {
+                this.next = None;
+                this.subscription = Some(subscription);
+                Poll::Ready(Some(envelope))
+            }
+        }
+    }
+}
+
+type NextPersistent<E> = BoxFuture<
+    'static,
+    (
+        Receiver<Result<ReceivedEvent, EventStoreError>>,
+        Option<Result<TypedEnvelope<E>, EventSourceError>>,
+    ),
+>;
+
+/// `TypedPersistentSubscription` wrap a persistent subscription,
+/// every envelope must be acked or nacked once processed
+///
+/// the subscription is owned by a task reading the next event and settling the acks at the same time,
+/// an envelope can be acked while the next one is awaited
+pub struct TypedPersistentSubscription<E> {
+    received: Option<Receiver<Result<ReceivedEvent, EventStoreError>>>,
+    settle: UnboundedSender<Settlement>,
+    next: Option<NextPersistent<E>>,
+    decoder: Decoder<E>,
+    task: JoinHandle<()>,
+}
+
+// the receiver is moved in and out of the boxed future, it is never pinned
+impl<E> Unpin for TypedPersistentSubscription<E> {}
+
+impl<E> TypedPersistentSubscription<E>
+where
+    E: Event + DeserializeOwned + 'static,
+{
+    /// every event is decoded as `E`, the subscription is moved to a task on the current tokio runtime
+    ///
+    /// # Panics
+    ///
+    /// Will panic if called outside of a tokio runtime
+    #[must_use]
+    pub fn new<P>(subscription: P) -> Self
+    where
+        P: PersistentEventSubscription + 'static,
+    {
+        // a single event is read ahead of the consumer
+        let (received_sender, received) = mpsc::channel(1);
+        let (settle, settlements) = mpsc::unbounded_channel();
+
+        let task = tokio::spawn(drive(subscription, received_sender, settlements));
+
+        Self {
+            received: Some(received),
+            settle,
+            next: None,
+            decoder: Decoder::new(),
+            task,
+        }
+    }
+
+    /// the `Upcasters` are applied to the old events before decoding them
+    #[must_use]
+    pub fn with_upcasters(mut self, upcasters: Upcasters) -> Self {
+        self.decoder.upcasters = upcasters;
+        self
+    }
+
+    /// only the events with an accepted `EventName` are decoded, the other ones are acked
+    #[must_use]
+    pub fn accept<F>(mut self, accept: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.decoder.accept = Some(Arc::new(accept));
+        self
+    }
+
+    fn next_envelope(
+        mut received: Receiver<Result<ReceivedEvent, EventStoreError>>,
+        sender: UnboundedSender<Settlement>,
+        decoder: Decoder<E>,
+    ) -> NextPersistent<E> {
+        Box::pin(async move {
+            loop {
+                let event = match received.recv().await {
+                    None => return (received, None),
+                    Some(Err(e)) => return (received, Some(Err(EventSourceError::EventStore(e)))),
+                    Some(Ok(event)) => event,
+                };
+
+                let outcome = event.event().and_then(|stored| decoder.decode(stored));
+
+                let settled = match outcome {
+                    None => settle(&sender, event, None).await,
+                    Some(Err(e)) => {
+                        let nack = Some((NakAction::Park, e.to_string()));
+                        let settled = settle(&sender, event, nack).await;
+                        let result = settled.map_err(EventSourceError::EventStore).and(Err(e));
+                        return (received, Some(result));
+                    }
+                    Some(Ok(mut envelope)) => {
+                        envelope.ack = Some(AckHandle {
+                            settle: sender,
+                            received: event,
+                        });
+                        return (received, Some(Ok(envelope)));
+                    }
+                };
+
+                if let Err(e) = settled {
+                    return (received, Some(Err(EventSourceError::EventStore(e))));
+                }
+            }
+        })
+    }
+}
+
+/// read the events one by one, the settlements are applied while the next event is awaited.
+/// It stop once all the envelopes are settled, or when the `TypedPersistentSubscription` is dropped
+async fn drive<P>(
+    mut subscription: P,
+    received: Sender<Result<ReceivedEvent, EventStoreError>>,
+    mut settlements: UnboundedReceiver<Settlement>,
+) where
+    P: PersistentEventSubscription,
+{
+    loop {
+        // the next event is only read once the previous one is taken
+        let permit = tokio::select! {
+            settlement = settlements.recv() => {
+                let Some(settlement) = settlement else { return };
+                apply(&mut subscription, settlement).await;
+                continue;
+            }
+            permit = received.reserve() => permit,
+        };
+
+        // the consumer is gone
+        let Ok(permit) = permit else { return };
+
+        let next = loop {
+            tokio::select! {
+                biased;
+                settlement = settlements.recv() => {
+                    let Some(settlement) = settlement else { return };
+                    apply(&mut subscription, settlement).await;
+                }
+                next = subscription.next() => break next,
+            }
+        };
+
+        permit.send(next);
+    }
+}
+
+async fn apply<P>(subscription: &mut P, settlement: Settlement)
+where
+    P: PersistentEventSubscription,
+{
+    let Settlement {
+        received,
+        nack,
+        done,
+    } = settlement;
+
+    let settled = match nack {
+        None => subscription.ack(&received).await,
+        Some((action, reason)) => subscription.nack(&received, action, &reason).await,
+    };
+
+    // the envelope may have been dropped with its future
+    let _ = done.send(settled);
+}
+
+impl<E> Drop for TypedPersistentSubscription<E> {
+    /// the task would otherwise wait for the next event forever
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl<E> Stream for TypedPersistentSubscription<E>
+where
+    E: Event + DeserializeOwned + 'static,
+{
+    type Item = Result<TypedEnvelope<E>, EventSourceError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        if this.next.is_none() {
+            let Some(received) = this.received.take() else {
+                return Poll::Ready(None);
+            };
+            this.next = Some(Self::next_envelope(
+                received,
+                this.settle.clone(),
+                this.decoder.clone(),
+            ));
+        }
+
+        let Some(next) = this.next.as_mut() else {
+            return Poll::Ready(None);
+        };
+
+        match next.as_mut().poll(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready((received, envelope)) => {
+                this.next = None;
+                this.received = Some(received);
+                Poll::Ready(envelope)
+            }
+        }
+    }
+}
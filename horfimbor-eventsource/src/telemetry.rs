@@ -0,0 +1,97 @@
+//! trace propagation through the commands and the events, behind the `telemetry` feature
+//!
+//! `add_command`, `try_append`, `complete_from_es` and `cache_dto` are wrapped in `tracing` spans.
+//! The W3C trace context of the current span is written in the `Metadata` of every command and event,
+//! so a subscriber in another service can continue the trace : `event_span` is the span
+//! of the handling of an event, its parent is the span that wrote it.
+//!
+//! The spans reach OpenTelemetry through the `tracing-opentelemetry` layer set up by the application.
+
+use opentelemetry::Context;
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+use crate::event_store::StoredEvent;
+use crate::metadata::Metadata;
+
+const VERSION: &str = "00";
+
+/// record the context of the current span, nothing when there is no valid one
+pub(crate) fn with_current_context(mut metadata: Metadata) -> Metadata {
+    let context = Span::current().context();
+    let span = context.span();
+    let span_context = span.span_context();
+
+    if span_context.is_valid() {
+        let tracestate = span_context.trace_state().header();
+
+        metadata.set_trace_context(
+            Some(traceparent(span_context)),
+            (!tracestate.is_empty()).then_some(tracestate),
+        );
+    }
+
+    metadata
+}
+
+fn traceparent(span_context: &SpanContext) -> String {
+    format!(
+        "{VERSION}-{:032x}-{:016x}-{:02x}",
+        span_context.trace_id(),
+        span_context.span_id(),
+        span_context.trace_flags().to_u8()
+    )
+}
+
+/// the remote context recorded in the `Metadata`, `None` when it is missing or malformed
+#[must_use]
+pub fn parent_context(metadata: &Metadata) -> Option<Context> {
+    let mut parts = metadata.traceparent()?.split('-');
+
+    let (Some(VERSION), Some(trace_id), Some(span_id), Some(flags), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return None;
+    };
+
+    let trace_state = metadata
+        .tracestate()
+        .map_or_else(|| Ok(TraceState::default()), str::parse)
+        .ok()?;
+
+    let span_context = SpanContext::new(
+        TraceId::from_hex(trace_id).ok()?,
+        SpanId::from_hex(span_id).ok()?,
+        TraceFlags::new(u8::from_str_radix(flags, 16).ok()?),
+        true,
+        trace_state,
+    );
+
+    span_context
+        .is_valid()
+        .then(|| Context::new().with_remote_span_context(span_context))
+}
+
+/// the span of a subscriber handling the event, child of the span that wrote the event
+#[must_use]
+pub fn event_span(name: &str, event: &StoredEvent) -> Span {
+    let span = tracing::info_span!(
+        "handle_event",
+        otel.name = name,
+        stream_id = event.stream_id(),
+        event_type = event.event_type(),
+        revision = event.revision(),
+    );
+
+    if let Some(parent) = event.metadata().ok().as_ref().and_then(parent_context) {
+        // the span is not entered yet, only a disabled span refuse it
+        let _ = span.set_parent(parent);
+    }
+
+    span
+}
@@ -0,0 +1,84 @@
+//! upcasters keep the old events readable
+//!
+//! every event is written with its `Event::event_version` in the `Metadata`,
+//! when the payload change the version is bumped and an upcaster is registered
+//! to turn the json of the previous version into the next one.
+//! The stored events are never rewritten, the chain is applied on each read.
+//!
+//! The events written with another `Codec` are decoded with it,
+//! the upcasters receive them as json.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde_json::{Error as SerdeError, Value};
+
+use crate::EventName;
+use crate::event_store::StoredEvent;
+use crate::metadata::Metadata;
+
+type Upcaster = Arc<dyn Fn(Value) -> Value + Send + Sync>;
+
+/// `Upcasters` is the registry of the upcasters, cheap to clone
+#[derive(Clone, Default)]
+pub struct Upcasters {
+    steps: HashMap<String, BTreeMap<u32, Upcaster>>,
+}
+
+impl Upcasters {
+    /// an empty registry, the payloads are read as they are
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the upcaster turn the payload of `event_name` from `from_version` to `from_version + 1`,
+    /// a missing step mean that the payload did not change for this event
+    #[must_use]
+    pub fn register<F>(mut self, event_name: EventName, from_version: u32, upcaster: F) -> Self
+    where
+        F: Fn(Value) -> Value + Send + Sync + 'static,
+    {
+        self.steps
+            .entry(event_name.to_string())
+            .or_default()
+            .insert(from_version, Arc::new(upcaster));
+        self
+    }
+
+    /// apply all the upcasters registered from `version` to the payload
+    #[must_use]
+    pub fn upcast(&self, event_name: &str, version: u32, payload: Value) -> Value {
+        let Some(steps) = self.steps.get(event_name) else {
+            return payload;
+        };
+
+        steps
+            .range(version..)
+            .fold(payload, |payload, (_, upcaster)| upcaster(payload))
+    }
+
+    /// decode the payload of the stored event with the schema version and the `Codec` of its `Metadata`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if the upcasted payload does not match `T`
+    pub fn decode<T>(&self, event: &StoredEvent, metadata: &Metadata) -> Result<T, SerdeError>
+    where
+        T: DeserializeOwned,
+    {
+        let version = metadata.schema_version();
+        let Some(steps) = self.steps.get(event.event_type()) else {
+            return event.decode(metadata);
+        };
+
+        if steps.range(version..).next().is_none() {
+            return event.decode(metadata);
+        }
+
+        let payload: Value = event.decode(metadata)?;
+
+        serde_json::from_value(self.upcast(event.event_type(), version, payload))
+    }
+}
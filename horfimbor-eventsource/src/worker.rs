@@ -0,0 +1,250 @@
+//! supervised `cache_dto`
+//!
+//! the `CacheWorker` consume the persistent subscription in a tokio task :
+//! an event failing to decode `max_failures` times is parked instead of stopping the worker,
+//! the subscription is created again following the restart `RetryPolicy`
+//! when the store or the cache cannot be reached, without counting it against the event,
+//! and the shutdown wait for the current event.
+
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::time::Instant;
+
+use kurrentdb::NakAction;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::cache_db::{CacheDb, DbError};
+use crate::event_store::{EventStore, PersistentEventSubscription};
+use crate::helper::get_persistent_subscription;
+use crate::repository::Repository;
+use crate::retry::RetryPolicy;
+use crate::{Dto, EventSourceError, Stream};
+
+/// `WorkerPolicy` tell the `CacheWorker` when to give up
+#[derive(Clone, Debug)]
+pub struct WorkerPolicy {
+    max_failures: u32,
+    restart: RetryPolicy,
+}
+
+impl Default for WorkerPolicy {
+    /// an event is parked after 3 failures, the restarts use the default `RetryPolicy`
+    fn default() -> Self {
+        Self {
+            max_failures: 3,
+            restart: RetryPolicy::default(),
+        }
+    }
+}
+
+impl WorkerPolicy {
+    /// the number of decoding failures of an event before it is parked, at least one.
+    /// An unreachable cache or store restart the worker instead
+    #[must_use]
+    pub fn with_max_failures(mut self, max_failures: u32) -> Self {
+        self.max_failures = max_failures.max(1);
+        self
+    }
+
+    /// the restarts of the subscription, the attempts are counted since the last cached event
+    #[must_use]
+    pub const fn with_restart(mut self, restart: RetryPolicy) -> Self {
+        self.restart = restart;
+        self
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn max_failures(&self) -> u32 {
+        self.max_failures
+    }
+
+    /// simple getter
+    #[must_use]
+    pub const fn restart(&self) -> &RetryPolicy {
+        &self.restart
+    }
+}
+
+/// `CacheWorker` is the handle of a spawned `cache_dto`
+pub struct CacheWorker {
+    cancellation: CancellationToken,
+    task: JoinHandle<Result<(), EventSourceError>>,
+}
+
+impl CacheWorker {
+    /// spawn the worker on the current tokio runtime
+    ///
+    /// # Panics
+    ///
+    /// Will panic if called outside of a tokio runtime
+    #[must_use]
+    pub fn spawn<R, D, C, E>(
+        repository: R,
+        stream: Stream,
+        group_name: &str,
+        policy: WorkerPolicy,
+    ) -> Self
+    where
+        R: Repository<D, C, E> + Sync + 'static,
+        D: Dto + 'static,
+        C: CacheDb<D> + Sync + 'static,
+        E: EventStore + 'static,
+    {
+        let cancellation = CancellationToken::new();
+
+        let supervisor = Supervisor {
+            repository,
+            stream,
+            group_name: group_name.to_string(),
+            policy,
+            cancellation: cancellation.clone(),
+            dto: PhantomData,
+        };
+
+        Self {
+            cancellation,
+            task: tokio::spawn(supervisor.run()),
+        }
+    }
+
+    /// a clone of the token stopping the worker, to share with the rest of the shutdown
+    #[must_use]
+    pub fn cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// ask the worker to stop once the current event is done
+    pub fn shutdown(&self) {
+        self.cancellation.cancel();
+    }
+
+    /// `false` once the worker stopped
+    #[must_use]
+    pub fn is_running(&self) -> bool {
+        !self.task.is_finished()
+    }
+
+    /// wait until the worker stop
+    ///
+    /// # Errors
+    ///
+    /// Will return the last `Err` once the restart `RetryPolicy` is exhausted
+    ///
+    /// # Panics
+    ///
+    /// Will panic if the worker panicked
+    pub async fn join(self) -> Result<(), EventSourceError> {
+        match self.task.await {
+            Ok(result) => result,
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // the task is never aborted
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+struct Supervisor<R, D, C, E> {
+    repository: R,
+    stream: Stream,
+    group_name: String,
+    policy: WorkerPolicy,
+    cancellation: CancellationToken,
+    dto: PhantomData<(D, C, E)>,
+}
+
+impl<R, D, C, E> Supervisor<R, D, C, E>
+where
+    R: Repository<D, C, E> + Sync,
+    D: Dto,
+    C: CacheDb<D> + Sync,
+    E: EventStore,
+{
+    async fn run(self) -> Result<(), EventSourceError> {
+        let mut restarts = 0;
+        let mut started = Instant::now();
+
+        loop {
+            let Err(e) = self.consume(&mut restarts, &mut started).await else {
+                return Ok(());
+            };
+
+            restarts += 1;
+            let Some(backoff) = self.policy.restart.next_backoff(restarts, started) else {
+                return Err(e);
+            };
+
+            tokio::select! {
+                () = self.cancellation.cancelled() => return Ok(()),
+                () = tokio::time::sleep(backoff) => {}
+            }
+        }
+    }
+
+    /// `Ok` once cancelled
+    async fn consume(
+        &self,
+        restarts: &mut u32,
+        started: &mut Instant,
+    ) -> Result<(), EventSourceError> {
+        let mut sub =
+            get_persistent_subscription(self.repository.event_db(), &self.stream, &self.group_name)
+                .await?;
+
+        let mut failures: HashMap<Uuid, u32> = HashMap::new();
+
+        loop {
+            let rcv_event = tokio::select! {
+                () = self.cancellation.cancelled() => return Ok(()),
+                rcv_event = sub.next() => rcv_event?,
+            };
+
+            let Some(event) = rcv_event.event() else {
+                sub.ack(&rcv_event).await?;
+                continue;
+            };
+
+            match self.repository.cache_event(event).await {
+                Ok(()) => {
+                    failures.remove(&rcv_event.ack_id());
+                    sub.ack(&rcv_event).await?;
+
+                    *restarts = 0;
+                    *started = Instant::now();
+                }
+                // the event is fine, the restart `RetryPolicy` back off until the outage is over
+                Err(e) if !is_poison(&e) => {
+                    sub.nack(&rcv_event, NakAction::Retry, &e.to_string())
+                        .await?;
+                    return Err(e);
+                }
+                Err(e) => {
+                    let failed = failures.entry(rcv_event.ack_id()).or_default();
+                    *failed += 1;
+
+                    if *failed >= self.policy.max_failures {
+                        failures.remove(&rcv_event.ack_id());
+                        sub.nack(&rcv_event, NakAction::Park, &e.to_string())
+                            .await?;
+                    } else {
+                        sub.nack(&rcv_event, NakAction::Retry, &e.to_string())
+                            .await?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// the event itself cannot be cached : it count toward parking,
+/// the other errors come from the cache or the store being unreachable
+const fn is_poison(e: &EventSourceError) -> bool {
+    matches!(
+        e,
+        EventSourceError::Serde(_)
+            | EventSourceError::ModelKey(_)
+            | EventSourceError::CacheDbError(DbError::SerdeJson(_) | DbError::Codec(_))
+    )
+}
@@ -0,0 +1,112 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use uuid::Uuid;
+
+use horfimbor_eventsource::EventSourceStateError;
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::fixture::StateFixture;
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{
+    DtoRepository, DtoRepositoryConstructor, RepositoryKind, StateRepository,
+    StateRepositoryConstructor,
+};
+
+use crate::guarded::{GuardedCommand, GuardedContext, GuardedError, GuardedEvent, GuardedState};
+use crate::simple::{SimpleCommand, SimpleState};
+
+mod guarded;
+// `SimpleNbAddDto` is not needed here
+#[allow(dead_code)]
+mod simple;
+
+fn context(event_db: &InMemoryEventStore) -> GuardedContext {
+    GuardedContext {
+        players: DtoRepository::new(
+            event_db.clone(),
+            NoCache::new(),
+            RepositoryKind::Dto("players"),
+        ),
+        calls: AtomicUsize::new(0),
+        conflict: None,
+    }
+}
+
+#[tokio::test]
+async fn command_with_context() {
+    let event_db = InMemoryEventStore::new();
+    let players = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let repo = StateRepository::new(event_db.clone(), NoCache::<GuardedState>::new());
+
+    let key = ModelKey::new("guarded_test", Uuid::new_v4());
+    let player = ModelKey::new("simple_test", Uuid::new_v4());
+
+    let command = GuardedCommand::Reward {
+        player: player.clone(),
+        amount: 7,
+    };
+
+    let result = repo
+        .add_command_with_context(&key, command.clone(), &context(&event_db), None)
+        .await;
+    assert!(matches!(result, Err(EventSourceStateError::State(_))));
+
+    players
+        .add_command(&player, SimpleCommand::Add(1), None)
+        .await
+        .expect("player created");
+
+    let state = repo
+        .add_command_with_context(&key, command, &context(&event_db), None)
+        .await
+        .expect("rewarded");
+    assert_eq!(state, GuardedState { total: 7 });
+}
+
+#[tokio::test]
+async fn handler_is_run_again_on_conflict() {
+    let event_db = InMemoryEventStore::new();
+    let players = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let repo = StateRepository::new(event_db.clone(), NoCache::<GuardedState>::new());
+
+    let key = ModelKey::new("guarded_test", Uuid::new_v4());
+    let player = ModelKey::new("simple_test", Uuid::new_v4());
+    players
+        .add_command(&player, SimpleCommand::Add(1), None)
+        .await
+        .expect("player created");
+
+    let context = GuardedContext {
+        conflict: Some((repo.clone(), key.clone())),
+        ..context(&event_db)
+    };
+
+    let state = repo
+        .add_command_with_context(
+            &key,
+            GuardedCommand::Reward { player, amount: 3 },
+            &context,
+            None,
+        )
+        .await
+        .expect("rewarded after a retry");
+
+    assert_eq!(context.calls.load(Ordering::SeqCst), 2);
+    assert_eq!(state, GuardedState { total: 6 });
+}
+
+#[tokio::test]
+async fn fixture_with_context() {
+    let event_db = InMemoryEventStore::new();
+
+    StateFixture::<GuardedState>::given([GuardedEvent::Rewarded(2)])
+        .when_with_context(
+            GuardedCommand::Reward {
+                player: ModelKey::new("simple_test", Uuid::new_v4()),
+                amount: 1,
+            },
+            &context(&event_db),
+        )
+        .await
+        .then_error(|e| matches!(e, GuardedError::UnknownPlayer));
+}
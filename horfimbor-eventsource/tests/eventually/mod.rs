@@ -0,0 +1,15 @@
+use std::time::Duration;
+
+use tokio::time::{sleep, timeout};
+
+/// poll `condition` until it holds, the workers run in the background
+pub async fn eventually(what: &str, mut condition: impl AsyncFnMut() -> bool) {
+    let polled = timeout(Duration::from_secs(2), async {
+        while !condition().await {
+            sleep(Duration::from_millis(5)).await;
+        }
+    })
+    .await;
+
+    assert!(polled.is_ok(), "{what} : not reached in time");
+}
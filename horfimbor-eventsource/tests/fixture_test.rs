@@ -0,0 +1,91 @@
+use horfimbor_eventsource::fixture::StateFixture;
+use horfimbor_eventsource_derive::{Command, Event, StateNamed};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use horfimbor_eventsource::{
+    Command, CommandName, Dto, Event, EventName, State, StateName, StateNamed,
+};
+
+use crate::simple::{SimpleCommand, SimpleError, SimpleEvent, SimpleState};
+
+// `SimpleNbAddDto` is not needed here
+#[allow(dead_code)]
+mod simple;
+
+#[test]
+fn given_when_then_expect() {
+    let state = StateFixture::<SimpleState>::given([SimpleEvent::Added(5)])
+        .when(SimpleCommand::Set(12))
+        .then_expect([SimpleEvent::Removed(5), SimpleEvent::Added(12)]);
+
+    assert_eq!(state, SimpleState { nb: 12 });
+}
+
+#[test]
+fn given_when_then_error() {
+    StateFixture::<SimpleState>::given([SimpleEvent::Added(5), SimpleEvent::Removed(2)])
+        .when(SimpleCommand::Remove(4))
+        .then_error(|e| matches!(e, SimpleError::Info(_)));
+}
+
+#[test]
+#[should_panic(expected = "expected events")]
+fn other_events_fail() {
+    let _ = StateFixture::<SimpleState>::given([])
+        .when(SimpleCommand::Add(3))
+        .then_expect([SimpleEvent::Added(4)]);
+}
+
+// an event that cannot be read back once stored
+
+const BROKEN_STATE_NAME: StateName = "BROKEN_STATE_NAME";
+
+#[derive(Deserialize, Serialize, Clone, Debug, Command)]
+#[state(BROKEN_STATE_NAME)]
+pub enum BrokenCommand {
+    Break,
+}
+
+#[derive(Error, Debug)]
+pub enum BrokenError {}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Event)]
+#[state(BROKEN_STATE_NAME)]
+pub enum BrokenEvent {
+    Broken {
+        #[serde(skip_serializing)]
+        reason: String,
+    },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, StateNamed)]
+#[state(BROKEN_STATE_NAME)]
+pub struct BrokenState {}
+
+impl Dto for BrokenState {
+    type Event = BrokenEvent;
+
+    fn play_event(&mut self, _event: &Self::Event) {}
+}
+
+impl State for BrokenState {
+    type Command = BrokenCommand;
+    type Error = BrokenError;
+
+    fn try_command(&self, _command: Self::Command) -> Result<Vec<Self::Event>, Self::Error> {
+        Ok(vec![BrokenEvent::Broken {
+            reason: "serde".to_string(),
+        }])
+    }
+}
+
+#[test]
+#[should_panic(expected = "cannot be deserialized")]
+fn serde_mistakes_fail() {
+    let _ = StateFixture::<BrokenState>::given([])
+        .when(BrokenCommand::Break)
+        .then_expect([BrokenEvent::Broken {
+            reason: "serde".to_string(),
+        }]);
+}
@@ -0,0 +1,120 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use async_trait::async_trait;
+use horfimbor_eventsource_derive::{Command, Event, StateNamed};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{DtoRepository, Repository, StateRepository};
+use horfimbor_eventsource::{
+    AsyncState, Command, CommandName, Dto, Event, EventName, State, StateName, StateNamed,
+};
+
+use crate::simple::SimpleState;
+
+const GUARDED_STATE_NAME: StateName = "GUARDED_STATE_NAME";
+
+#[derive(Deserialize, Serialize, Clone, Debug, Command)]
+#[state(GUARDED_STATE_NAME)]
+pub enum GuardedCommand {
+    Reward { player: ModelKey, amount: u32 },
+}
+
+#[derive(Error, Debug)]
+pub enum GuardedError {
+    #[error("the player does not exist")]
+    UnknownPlayer,
+    #[error("the player cannot be read")]
+    Lookup,
+    #[error("a context is needed")]
+    MissingContext,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Event)]
+#[state(GUARDED_STATE_NAME)]
+pub enum GuardedEvent {
+    Rewarded(u32),
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, StateNamed)]
+#[state(GUARDED_STATE_NAME)]
+pub struct GuardedState {
+    pub total: u32,
+}
+
+pub type GuardedRepository =
+    StateRepository<GuardedState, NoCache<GuardedState>, InMemoryEventStore>;
+
+/// the players are read from another repository,
+/// the first call can append a command to create a conflict
+pub struct GuardedContext {
+    pub players: DtoRepository<SimpleState, NoCache<SimpleState>, InMemoryEventStore>,
+    pub calls: AtomicUsize,
+    pub conflict: Option<(GuardedRepository, ModelKey)>,
+}
+
+impl Dto for GuardedState {
+    type Event = GuardedEvent;
+
+    fn play_event(&mut self, event: &Self::Event) {
+        match event {
+            GuardedEvent::Rewarded(n) => self.total += n,
+        }
+    }
+}
+
+impl State for GuardedState {
+    type Command = GuardedCommand;
+    type Error = GuardedError;
+
+    fn try_command(&self, _command: Self::Command) -> Result<Vec<Self::Event>, Self::Error> {
+        Err(GuardedError::MissingContext)
+    }
+}
+
+#[async_trait]
+impl AsyncState for GuardedState {
+    type Context = GuardedContext;
+
+    async fn try_command_with_context(
+        &self,
+        command: Self::Command,
+        context: &Self::Context,
+    ) -> Result<Vec<Self::Event>, Self::Error> {
+        if context.calls.fetch_add(1, Ordering::SeqCst) == 0
+            && let Some((repo, key)) = &context.conflict
+        {
+            repo.add_command_with_context(
+                key,
+                command.clone(),
+                &GuardedContext {
+                    players: context.players.clone(),
+                    calls: AtomicUsize::new(1),
+                    conflict: None,
+                },
+                None,
+            )
+            .await
+            .map_err(|_| GuardedError::Lookup)?;
+        }
+
+        match command {
+            GuardedCommand::Reward { player, amount } => {
+                let player = context
+                    .players
+                    .get_model(&player)
+                    .await
+                    .map_err(|_| GuardedError::Lookup)?;
+
+                if player.position().is_none() {
+                    return Err(GuardedError::UnknownPlayer);
+                }
+
+                Ok(vec![GuardedEvent::Rewarded(amount)])
+            }
+        }
+    }
+}
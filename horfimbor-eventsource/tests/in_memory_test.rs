@@ -0,0 +1,1154 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use tokio::runtime::Handle;
+use tokio::task::{spawn_blocking, yield_now};
+use tokio::time::{sleep, timeout};
+use uuid::Uuid;
+
+use horfimbor_eventsource::cache_db::notify::{BroadcastNotifier, NotifyingCache};
+use horfimbor_eventsource::cache_db::{CacheDb, DbError, NoCache};
+use horfimbor_eventsource::causation::{CausationDecoders, CausationPayload, causation_tree};
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::event_store::{
+    EventReader, EventStore, EventSubscription, PersistentEventSubscription,
+};
+use horfimbor_eventsource::helper::{
+    create_subscription, get_persistent_subscription, get_subscription,
+    get_typed_persistent_subscription, get_typed_subscription,
+};
+use horfimbor_eventsource::history::HistoryPayload;
+use horfimbor_eventsource::metadata::{CompleteEvent, Metadata, MetadataExtensions};
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{
+    DtoRepository, DtoRepositoryConstructor, Repository, RepositoryKind, StateRepository,
+    StateRepositoryConstructor,
+};
+use horfimbor_eventsource::retry::RetryPolicy;
+use horfimbor_eventsource::upcaster::Upcasters;
+use horfimbor_eventsource::worker::{CacheWorker, WorkerPolicy};
+use horfimbor_eventsource::{
+    Dto, Event, EventName, EventSourceError, EventSourceStateError, StateName, Stream,
+};
+use horfimbor_eventsource_derive::Event;
+use kurrentdb::{NakAction, StreamPosition, StreamState};
+use serde::{Deserialize, Serialize};
+
+use crate::concurrent::{ConcurrentCommand, ConcurrentState};
+use crate::eventually::eventually;
+use crate::guarded::{GuardedCommand, GuardedContext, GuardedState};
+use crate::simple::{SimpleCommand, SimpleEvent, SimpleNbAddDto, SimpleState};
+use crate::with_public::public::Player::Circle;
+use crate::with_public::public::{TTT_STREAM, TTTEvents, Victory};
+use crate::with_public::{TTTCommand, TTTState};
+
+mod concurrent;
+mod eventually;
+mod guarded;
+mod simple;
+mod with_public;
+
+/// minimal cache keeping the serialized models in a map
+#[derive(Clone)]
+struct MapCache<S> {
+    data: Arc<Mutex<HashMap<String, String>>>,
+    outage: Arc<AtomicUsize>,
+    state: PhantomData<S>,
+}
+
+impl<S> MapCache<S> {
+    fn new() -> Self {
+        Self {
+            data: Arc::new(Mutex::new(HashMap::new())),
+            outage: Arc::new(AtomicUsize::new(0)),
+            state: PhantomData,
+        }
+    }
+
+    /// the next `writes` are refused as if the cache was disconnected
+    fn disconnect_for(&self, writes: usize) {
+        self.outage.store(writes, Ordering::SeqCst);
+    }
+
+    fn raw(&self, key: &ModelKey) -> Option<String> {
+        self.data
+            .lock()
+            .expect("poisoned")
+            .get(&key.format())
+            .cloned()
+    }
+}
+
+#[async_trait]
+impl<S> CacheDb<S> for MapCache<S>
+where
+    S: Dto,
+{
+    async fn get_from_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+    ) -> Result<Option<String>, DbError> {
+        let key = prefix.map_or_else(|| key.format(), |prefix| format!("{prefix}-{key}"));
+        Ok(self.data.lock().expect("poisoned").get(&key).cloned())
+    }
+
+    async fn set_in_db(
+        &self,
+        prefix: Option<&str>,
+        key: &ModelKey,
+        state: String,
+    ) -> Result<(), DbError> {
+        let disconnected = self
+            .outage
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok();
+        if disconnected {
+            return Err(DbError::Disconnect("outage".to_string()));
+        }
+
+        let key = prefix.map_or_else(|| key.format(), |prefix| format!("{prefix}-{key}"));
+        self.data.lock().expect("poisoned").insert(key, state);
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn easy_case_in_memory() {
+    let event_db = InMemoryEventStore::new();
+
+    let repo_state = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let repo_dto = DtoRepository::new(
+        event_db,
+        NoCache::<SimpleNbAddDto>::new(),
+        RepositoryKind::Dto("easy_cache"),
+    );
+
+    let key = ModelKey::new("simple_test", Uuid::new_v4());
+
+    let model = repo_state.get_model(&key).await.expect("empty state");
+    assert_eq!(model.state(), &SimpleState { nb: 0 });
+    assert_eq!(model.position(), None);
+
+    let added = repo_state
+        .add_command(&key, SimpleCommand::Add(17), None)
+        .await
+        .expect("add 17");
+    assert_eq!(added, SimpleState { nb: 17 });
+
+    repo_state
+        .add_command(&key, SimpleCommand::Set(50), None)
+        .await
+        .expect("set 50");
+
+    let model = repo_state.get_model(&key).await.expect("state");
+    assert_eq!(model.state(), &SimpleState { nb: 50 });
+    // 2 commands and 3 events
+    assert_eq!(model.position(), Some(4));
+
+    let dto = repo_dto.get_model(&key).await.expect("dto");
+    assert_eq!(dto.state(), &SimpleNbAddDto { nb: 2 });
+
+    let result = repo_state
+        .add_command(&key, SimpleCommand::Remove(51), None)
+        .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn concurrent_case_in_memory() {
+    let repo = StateRepository::new(InMemoryEventStore::new(), NoCache::<ConcurrentState>::new());
+
+    let key = ModelKey::new("concurrent_test", Uuid::new_v4());
+
+    let handles: Vec<_> = [(1, "one"), (2, "two")]
+        .into_iter()
+        .map(|(time, name)| {
+            let repo = repo.clone();
+            let key = key.clone();
+            // the handler block its thread, the retries wait on the runtime timer
+            spawn_blocking(move || {
+                Handle::current()
+                    .block_on(repo.add_command(
+                        &key,
+                        ConcurrentCommand::TakeTime(time, name.to_string()),
+                        None,
+                    ))
+                    .expect("command must be retried until it succeed");
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.await.expect("command panicked");
+    }
+
+    let model = repo.get_model(&key).await.expect("state");
+    assert_eq!(
+        model.state(),
+        &ConcurrentState {
+            names: vec!["one".to_string(), "two".to_string()],
+        }
+    );
+}
+
+#[tokio::test]
+async fn idempotent_command_in_memory() {
+    let repo = StateRepository::new(InMemoryEventStore::new(), NoCache::<SimpleState>::new());
+    let key = ModelKey::new("simple_test", Uuid::new_v4());
+
+    let first = repo
+        .add_idempotent_command(&key, SimpleCommand::Add(10), "request-1", None)
+        .await
+        .expect("add 10");
+    assert_eq!(first, SimpleState { nb: 10 });
+
+    repo.add_command(&key, SimpleCommand::Add(5), None)
+        .await
+        .expect("add 5");
+
+    // the client retry : nothing is appended and the original outcome is returned
+    let retried = repo
+        .add_idempotent_command(&key, SimpleCommand::Add(10), "request-1", None)
+        .await
+        .expect("retry add 10");
+    assert_eq!(retried, SimpleState { nb: 10 });
+
+    let model = repo.get_model(&key).await.expect("state");
+    assert_eq!(model.state(), &SimpleState { nb: 15 });
+    assert_eq!(model.position(), Some(3));
+
+    let other = repo
+        .add_idempotent_command(&key, SimpleCommand::Add(10), "request-2", None)
+        .await
+        .expect("add 10 again");
+    assert_eq!(other, SimpleState { nb: 25 });
+}
+
+#[tokio::test]
+async fn idempotency_window_in_memory() {
+    let repo = StateRepository::new(InMemoryEventStore::new(), NoCache::<SimpleState>::new())
+        .with_idempotency_window(4);
+    let key = ModelKey::new("simple_test", Uuid::new_v4());
+
+    repo.add_idempotent_command(&key, SimpleCommand::Add(10), "request-1", None)
+        .await
+        .expect("add 10");
+    repo.add_command(&key, SimpleCommand::Add(5), None)
+        .await
+        .expect("add 5");
+
+    // the command and its event are the first 2 of the last 4 entries
+    let retried = repo
+        .add_idempotent_command(&key, SimpleCommand::Add(10), "request-1", None)
+        .await
+        .expect("retry add 10");
+    assert_eq!(retried, SimpleState { nb: 10 });
+
+    repo.add_command(&key, SimpleCommand::Add(1), None)
+        .await
+        .expect("add 1");
+
+    // out of the window, the command is appended again
+    let late = repo
+        .add_idempotent_command(&key, SimpleCommand::Add(10), "request-1", None)
+        .await
+        .expect("late retry add 10");
+    assert_eq!(late, SimpleState { nb: 26 });
+}
+
+#[tokio::test]
+async fn command_outcome_in_memory() {
+    let repo = StateRepository::new(InMemoryEventStore::new(), NoCache::<SimpleState>::new());
+    let key = ModelKey::new("simple_test", Uuid::new_v4());
+
+    let first = repo
+        .add_command_detailed(&key, SimpleCommand::Add(10), None)
+        .await
+        .expect("add 10");
+    assert_eq!(first.state(), &SimpleState { nb: 10 });
+    assert_eq!(first.revision(), 1);
+    assert_eq!(first.command_metadata().id(), Some(first.command_id()));
+
+    assert_eq!(first.events().len(), 1);
+    assert!(matches!(first.events()[0].event(), SimpleEvent::Added(10)));
+    assert_eq!(first.events()[0].position(), 1);
+    assert_eq!(
+        first.last_metadata().causation_id(),
+        first.command_id(),
+        "the event is caused by the command"
+    );
+
+    // the saga way : the next command follow the last event
+    let second = repo
+        .add_command_detailed(&key, SimpleCommand::Remove(3), Some(first.last_metadata()))
+        .await
+        .expect("remove 3");
+    assert_eq!(second.revision(), 3);
+    assert_eq!(second.events()[0].position(), 3);
+    assert_eq!(
+        second.command_metadata().correlation_id(),
+        first.command_metadata().correlation_id()
+    );
+    assert_eq!(
+        second.command_metadata().causation_id(),
+        first.last_metadata().id().expect("event id")
+    );
+
+    let model = repo.get_model(&key).await.expect("state");
+    assert_eq!(model.position(), Some(second.revision()));
+    assert_eq!(second.into_state(), SimpleState { nb: 7 });
+}
+
+#[tokio::test]
+async fn time_travel_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let cache = MapCache::<SimpleState>::new();
+    let repo = StateRepository::new(event_db.clone(), cache.clone());
+    let key = ModelKey::new("time_travel", Uuid::new_v4());
+
+    for n in [5, 7, 11] {
+        repo.add_command(&key, SimpleCommand::Add(n), None)
+            .await
+            .expect("add");
+        // distinct creation times to travel between, not a synchronisation
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    // the cache is after every bound below
+    let latest = repo.get_model(&key).await.expect("get model");
+    cache.set(&key, &latest, None).await.expect("set");
+    assert_eq!(latest.position(), Some(5));
+
+    let before = repo
+        .get_model_at_revision(&key, 2)
+        .await
+        .expect("at revision 2");
+    assert_eq!(before.state().nb, 5);
+    assert_eq!(before.position(), Some(2));
+
+    let after = repo
+        .get_model_at_revision(&key, 3)
+        .await
+        .expect("at revision 3");
+    assert_eq!(after.state().nb, 12);
+    assert_eq!(after.position(), Some(3));
+
+    let beyond = repo
+        .get_model_at_revision(&key, 42)
+        .await
+        .expect("at revision 42");
+    assert_eq!(beyond.state().nb, 23);
+
+    // a command and its events are written together
+    let first_event = event_db
+        .read_stream(&key.format(), StreamPosition::Position(1))
+        .await
+        .expect("read stream")
+        .next()
+        .await
+        .expect("next")
+        .expect("first event");
+
+    let at = repo
+        .get_model_at(&key, first_event.created())
+        .await
+        .expect("at time");
+    assert_eq!(at.state().nb, 5);
+    assert_eq!(at.position(), Some(1));
+
+    let empty = repo
+        .get_model_at(&key, first_event.created() - chrono::Duration::days(1))
+        .await
+        .expect("before the first command");
+    assert_eq!(empty.state().nb, 0);
+    assert_eq!(empty.position(), None);
+}
+
+#[tokio::test]
+async fn time_travel_unknown_entity_in_memory() {
+    let repo = StateRepository::new(InMemoryEventStore::new(), NoCache::<SimpleState>::new());
+    let key = ModelKey::new("time_travel", Uuid::new_v4());
+
+    // an unknown entity has the default model, as with `get_model`
+    let at = repo
+        .get_model_at(&key, chrono::Utc::now())
+        .await
+        .expect("unknown entity at time");
+    assert_eq!(at.state(), &SimpleState::default());
+    assert_eq!(at.position(), None);
+
+    let at_revision = repo
+        .get_model_at_revision(&key, 3)
+        .await
+        .expect("unknown entity at revision");
+    assert_eq!(at_revision.state(), &SimpleState::default());
+    assert_eq!(at_revision.position(), None);
+}
+
+#[tokio::test]
+async fn history_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db, NoCache::<SimpleState>::new());
+    let key = ModelKey::new("history", Uuid::new_v4());
+
+    let empty = repo.history(&key, 0..10).await.expect("empty history");
+    assert!(empty.entries().is_empty());
+    assert_eq!(empty.next(), None);
+
+    repo.add_command(&key, SimpleCommand::Add(5), None)
+        .await
+        .expect("add 5");
+    repo.add_command(&key, SimpleCommand::Set(2), None)
+        .await
+        .expect("set 2");
+
+    // add : command + event, set : command + 2 events
+    let first = repo.history(&key, 0..3).await.expect("first page");
+    assert_eq!(first.next(), Some(3));
+    let entries = first.entries();
+    assert_eq!(entries.len(), 3);
+    assert!(matches!(
+        entries[0].payload(),
+        HistoryPayload::Command(SimpleCommand::Add(5))
+    ));
+    assert!(matches!(
+        entries[1].payload(),
+        HistoryPayload::Event(SimpleEvent::Added(5))
+    ));
+    assert!(matches!(
+        entries[2].payload(),
+        HistoryPayload::Command(SimpleCommand::Set(2))
+    ));
+    assert_eq!(entries[1].causation_id(), entries[0].id());
+    assert_eq!(entries[1].correlation_id(), entries[0].id());
+    assert!(entries[0].created() <= entries[2].created());
+
+    let second = repo
+        .history(&key, first.next().expect("next")..10)
+        .await
+        .expect("second page");
+    assert_eq!(second.next(), None);
+    let revisions: Vec<u64> = second.entries().iter().map(|e| e.revision()).collect();
+    assert_eq!(revisions, vec![3, 4]);
+    assert!(second.entries().iter().all(|e| e.is_event()));
+}
+
+#[tokio::test]
+async fn causation_tree_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let simple_repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let ttt_repo = StateRepository::new(event_db.clone(), NoCache::<TTTState>::new());
+    let simple_key = ModelKey::new("causation", Uuid::new_v4());
+    let ttt_key = ModelKey::new(TTT_STREAM, Uuid::new_v4());
+
+    simple_repo
+        .add_command(&simple_key, SimpleCommand::Add(5), None)
+        .await
+        .expect("add 5");
+    let history = simple_repo
+        .history(&simple_key, 0..2)
+        .await
+        .expect("history");
+    let command_id = history.entries()[0].id();
+    let added = history.entries()[1].metadata().clone();
+
+    // the game is created because of the `Added` event, on another entity
+    ttt_repo
+        .add_command(&ttt_key, TTTCommand::Create, Some(&added))
+        .await
+        .expect("create");
+
+    // an unrelated command is not part of the tree
+    simple_repo
+        .add_command(&simple_key, SimpleCommand::Add(1), None)
+        .await
+        .expect("add 1");
+
+    let decoders = CausationDecoders::new().register::<SimpleState>("causation", Upcasters::new());
+    let tree = causation_tree(&event_db, command_id, &decoders)
+        .await
+        .expect("tree");
+
+    assert_eq!(tree.correlation_id(), command_id);
+    assert_eq!(tree.roots().len(), 1);
+    assert_eq!(tree.len(), 4);
+
+    let root = &tree.roots()[0];
+    assert_eq!(root.id(), command_id);
+    assert!(!root.is_event());
+    assert_eq!(
+        root.payload(),
+        &CausationPayload::Typed(serde_json::json!({"Add": 5}))
+    );
+
+    let event = &root.children()[0];
+    assert!(event.is_event());
+    assert_eq!(event.stream_id(), simple_key.format());
+
+    let create = &event.children()[0];
+    assert_eq!(create.stream_id(), ttt_key.format());
+    assert_eq!(create.revision(), 0);
+    assert!(matches!(create.payload(), CausationPayload::Raw(_)));
+    assert_eq!(create.children().len(), 1);
+    assert!(tree.find(create.children()[0].id()).is_some());
+
+    let json = tree.to_json().expect("json");
+    assert_eq!(json["roots"][0]["payload"]["kind"], "typed");
+    assert_eq!(
+        json["roots"][0]["children"][0]["children"][0]["event_type"],
+        create.event_type()
+    );
+
+    let unknown = causation_tree(&event_db, Uuid::new_v4(), &decoders)
+        .await
+        .expect("unknown correlation");
+    assert!(unknown.is_empty());
+}
+
+#[tokio::test]
+async fn metadata_extensions_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let key = ModelKey::new("extensions", Uuid::new_v4());
+    let actor = ModelKey::new("account", Uuid::new_v4());
+
+    let extensions = MetadataExtensions::new()
+        .with_actor(actor.clone())
+        .with_service("lobby")
+        .with_header("locale", "fr")
+        .with_hf_time(42_000);
+
+    repo.add_command_with_extensions(&key, SimpleCommand::Add(5), &extensions, None)
+        .await
+        .expect("add 5");
+
+    let first = repo.history(&key, 0..2).await.expect("history");
+    for entry in first.entries() {
+        assert_eq!(entry.metadata().extensions(), &extensions);
+    }
+    let added = first.entries()[1].metadata().clone();
+
+    // the correlation projections still find their keys
+    let mut stream = event_db
+        .read_stream(&key.format(), StreamPosition::Start)
+        .await
+        .expect("read");
+    let stored = stream.next().await.expect("next").expect("command");
+    let raw: serde_json::Value =
+        serde_json::from_slice(stored.custom_metadata()).expect("raw metadata");
+    assert_eq!(raw["$correlationId"], stored.id().to_string());
+    assert_eq!(raw["actor"]["stream_name"], "account");
+    assert_eq!(raw["headers"]["locale"], "fr");
+
+    // inherited from the previous metadata, the given values win
+    repo.add_command_with_extensions(
+        &key,
+        SimpleCommand::Add(1),
+        &MetadataExtensions::new().with_service("matchmaker"),
+        Some(&added),
+    )
+    .await
+    .expect("add 1");
+
+    let second = repo.history(&key, 2..3).await.expect("history");
+    let metadata = second.entries()[0].metadata();
+    assert_eq!(metadata.actor(), Some(&actor));
+    assert_eq!(metadata.service(), Some("matchmaker"));
+    assert_eq!(metadata.header("locale"), Some("fr"));
+    assert_eq!(metadata.hf_time(), Some(42_000));
+
+    // written before the extensions
+    let id = Uuid::new_v4();
+    let old: Metadata = serde_json::from_value(serde_json::json!({
+        "$correlationId": id,
+        "$causationId": id,
+        "is_event": false,
+    }))
+    .expect("old metadata");
+    assert_eq!(old.extensions(), &MetadataExtensions::new());
+    assert!(old.actor().is_none());
+}
+
+#[tokio::test]
+async fn retry_exhausted_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let players = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let repo = StateRepository::new(event_db.clone(), NoCache::<GuardedState>::new())
+        .with_retry_policy(RetryPolicy::new(1));
+
+    let key = ModelKey::new("guarded_test", Uuid::new_v4());
+    let player = ModelKey::new("simple_test", Uuid::new_v4());
+    players
+        .add_command(&player, SimpleCommand::Add(1), None)
+        .await
+        .expect("player created");
+
+    // the first call of the handler append a command before its own
+    let context = GuardedContext {
+        players: DtoRepository::new(event_db, NoCache::new(), RepositoryKind::Dto("players")),
+        calls: AtomicUsize::new(0),
+        conflict: Some((repo.clone(), key.clone())),
+    };
+
+    let result = repo
+        .add_command_with_context(
+            &key,
+            GuardedCommand::Reward { player, amount: 3 },
+            &context,
+            None,
+        )
+        .await;
+    assert!(matches!(
+        result,
+        Err(EventSourceStateError::EventSourceError(
+            EventSourceError::RetryExhausted {
+                attempts: 1,
+                last_revision: None,
+            }
+        ))
+    ));
+}
+
+#[tokio::test]
+async fn cache_dto_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let cache = MapCache::<SimpleState>::new();
+
+    let name = "cache_dto_test";
+    // the group starts at the end of the stream, it exists before writing
+    create_subscription(&event_db, &Stream::Stream(name), "cache_group")
+        .await
+        .expect("create group");
+    let worker = StateRepository::new(event_db.clone(), cache.clone());
+    tokio::spawn(async move {
+        worker
+            .cache_dto(&Stream::Stream(name), "cache_group")
+            .await
+            .expect("cache worker stopped");
+    });
+
+    let repo = StateRepository::new(event_db, cache.clone());
+    let key = ModelKey::new(name, Uuid::new_v4());
+
+    repo.add_command(&key, SimpleCommand::Add(80), None)
+        .await
+        .expect("add 80");
+    repo.add_command(&key, SimpleCommand::Add(102), None)
+        .await
+        .expect("add 102");
+
+    let expected = Some(format!(
+        r#"{{"fingerprint":"{}","position":3,"model":{{"nb":182}}}}"#,
+        SimpleState::cache_fingerprint()
+    ));
+    eventually("both commands cached", async || cache.raw(&key) == expected).await;
+}
+
+#[tokio::test]
+async fn state_change_notification_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let notifier = BroadcastNotifier::new(16);
+    let cache = NotifyingCache::new(MapCache::<SimpleState>::new(), notifier.clone());
+    let mut changes = notifier.subscribe();
+
+    let name = "notify_test";
+    create_subscription(&event_db, &Stream::Stream(name), "notify_group")
+        .await
+        .expect("create group");
+    let worker = StateRepository::new(event_db.clone(), cache.clone());
+    tokio::spawn(async move {
+        worker
+            .cache_dto(&Stream::Stream(name), "notify_group")
+            .await
+            .expect("cache worker stopped");
+    });
+
+    let repo = StateRepository::new(event_db, cache.clone());
+    let key = ModelKey::new(name, Uuid::new_v4());
+    repo.add_command(&key, SimpleCommand::Add(80), None)
+        .await
+        .expect("add 80");
+
+    let change = timeout(Duration::from_secs(1), changes.recv())
+        .await
+        .expect("no change published")
+        .expect("change");
+
+    assert_eq!(change.key(), &key);
+    assert_eq!(change.prefix(), None);
+    assert_eq!(change.position(), Some(1));
+    assert!(cache.cache().raw(&key).is_some());
+}
+
+#[tokio::test]
+async fn cache_fingerprint_mismatch_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let cache = MapCache::<SimpleState>::new();
+    let repo = StateRepository::new(event_db, cache.clone());
+    let key = ModelKey::new("fingerprint_test", Uuid::new_v4());
+
+    repo.add_command(&key, SimpleCommand::Add(12), None)
+        .await
+        .expect("add 12");
+
+    // cached before the fingerprint, or by an older version of the `Dto`
+    for stale in [
+        r#"{"position":1,"model":{"nb":999}}"#.to_string(),
+        r#"{"fingerprint":"simple.v0","position":1,"model":{"nb":999,"gone":true}}"#.to_string(),
+    ] {
+        cache
+            .set_in_db(None, &key, stale)
+            .await
+            .expect("stale cache");
+
+        let model = repo.get_model(&key).await.expect("get model");
+        assert_eq!(model.state().nb, 12);
+    }
+
+    let model = repo.get_model(&key).await.expect("get model");
+    cache.set(&key, &model, None).await.expect("set");
+    let cached = CacheDb::<SimpleState>::get(&cache, None, &key)
+        .await
+        .expect("get");
+    assert_eq!(cached.position(), Some(1));
+}
+
+const POISON_STATE_NAME: StateName = "POISON";
+
+/// same variant as `SimpleEvent::Added` with a payload it cannot read
+#[derive(Deserialize, Serialize, Clone, Debug, Event)]
+#[state(POISON_STATE_NAME)]
+enum PoisonEvent {
+    Added(String),
+}
+
+#[cfg(feature = "codec-msgpack")]
+#[tokio::test]
+async fn codec_migration_in_memory() {
+    use horfimbor_eventsource::codec::{Codec, Format};
+
+    let event_db = InMemoryEventStore::new();
+    let cache = MapCache::<SimpleState>::new();
+    let key = ModelKey::new("codec_test", Uuid::new_v4());
+
+    let json = StateRepository::new(event_db.clone(), cache.clone());
+    json.add_command(&key, SimpleCommand::Add(5), None)
+        .await
+        .expect("add 5");
+
+    let msgpack = StateRepository::new(event_db.clone(), cache.clone())
+        .with_codec(Codec::new(Format::MessagePack));
+    msgpack
+        .add_command(&key, SimpleCommand::Add(7), None)
+        .await
+        .expect("add 7");
+
+    // the events written with both codecs are read by both repositories
+    for repo in [&json, &msgpack] {
+        let model = repo.get_model(&key).await.expect("get model");
+        assert_eq!(model.state().nb, 12);
+    }
+
+    let mut events = event_db
+        .read_stream(&key.format(), StreamPosition::Start)
+        .await
+        .expect("read stream");
+    let mut codecs = Vec::new();
+    while let Some(event) = events.next().await.expect("next") {
+        let metadata = event.metadata().expect("metadata");
+        if metadata.is_event() {
+            codecs.push(metadata.codec().map(ToString::to_string));
+        }
+    }
+    assert_eq!(codecs, vec![None, Some("msgpack".to_string())]);
+
+    let model = msgpack.get_model(&key).await.expect("get model");
+    msgpack
+        .cache_db()
+        .set_with_codec(&key, &model, None, msgpack.codec())
+        .await
+        .expect("set");
+    assert!(cache.raw(&key).expect("cached").starts_with("msgpack;"));
+
+    let cached = json.cache_db().get(None, &key).await.expect("get");
+    assert_eq!(cached.state().nb, 12);
+    assert_eq!(cached.position(), Some(3));
+}
+
+#[tokio::test]
+async fn cache_worker_park_poison_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let cache = MapCache::<SimpleState>::new();
+
+    let name = "cache_worker_test";
+    let stream = Stream::Stream(name);
+    create_subscription(&event_db, &stream, "worker_group")
+        .await
+        .expect("create group");
+    let worker = CacheWorker::spawn(
+        StateRepository::new(event_db.clone(), cache.clone()),
+        Stream::Stream(name),
+        "worker_group",
+        WorkerPolicy::default().with_max_failures(2),
+    );
+
+    let poisoned = ModelKey::new(name, Uuid::new_v4());
+    let id = Uuid::now_v7();
+    let metadata = Metadata::new(Some(id), id, id, false);
+    let poison = || {
+        CompleteEvent::from_event(&PoisonEvent::Added("not a number".to_string()), &metadata)
+            .expect("poison event")
+    };
+    // the first revision of a stream is a command, nothing is cached for it
+    event_db
+        .append_to_stream(
+            &poisoned.format(),
+            StreamState::NoStream,
+            vec![poison(), poison()],
+        )
+        .await
+        .expect("append poison");
+
+    let repo = StateRepository::new(event_db.clone(), cache.clone());
+    let key = ModelKey::new(name, Uuid::new_v4());
+    repo.add_command(&key, SimpleCommand::Add(80), None)
+        .await
+        .expect("add 80");
+
+    let expected = Some(format!(
+        r#"{{"fingerprint":"{}","position":1,"model":{{"nb":80}}}}"#,
+        SimpleState::cache_fingerprint()
+    ));
+    eventually("poison parked and command cached", async || {
+        cache.raw(&key) == expected && !event_db.parked_events(&stream, "worker_group").is_empty()
+    })
+    .await;
+    assert_eq!(cache.raw(&poisoned), None);
+
+    let parked = event_db.parked_events(&stream, "worker_group");
+    assert_eq!(parked.len(), 1);
+    assert_eq!(parked[0].stream_id(), poisoned.format());
+    assert_eq!(parked[0].revision(), 1);
+    assert!(worker.is_running());
+
+    worker.shutdown();
+    worker.join().await.expect("graceful shutdown");
+}
+
+#[tokio::test]
+async fn cache_worker_outlive_an_outage_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let cache = MapCache::<SimpleState>::new();
+
+    let name = "cache_worker_outage";
+    let stream = Stream::Stream(name);
+    create_subscription(&event_db, &stream, "worker_group")
+        .await
+        .expect("create group");
+
+    // more failed writes than `max_failures`, an outage is not counted against the event
+    cache.disconnect_for(5);
+    let worker = CacheWorker::spawn(
+        StateRepository::new(event_db.clone(), cache.clone()),
+        Stream::Stream(name),
+        "worker_group",
+        WorkerPolicy::default().with_max_failures(2),
+    );
+
+    let repo = StateRepository::new(event_db.clone(), cache.clone());
+    let key = ModelKey::new(name, Uuid::new_v4());
+    repo.add_command(&key, SimpleCommand::Add(80), None)
+        .await
+        .expect("add 80");
+
+    let expected = Some(format!(
+        r#"{{"fingerprint":"{}","position":1,"model":{{"nb":80}}}}"#,
+        SimpleState::cache_fingerprint()
+    ));
+    eventually("command cached after the outage", async || {
+        cache.raw(&key) == expected
+    })
+    .await;
+    assert!(event_db.parked_events(&stream, "worker_group").is_empty());
+    assert!(worker.is_running());
+
+    worker.shutdown();
+    worker.join().await.expect("graceful shutdown");
+}
+
+#[tokio::test]
+async fn subscriptions_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let repo_state = StateRepository::new(event_db.clone(), NoCache::<TTTState>::new());
+
+    let stream = Stream::Stream(TTT_STREAM);
+    let mut persistent = get_persistent_subscription(&event_db, &stream, "beta")
+        .await
+        .expect("cannot create subscribe");
+
+    let key = ModelKey::new(TTT_STREAM, Uuid::new_v4());
+
+    repo_state
+        .add_command(&key, TTTCommand::Create, None)
+        .await
+        .expect("cannot play command create");
+    repo_state
+        .add_command(&key, TTTCommand::Circle(1), None)
+        .await
+        .expect("circle cannot play");
+    repo_state
+        .add_command(&key, TTTCommand::Cross(0), None)
+        .await
+        .expect("cross cannot play");
+    repo_state
+        .add_command(&key, TTTCommand::Circle(3), None)
+        .await
+        .expect("circle cannot play twice");
+
+    let model = repo_state.get_model(&key).await.expect("cannot load TTT");
+    assert_eq!(model.state().get_winner(), Some(Victory::Winner(Circle)));
+
+    // `$ce-` : every command and event of the category
+    let mut types = Vec::new();
+    for _ in 0..9 {
+        let received = timeout(Duration::from_secs(1), persistent.next())
+            .await
+            .expect("missing event")
+            .expect("subscription dropped");
+        let event = received.event().expect("resolved event");
+        assert_eq!(event.stream_id(), key.format());
+        types.push(event.event_type().to_string());
+        persistent.ack(&received).await.expect("cannot ack");
+    }
+    assert_eq!(types[1], TTTEvents::Started.event_name());
+    assert_eq!(
+        types[8],
+        TTTEvents::Ended(Victory::Winner(Circle)).event_name()
+    );
+
+    // `$et-` : only the public event
+    let mut by_type = get_subscription(
+        &event_db,
+        &Stream::Event(TTTEvents::Started.event_name()),
+        None,
+    )
+    .await;
+    let started = by_type.next().await.expect("started");
+    assert_eq!(
+        started.as_json::<TTTEvents>().expect("public event"),
+        TTTEvents::Started
+    );
+
+    // `bc-` : the command and its events share the correlation id
+    let first = started.metadata().expect("metadata");
+    let mut by_correlation = get_subscription(
+        &event_db,
+        &Stream::Correlation(first.correlation_id()),
+        None,
+    )
+    .await;
+    let command = by_correlation.next().await.expect("command");
+    assert_eq!(Some(command.id()), Some(first.correlation_id()));
+    assert!(!command.metadata().expect("metadata").is_event());
+    let event = by_correlation.next().await.expect("event");
+    assert_eq!(event.id(), started.id());
+}
+
+#[tokio::test]
+async fn subscribe_from_position_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let key = ModelKey::new("simple_test", Uuid::new_v4());
+
+    repo.add_command(&key, SimpleCommand::Add(1), None)
+        .await
+        .expect("add 1");
+
+    let mut sub = get_subscription(&event_db, &Stream::Model(key.clone()), Some(0)).await;
+    let event = sub.next().await.expect("event after the command");
+    assert_eq!(event.revision(), 1);
+    assert_eq!(
+        event.as_json::<SimpleEvent>().expect("event").event_name(),
+        SimpleEvent::Added(1).event_name()
+    );
+}
+
+#[tokio::test]
+async fn typed_subscriptions_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+
+    let name = "typed_simple";
+    let mut removed = get_typed_persistent_subscription::<_, SimpleEvent>(
+        &event_db,
+        &Stream::Stream(name),
+        "typed",
+    )
+    .await
+    .expect("cannot create subscribe")
+    .accept(|event_name| event_name == SimpleEvent::Removed(0).event_name());
+
+    let key = ModelKey::new(name, Uuid::new_v4());
+    for command in [
+        SimpleCommand::Add(5),
+        SimpleCommand::Add(2),
+        SimpleCommand::Remove(3),
+        SimpleCommand::Set(1),
+    ] {
+        repo.add_command(&key, command, None)
+            .await
+            .expect("cannot add command");
+    }
+
+    // the commands are skipped, the events come decoded with their key
+    let all =
+        get_typed_subscription::<_, SimpleEvent>(&event_db, &Stream::Model(key.clone()), None)
+            .await
+            .take(5)
+            .map(|envelope| envelope.expect("typed envelope"))
+            .collect::<Vec<_>>()
+            .await;
+    assert!(all.iter().all(|envelope| envelope.key() == &key));
+    assert!(all.iter().all(|envelope| envelope.metadata().is_event()));
+    assert!(all.iter().all(|envelope| !envelope.is_persistent()));
+    assert_eq!(
+        all.iter()
+            .map(|envelope| envelope.revision())
+            .collect::<Vec<_>>(),
+        vec![1, 3, 5, 7, 8]
+    );
+    assert!(matches!(all[4].event(), SimpleEvent::Added(1)));
+
+    // the refused event types are acked by the subscription
+    let first = timeout(Duration::from_secs(1), removed.next())
+        .await
+        .expect("missing event")
+        .expect("subscription dropped")
+        .expect("typed envelope");
+    assert!(first.is_persistent());
+    assert_eq!(first.revision(), 5);
+    assert!(matches!(first.event(), SimpleEvent::Removed(3)));
+    first.ack().await.expect("cannot ack");
+
+    let second = timeout(Duration::from_secs(1), removed.next())
+        .await
+        .expect("missing event")
+        .expect("subscription dropped")
+        .expect("typed envelope");
+    assert_eq!(second.revision(), 7);
+    second
+        .nack(NakAction::Park, "not handled by this consumer")
+        .await
+        .expect("cannot nack");
+    assert!(matches!(second.into_event(), SimpleEvent::Removed(4)));
+
+    assert!(
+        timeout(Duration::from_millis(50), removed.next())
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn typed_persistent_ack_while_waiting_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+
+    let name = "typed_waiting";
+    let mut events = get_typed_persistent_subscription::<_, SimpleEvent>(
+        &event_db,
+        &Stream::Stream(name),
+        "typed",
+    )
+    .await
+    .expect("cannot create subscribe");
+
+    let key = ModelKey::new(name, Uuid::new_v4());
+    repo.add_command(&key, SimpleCommand::Add(5), None)
+        .await
+        .expect("cannot add command");
+
+    let first = timeout(Duration::from_secs(1), events.next())
+        .await
+        .expect("missing event")
+        .expect("subscription dropped")
+        .expect("typed envelope");
+
+    // nothing to read yet, the subscription is waiting,
+    // it is given back : the envelopes cannot be acked once it is dropped
+    let waiting = tokio::spawn(async move {
+        let next = events.next().await;
+        (events, next)
+    });
+    // the spawned task start waiting before the ack
+    yield_now().await;
+
+    timeout(Duration::from_secs(1), first.ack())
+        .await
+        .expect("ack blocked by the next event")
+        .expect("cannot ack");
+
+    repo.add_command(&key, SimpleCommand::Add(2), None)
+        .await
+        .expect("cannot add command");
+
+    let (_events, second) = timeout(Duration::from_secs(1), waiting)
+        .await
+        .expect("missing event")
+        .expect("task");
+    let second = second
+        .expect("subscription dropped")
+        .expect("typed envelope");
+    assert!(matches!(second.event(), SimpleEvent::Added(2)));
+    second.ack().await.expect("cannot ack");
+}
+
+#[tokio::test]
+async fn typed_persistent_dropped_in_memory() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+
+    let name = "typed_dropped";
+    let events = get_typed_persistent_subscription::<_, SimpleEvent>(
+        &event_db,
+        &Stream::Stream(name),
+        "typed",
+    )
+    .await
+    .expect("cannot create subscribe");
+
+    // the task is waiting for the next event when the subscription is dropped
+    yield_now().await;
+    drop(events);
+    yield_now().await;
+
+    let key = ModelKey::new(name, Uuid::new_v4());
+    repo.add_command(&key, SimpleCommand::Add(5), None)
+        .await
+        .expect("cannot add command");
+
+    // the stopped task did not take the event
+    let mut events = get_typed_persistent_subscription::<_, SimpleEvent>(
+        &event_db,
+        &Stream::Stream(name),
+        "typed",
+    )
+    .await
+    .expect("cannot subscribe again");
+
+    let envelope = timeout(Duration::from_secs(1), events.next())
+        .await
+        .expect("event taken by the dropped subscription")
+        .expect("subscription dropped")
+        .expect("typed envelope");
+    assert!(matches!(envelope.event(), SimpleEvent::Added(5)));
+    envelope.ack().await.expect("cannot ack");
+}
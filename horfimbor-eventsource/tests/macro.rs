@@ -61,6 +61,7 @@ pub enum PublicTestEvent {
 }
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize, Event)]
 #[state(STATE_NAME)]
+#[event_version(3)]
 pub enum PrivateTestEvent {
     OtherStuff { a: String },
 }
@@ -93,4 +94,7 @@ fn it_works() {
     assert_eq!(evt_add.event_name(), "PUB_NAME.evt.added");
     assert_eq!(evt_restarted.event_name(), "PUB_NAME.evt.restarted");
     assert_eq!(evt_other.event_name(), "STATE_NAME.evt.other_stuff");
+
+    assert_eq!(evt_add.event_version(), 0);
+    assert_eq!(evt_other.event_version(), 3);
 }
@@ -0,0 +1,166 @@
+#![cfg(feature = "metrics")]
+
+use std::sync::atomic::AtomicUsize;
+
+use uuid::Uuid;
+
+use horfimbor_eventsource::Stream;
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::cache_db::memory::MemoryCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::helper::create_subscription;
+use horfimbor_eventsource::metrics::Metrics;
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{
+    DtoRepository, DtoRepositoryConstructor, Repository, RepositoryKind, StateRepository,
+    StateRepositoryConstructor,
+};
+use horfimbor_eventsource::retry::RetryPolicy;
+
+use crate::eventually::eventually;
+use crate::guarded::{GuardedCommand, GuardedContext, GuardedState};
+use crate::simple::{SimpleCommand, SimpleState};
+
+mod eventually;
+mod guarded;
+// `SimpleNbAddDto` is not needed here
+#[allow(dead_code)]
+mod simple;
+
+/// the value of the sample starting with `sample`, labels included
+fn sample(rendered: &str, sample: &str) -> Option<f64> {
+    rendered
+        .lines()
+        .find_map(|line| line.strip_prefix(sample)?.trim().parse().ok())
+}
+
+#[tokio::test]
+async fn commands_and_cache_are_measured() {
+    let metrics = Metrics::new().expect("metrics");
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), MemoryCache::<SimpleState>::new(10))
+        .with_metrics(metrics.clone());
+    let key = ModelKey::new("metrics", Uuid::new_v4());
+
+    repo.add_command(&key, SimpleCommand::Add(5), None)
+        .await
+        .expect("add 5");
+    repo.add_command(&key, SimpleCommand::Add(2), None)
+        .await
+        .expect("add 2");
+    assert!(
+        repo.add_command(&key, SimpleCommand::Remove(10), None)
+            .await
+            .is_err()
+    );
+    repo.get_model(&key).await.expect("model");
+
+    let rendered = metrics.render().expect("render");
+    let state = r#"state="SIMPLE_STATE_NAME""#;
+
+    assert_eq!(
+        sample(&rendered, &format!("horfimbor_commands_total{{{state}}}")),
+        Some(3.0)
+    );
+    assert_eq!(
+        sample(
+            &rendered,
+            &format!(r#"horfimbor_command_rejections_total{{error="Info",{state}}}"#)
+        ),
+        Some(1.0)
+    );
+    // nothing is cached by add_command
+    assert_eq!(
+        sample(
+            &rendered,
+            &format!(r#"horfimbor_cache_requests_total{{result="miss",{state}}}"#)
+        ),
+        Some(4.0)
+    );
+    // 0, 2, 4 then 4 entries read from the start of the stream
+    assert_eq!(
+        sample(
+            &rendered,
+            &format!("horfimbor_replay_length_count{{{state}}}")
+        ),
+        Some(4.0)
+    );
+    assert_eq!(
+        sample(
+            &rendered,
+            &format!("horfimbor_replay_length_sum{{{state}}}")
+        ),
+        Some(10.0)
+    );
+}
+
+#[tokio::test]
+async fn retries_and_lag_are_measured() {
+    let metrics = Metrics::new().expect("metrics");
+    let event_db = InMemoryEventStore::new();
+
+    let name = "metrics_lag";
+    create_subscription(&event_db, &Stream::Stream(name), "metrics_group")
+        .await
+        .expect("create group");
+    let worker = StateRepository::new(event_db.clone(), MemoryCache::<SimpleState>::new(10))
+        .with_metrics(metrics.clone());
+    tokio::spawn(async move {
+        worker
+            .cache_dto(&Stream::Stream(name), "metrics_group")
+            .await
+            .expect("cache worker stopped");
+    });
+
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let player = ModelKey::new(name, Uuid::new_v4());
+    repo.add_command(&player, SimpleCommand::Add(1), None)
+        .await
+        .expect("add 1");
+
+    let guarded = StateRepository::new(event_db.clone(), NoCache::<GuardedState>::new())
+        .with_retry_policy(RetryPolicy::new(1))
+        .with_metrics(metrics.clone());
+    let key = ModelKey::new("metrics_retry", Uuid::new_v4());
+
+    // the first call of the handler append a command before its own
+    let context = GuardedContext {
+        players: DtoRepository::new(event_db, NoCache::new(), RepositoryKind::Dto("players")),
+        calls: AtomicUsize::new(0),
+        conflict: Some((guarded.clone(), key.clone())),
+    };
+    assert!(
+        guarded
+            .add_command_with_context(
+                &key,
+                GuardedCommand::Reward { player, amount: 3 },
+                &context,
+                None,
+            )
+            .await
+            .is_err()
+    );
+
+    // the command and its event
+    let lag = r#"horfimbor_cache_dto_lag_seconds_count{state="SIMPLE_STATE_NAME"}"#;
+    eventually("both entries cached", async || {
+        sample(&metrics.render().expect("render"), lag) == Some(2.0)
+    })
+    .await;
+    let rendered = metrics.render().expect("render");
+
+    assert_eq!(
+        sample(
+            &rendered,
+            r#"horfimbor_command_retries_total{state="GUARDED_STATE_NAME"}"#
+        ),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(
+            &rendered,
+            r#"horfimbor_command_rejections_total{error="retry_exhausted",state="GUARDED_STATE_NAME"}"#
+        ),
+        Some(1.0)
+    );
+}
@@ -0,0 +1,209 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use kurrentdb::StreamPosition;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use horfimbor_eventsource::Stream;
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::event_store::{EventReader, EventStore};
+use horfimbor_eventsource::metadata::Metadata;
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::projection::{Projection, ProjectionError, ProjectionRunner};
+use horfimbor_eventsource::repository::{StateRepository, StateRepositoryConstructor};
+
+use crate::eventually::eventually;
+use crate::simple::{SimpleCommand, SimpleEvent, SimpleState};
+
+mod eventually;
+// `SimpleNbAddDto` is not needed here
+#[allow(dead_code)]
+mod simple;
+
+/// the total of each entity, across all the streams
+#[derive(Clone, Default)]
+struct Totals {
+    totals: Arc<Mutex<HashMap<ModelKey, i64>>>,
+}
+
+impl Totals {
+    fn get(&self, key: &ModelKey) -> Option<i64> {
+        self.totals.lock().expect("totals").get(key).copied()
+    }
+}
+
+#[async_trait]
+impl Projection for Totals {
+    type Event = SimpleEvent;
+
+    fn projection_name(&self) -> &'static str {
+        "totals"
+    }
+
+    fn stream(&self) -> Stream {
+        Stream::All
+    }
+
+    fn accept(&self, event_type: &str) -> bool {
+        event_type.starts_with("SIMPLE_STATE_NAME.evt")
+    }
+
+    async fn apply(
+        &self,
+        key: &ModelKey,
+        _metadata: &Metadata,
+        event: &Self::Event,
+    ) -> Result<(), ProjectionError> {
+        let mut totals = self.totals.lock().expect("totals");
+        let total = totals.entry(key.clone()).or_default();
+        match event {
+            SimpleEvent::Added(n) => *total += i64::from(*n),
+            SimpleEvent::Removed(n) => *total -= i64::from(*n),
+        }
+        Ok(())
+    }
+
+    async fn reset(&self) -> Result<(), ProjectionError> {
+        self.totals.lock().expect("totals").clear();
+        Ok(())
+    }
+}
+
+fn spawn_runner(
+    runner: &ProjectionRunner<Totals, InMemoryEventStore>,
+    rebuild: bool,
+) -> JoinHandle<()> {
+    let runner = runner.clone();
+    tokio::spawn(async move {
+        let result = if rebuild {
+            runner.rebuild().await
+        } else {
+            runner.run().await
+        };
+        result.expect("projection stopped");
+    })
+}
+
+/// the in-memory `$all` position of the event of the n-th command,
+/// until the first checkpoint is written in `$all` too
+const fn event_position(command: u64) -> u64 {
+    command * 2 + 1
+}
+
+#[tokio::test]
+async fn projection_across_entities() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let first = ModelKey::new("first", Uuid::new_v4());
+    let second = ModelKey::new("second", Uuid::new_v4());
+
+    repo.add_command(&first, SimpleCommand::Add(5), None)
+        .await
+        .expect("add 5");
+    repo.add_command(&second, SimpleCommand::Add(3), None)
+        .await
+        .expect("add 3");
+
+    let totals = Totals::default();
+    let runner = ProjectionRunner::new(event_db.clone(), totals.clone())
+        .with_checkpoint(100, Duration::from_millis(10));
+
+    // the past events are applied first, the checkpoint follow shortly
+    let worker = spawn_runner(&runner, false);
+    eventually("the checkpoint after the past events", async || {
+        runner.checkpoint().await.expect("checkpoint") == Some(event_position(1))
+    })
+    .await;
+    assert_eq!(totals.get(&first), Some(5));
+    assert_eq!(totals.get(&second), Some(3));
+
+    worker.abort();
+    let checkpoint = runner.checkpoint().await.expect("checkpoint");
+
+    repo.add_command(&first, SimpleCommand::Remove(2), None)
+        .await
+        .expect("remove 2");
+
+    // the restarted runner resume after its checkpoint
+    let worker = spawn_runner(&runner, false);
+    eventually("the checkpoint after the new event", async || {
+        runner.checkpoint().await.expect("checkpoint") > checkpoint
+    })
+    .await;
+    assert_eq!(totals.get(&first), Some(3));
+    assert_eq!(totals.get(&second), Some(3));
+
+    // only the last checkpoint is kept, without correlation id
+    let mut checkpoints = event_db
+        .read_stream("projection_totals", StreamPosition::Start)
+        .await
+        .expect("read checkpoints");
+    let checkpoint = checkpoints
+        .next()
+        .await
+        .expect("checkpoints")
+        .expect("checkpoint");
+    assert!(checkpoints.next().await.expect("checkpoints").is_none());
+    let checkpoint_metadata = String::from_utf8_lossy(checkpoint.custom_metadata()).to_string();
+    assert!(!checkpoint_metadata.contains("$correlationId"));
+
+    worker.abort();
+    totals
+        .totals
+        .lock()
+        .expect("totals")
+        .insert(second.clone(), 42);
+
+    // both totals are back to 3 once every event is applied again
+    let worker = spawn_runner(&runner, true);
+    eventually("the rebuilt totals", async || {
+        totals.get(&first) == Some(3) && totals.get(&second) == Some(3)
+    })
+    .await;
+
+    // live events after the rebuild
+    repo.add_command(&second, SimpleCommand::Add(1), None)
+        .await
+        .expect("add 1");
+    eventually("the live event", async || totals.get(&second) == Some(4)).await;
+
+    worker.abort();
+}
+
+#[tokio::test]
+async fn projection_checkpoints_are_batched() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let keys: Vec<_> = (0..3)
+        .map(|_| ModelKey::new("batched", Uuid::new_v4()))
+        .collect();
+
+    for key in &keys {
+        repo.add_command(key, SimpleCommand::Add(1), None)
+            .await
+            .expect("add 1");
+    }
+
+    let totals = Totals::default();
+    let runner = ProjectionRunner::new(event_db.clone(), totals.clone())
+        .with_checkpoint(2, Duration::from_secs(60));
+
+    let worker = spawn_runner(&runner, false);
+    eventually("the first batch", async || {
+        runner.checkpoint().await.expect("checkpoint") == Some(event_position(1))
+    })
+    .await;
+    eventually("the third event", async || totals.get(&keys[2]) == Some(1)).await;
+
+    // the third event wait for another one, or the delay
+    assert_eq!(
+        runner.checkpoint().await.expect("checkpoint"),
+        Some(event_position(1))
+    );
+
+    worker.abort();
+}
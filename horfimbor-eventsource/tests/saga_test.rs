@@ -0,0 +1,220 @@
+use async_trait::async_trait;
+use kurrentdb::StreamPosition;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::event_store::{EventReader, EventStore};
+use horfimbor_eventsource::helper::create_subscription;
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{Repository, StateRepository, StateRepositoryConstructor};
+use horfimbor_eventsource::saga::{Saga, SagaCommands, SagaRunner};
+use horfimbor_eventsource::{EventSourceStateError, Stream};
+
+use crate::eventually::eventually;
+use crate::simple::{SimpleCommand, SimpleState};
+use crate::with_public::public::{Player, TTT_PUB, TTT_STREAM, TTTEvents, Victory};
+use crate::with_public::{TTTCommand, TTTState};
+
+mod eventually;
+// `SimpleNbAddDto` is not needed here
+#[allow(dead_code)]
+mod simple;
+mod with_public;
+
+const LADDER: &str = "ladder";
+
+/// each victory send the `reward` to the ladder of the winner
+#[derive(Clone)]
+struct LadderSaga {
+    ladder: StateRepository<SimpleState, NoCache<SimpleState>, InMemoryEventStore>,
+    reward: SimpleCommand,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq)]
+struct GameProgress {
+    started: bool,
+    winner: Option<Player>,
+}
+
+fn ladder_key(player: &Player) -> ModelKey {
+    ModelKey::new_uuid_v8(LADDER, "player", &format!("{player:?}"))
+}
+
+#[async_trait]
+impl Saga for LadderSaga {
+    type State = GameProgress;
+    type Event = TTTEvents;
+
+    fn saga_name(&self) -> &'static str {
+        "ladder_saga"
+    }
+
+    fn stream(&self) -> Stream {
+        Stream::Stream(TTT_STREAM)
+    }
+
+    fn accept(&self, event_type: &str) -> bool {
+        event_type.starts_with(TTT_PUB)
+    }
+
+    async fn handle(
+        &self,
+        state: &mut Self::State,
+        _key: &ModelKey,
+        event: &Self::Event,
+        commands: &mut SagaCommands,
+    ) -> Result<(), EventSourceStateError> {
+        match event {
+            TTTEvents::Started => state.started = true,
+            TTTEvents::Ended(Victory::Draw) => {}
+            TTTEvents::Ended(Victory::Winner(player)) => {
+                commands
+                    .send(&self.ladder, &ladder_key(player), self.reward.clone())
+                    .await?;
+                state.winner = Some(player.clone());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn saga_update_the_ladder() {
+    let event_db = InMemoryEventStore::new();
+    let ladder = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+
+    let runner = SagaRunner::new(
+        event_db.clone(),
+        LadderSaga {
+            ladder: ladder.clone(),
+            reward: SimpleCommand::Add(1),
+        },
+    );
+    create_subscription(&event_db, &Stream::Stream(TTT_STREAM), "ladder_saga")
+        .await
+        .expect("create group");
+    let worker = runner.clone();
+    tokio::spawn(async move {
+        worker.run().await.expect("saga stopped");
+    });
+
+    let games = StateRepository::new(event_db.clone(), NoCache::<TTTState>::new());
+    let game = ModelKey::new(TTT_STREAM, Uuid::new_v4());
+
+    let created = games
+        .add_command_detailed(&game, TTTCommand::Create, None)
+        .await
+        .expect("create");
+    let correlation_id = created.command_metadata().correlation_id();
+
+    let mut state = created.state().clone();
+    for command in [
+        TTTCommand::Circle(1),
+        TTTCommand::Cross(0),
+        TTTCommand::Circle(3),
+    ] {
+        state = games
+            .add_command(&game, command, Some(created.last_metadata()))
+            .await
+            .expect("play");
+    }
+    assert_eq!(state.get_winner(), Some(Victory::Winner(Player::Circle)));
+
+    // the ladder of the winner is a new entity, its first command is sent by the saga
+    eventually("the winner ladder", async || {
+        ladder
+            .get_model(&ladder_key(&Player::Circle))
+            .await
+            .expect("ladder")
+            .state()
+            == &SimpleState { nb: 1 }
+    })
+    .await;
+
+    let mut ladder_stream = event_db
+        .read_stream(&ladder_key(&Player::Circle).format(), StreamPosition::Start)
+        .await
+        .expect("read ladder");
+    let command = ladder_stream
+        .next()
+        .await
+        .expect("ladder stream")
+        .expect("ladder command");
+    let metadata = command.metadata().expect("metadata");
+    assert!(!metadata.is_event());
+    assert_eq!(metadata.correlation_id(), correlation_id);
+
+    assert_eq!(
+        runner.saga_state(correlation_id).await.expect("saga state"),
+        Some(GameProgress {
+            started: true,
+            winner: Some(Player::Circle),
+        })
+    );
+    // the checkpoint has no correlation id, it does not create a `bc-` stream
+    let checkpoint = event_db
+        .read_last_event(&format!("saga_ladder_saga-{correlation_id}"))
+        .await
+        .expect("read checkpoint")
+        .expect("checkpoint");
+    let checkpoint_metadata = String::from_utf8_lossy(checkpoint.custom_metadata()).to_string();
+    assert!(!checkpoint_metadata.contains("$correlationId"));
+}
+
+#[tokio::test]
+async fn saga_park_the_failing_events() {
+    let event_db = InMemoryEventStore::new();
+    let ladder = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+
+    // nothing can be removed from a new ladder, the winner event always fail
+    let runner = SagaRunner::new(
+        event_db.clone(),
+        LadderSaga {
+            ladder: ladder.clone(),
+            reward: SimpleCommand::Remove(1),
+        },
+    )
+    .with_max_failures(2);
+    let stream = Stream::Stream(TTT_STREAM);
+    create_subscription(&event_db, &stream, "ladder_saga")
+        .await
+        .expect("create group");
+    let worker = runner.clone();
+    tokio::spawn(async move {
+        worker.run().await.expect("saga stopped");
+    });
+
+    let games = StateRepository::new(event_db.clone(), NoCache::<TTTState>::new());
+    let game = ModelKey::new(TTT_STREAM, Uuid::new_v4());
+    let created = games
+        .add_command_detailed(&game, TTTCommand::Create, None)
+        .await
+        .expect("create");
+    for command in [
+        TTTCommand::Circle(1),
+        TTTCommand::Cross(0),
+        TTTCommand::Circle(3),
+    ] {
+        games
+            .add_command(&game, command, Some(created.last_metadata()))
+            .await
+            .expect("play");
+    }
+
+    eventually("the winner event parked", async || {
+        !event_db.parked_events(&stream, "ladder_saga").is_empty()
+    })
+    .await;
+
+    let parked = event_db.parked_events(&stream, "ladder_saga");
+    assert_eq!(parked.len(), 1);
+    assert_eq!(parked[0].stream_id(), game.format());
+
+    let winner = ladder
+        .get_model(&ladder_key(&Player::Circle))
+        .await
+        .expect("ladder");
+    assert_eq!(winner.position(), None);
+}
@@ -0,0 +1,103 @@
+use horfimbor_eventsource_derive::{Command, Event, StateNamed};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use horfimbor_eventsource::snapshot::SnapshotPolicy;
+use horfimbor_eventsource::{
+    Command, CommandName, Dto, Event, EventName, State, StateName, StateNamed,
+};
+
+const COUNTER_STATE_NAME: StateName = "COUNTER_STATE_NAME";
+
+#[derive(Deserialize, Serialize, Clone, Debug, Command)]
+#[state(COUNTER_STATE_NAME)]
+pub enum CounterCommand {
+    Add(u32),
+}
+
+#[derive(Error, Debug)]
+pub enum CounterError {}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Event)]
+#[state(COUNTER_STATE_NAME)]
+pub enum CounterEvent {
+    Added(u32),
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, StateNamed)]
+#[state(COUNTER_STATE_NAME)]
+pub struct CounterState {
+    pub total: u32,
+    // not part of the snapshot : count the events played since the last one
+    #[serde(skip)]
+    pub replayed: usize,
+}
+
+impl Dto for CounterState {
+    type Event = CounterEvent;
+
+    fn play_event(&mut self, event: &Self::Event) {
+        match event {
+            CounterEvent::Added(n) => self.total += n,
+        }
+        self.replayed += 1;
+    }
+}
+
+impl State for CounterState {
+    type Command = CounterCommand;
+    type Error = CounterError;
+
+    fn try_command(&self, command: Self::Command) -> Result<Vec<Self::Event>, Self::Error> {
+        match command {
+            CounterCommand::Add(n) => Ok(vec![CounterEvent::Added(n)]),
+        }
+    }
+
+    fn snapshot_policy() -> SnapshotPolicy {
+        SnapshotPolicy::Every(4)
+    }
+
+    fn snapshot_version() -> u32 {
+        1
+    }
+}
+
+/// same stream as `CounterState` after a change of the serialized state
+#[derive(Debug, Default, Serialize, Deserialize, Clone, StateNamed)]
+#[state(COUNTER_STATE_NAME)]
+pub struct CounterStateV2 {
+    pub total: u32,
+    #[serde(skip)]
+    pub replayed: usize,
+}
+
+impl Dto for CounterStateV2 {
+    type Event = CounterEvent;
+
+    fn play_event(&mut self, event: &Self::Event) {
+        match event {
+            CounterEvent::Added(n) => self.total += n,
+        }
+        self.replayed += 1;
+    }
+}
+
+impl State for CounterStateV2 {
+    type Command = CounterCommand;
+    type Error = CounterError;
+
+    fn try_command(&self, command: Self::Command) -> Result<Vec<Self::Event>, Self::Error> {
+        match command {
+            CounterCommand::Add(n) => Ok(vec![CounterEvent::Added(n)]),
+        }
+    }
+
+    fn snapshot_policy() -> SnapshotPolicy {
+        SnapshotPolicy::OnDemand
+    }
+
+    fn snapshot_version() -> u32 {
+        2
+    }
+}
@@ -0,0 +1,160 @@
+use kurrentdb::StreamPosition;
+use uuid::Uuid;
+
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::event_store::{EventReader, EventStore};
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{Repository, StateRepository, StateRepositoryConstructor};
+
+use crate::snapshot::{CounterCommand, CounterState, CounterStateV2};
+
+mod snapshot;
+
+#[tokio::test]
+async fn snapshot_every_n_events() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<CounterState>::new());
+    let key = ModelKey::new("counter_test", Uuid::new_v4());
+    let snapshot_stream = format!("snapshot_{}", key.format());
+
+    repo.add_command(&key, CounterCommand::Add(1), None)
+        .await
+        .expect("add 1");
+    assert!(
+        event_db
+            .read_last_event(&snapshot_stream)
+            .await
+            .expect("read")
+            .is_none()
+    );
+
+    repo.add_command(&key, CounterCommand::Add(2), None)
+        .await
+        .expect("add 2");
+    let snapshot = event_db
+        .read_last_event(&snapshot_stream)
+        .await
+        .expect("read")
+        .expect("snapshot after 4 entries");
+    assert_eq!(
+        snapshot.data(),
+        br#"{"version":1,"position":3,"model":{"total":3}}"#
+    );
+
+    repo.add_command(&key, CounterCommand::Add(3), None)
+        .await
+        .expect("add 3");
+
+    // only the event after the snapshot is replayed
+    let model = repo.get_model(&key).await.expect("counter");
+    assert_eq!(model.position(), Some(5));
+    assert_eq!(model.state().total, 6);
+    assert_eq!(model.state().replayed, 1);
+
+    // the entity category does not contain the snapshots
+    let category = event_db
+        .read_last_event("$ce-counter_test")
+        .await
+        .expect("read")
+        .expect("category");
+    assert_eq!(category.stream_id(), key.format());
+}
+
+#[tokio::test]
+async fn snapshot_on_demand() {
+    let repo = StateRepository::new(InMemoryEventStore::new(), NoCache::<CounterState>::new());
+    let key = ModelKey::new("counter_test", Uuid::new_v4());
+
+    assert_eq!(repo.snapshot(&key).await.expect("empty stream"), None);
+
+    repo.add_command(&key, CounterCommand::Add(5), None)
+        .await
+        .expect("add 5");
+
+    assert_eq!(repo.snapshot(&key).await.expect("snapshot"), Some(1));
+
+    let model = repo.get_model(&key).await.expect("counter");
+    assert_eq!(model.state().total, 5);
+    assert_eq!(model.state().replayed, 0);
+
+    repo.add_command(&key, CounterCommand::Add(1), None)
+        .await
+        .expect("add 1");
+    assert_eq!(repo.snapshot(&key).await.expect("snapshot"), Some(3));
+
+    // only the last snapshot is kept, without correlation id
+    let mut snapshots = repo
+        .event_db()
+        .read_stream(&format!("snapshot_{}", key.format()), StreamPosition::Start)
+        .await
+        .expect("read snapshots");
+    let snapshot = snapshots
+        .next()
+        .await
+        .expect("snapshots")
+        .expect("snapshot");
+    assert!(snapshots.next().await.expect("snapshots").is_none());
+    let metadata = String::from_utf8_lossy(snapshot.custom_metadata()).to_string();
+    assert!(!metadata.contains("$correlationId"));
+}
+
+#[tokio::test]
+async fn snapshot_with_another_version_is_ignored() {
+    let event_db = InMemoryEventStore::new();
+    let repo_v1 = StateRepository::new(event_db.clone(), NoCache::<CounterState>::new());
+    let repo_v2 = StateRepository::new(event_db, NoCache::<CounterStateV2>::new());
+    let key = ModelKey::new("counter_test", Uuid::new_v4());
+
+    for n in 1..=3 {
+        repo_v1
+            .add_command(&key, CounterCommand::Add(n), None)
+            .await
+            .expect("add");
+    }
+
+    let model = repo_v2.get_model(&key).await.expect("counter");
+    assert_eq!(model.state().total, 6);
+    assert_eq!(model.state().replayed, 3);
+
+    // once written with the new version, the snapshot is used again
+    assert_eq!(repo_v2.snapshot(&key).await.expect("snapshot"), Some(5));
+    let model = repo_v2.get_model(&key).await.expect("counter");
+    assert_eq!(model.state().total, 6);
+    assert_eq!(model.state().replayed, 0);
+}
+
+#[tokio::test]
+async fn snapshot_stream_without_max_count_is_bounded() {
+    let repo = StateRepository::new(InMemoryEventStore::new(), NoCache::<CounterState>::new());
+    let key = ModelKey::new("counter_test", Uuid::new_v4());
+    let snapshot_stream = format!("snapshot_{}", key.format());
+
+    repo.add_command(&key, CounterCommand::Add(5), None)
+        .await
+        .expect("add 5");
+    assert_eq!(repo.snapshot(&key).await.expect("snapshot"), Some(1));
+
+    // like a stream written before its `$maxCount` was set
+    repo.event_db()
+        .set_max_count(&snapshot_stream, u64::MAX)
+        .await
+        .expect("unbounded");
+
+    repo.add_command(&key, CounterCommand::Add(1), None)
+        .await
+        .expect("add 1");
+    assert_eq!(repo.snapshot(&key).await.expect("snapshot"), Some(3));
+
+    let mut snapshots = repo
+        .event_db()
+        .read_stream(&snapshot_stream, StreamPosition::Start)
+        .await
+        .expect("read snapshots");
+    snapshots
+        .next()
+        .await
+        .expect("snapshots")
+        .expect("snapshot");
+    assert!(snapshots.next().await.expect("snapshots").is_none());
+}
@@ -0,0 +1,138 @@
+#![cfg(feature = "sse")]
+
+use std::time::Duration;
+
+use futures::StreamExt;
+use rocket::local::asynchronous::{Client, LocalResponse};
+use rocket::response::stream::{Event, EventStream};
+use rocket::tokio::io::AsyncReadExt;
+use rocket::{State, get, routes};
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{
+    DtoRepository, DtoRepositoryConstructor, RepositoryKind, StateRepository,
+    StateRepositoryConstructor,
+};
+use horfimbor_eventsource::sse::{EventStoreMessage, event_stream, model_messages};
+
+use crate::simple::{SimpleCommand, SimpleEvent, SimpleNbAddDto, SimpleState};
+
+mod simple;
+
+type NbAddRepository = DtoRepository<SimpleNbAddDto, NoCache<SimpleNbAddDto>, InMemoryEventStore>;
+
+#[tokio::test]
+async fn model_then_live_events() {
+    let event_db = InMemoryEventStore::new();
+    let repo_state = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let repo_dto: NbAddRepository =
+        DtoRepository::new(event_db, NoCache::new(), RepositoryKind::Dto("sse_nb_add"));
+    let key = ModelKey::new("sse_test", Uuid::new_v4());
+
+    repo_state
+        .add_command(&key, SimpleCommand::Add(3), None)
+        .await
+        .expect("add 3");
+
+    let mut messages = Box::pin(model_messages(repo_dto, key.clone()));
+
+    let first = messages.next().await.expect("dto");
+    assert!(matches!(
+        first,
+        EventStoreMessage::Dto(SimpleNbAddDto { nb: 1 })
+    ));
+    // untagged, the client read the json of the dto
+    assert_eq!(serde_json::to_string(&first).expect("json"), r#"{"nb":1}"#);
+
+    repo_state
+        .add_command(&key, SimpleCommand::Set(7), None)
+        .await
+        .expect("set 7");
+
+    // only the events appended after the model, the command is skipped
+    let removed = timeout(Duration::from_secs(1), messages.next())
+        .await
+        .expect("missing event")
+        .expect("event");
+    assert!(matches!(
+        removed,
+        EventStoreMessage::Event(SimpleEvent::Removed(3))
+    ));
+    let added = timeout(Duration::from_secs(1), messages.next())
+        .await
+        .expect("missing event")
+        .expect("event");
+    assert_eq!(
+        serde_json::to_string(&added).expect("json"),
+        r#"{"Added":7}"#
+    );
+
+    assert!(
+        timeout(Duration::from_millis(50), messages.next())
+            .await
+            .is_err()
+    );
+}
+
+/// the next message of the stream, up to the blank line
+async fn next_message(body: &mut LocalResponse<'_>) -> String {
+    let mut message = Vec::new();
+    while !message.ends_with(b"\n\n") {
+        let byte = timeout(Duration::from_secs(1), body.read_u8())
+            .await
+            .expect("missing message")
+            .expect("body");
+        message.push(byte);
+    }
+    String::from_utf8(message).expect("utf8")
+}
+
+#[get("/nb_add/<id>/<_jwt>")]
+fn nb_add(id: &str, _jwt: &str, repo: &State<NbAddRepository>) -> EventStream![Event + 'static] {
+    let uuid = Uuid::parse_str(id).unwrap_or_default();
+    event_stream(repo.inner().clone(), ModelKey::new("sse_test", uuid))
+}
+
+#[tokio::test]
+async fn rocket_event_stream() {
+    let event_db = InMemoryEventStore::new();
+    let repo_state = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let repo_dto: NbAddRepository =
+        DtoRepository::new(event_db, NoCache::new(), RepositoryKind::Dto("sse_nb_add"));
+    let uuid = Uuid::new_v4();
+
+    repo_state
+        .add_command(
+            &ModelKey::new("sse_test", uuid),
+            SimpleCommand::Add(2),
+            None,
+        )
+        .await
+        .expect("add 2");
+
+    let rocket = rocket::build().manage(repo_dto).mount("/", routes![nb_add]);
+    let client = Client::tracked(rocket).await.expect("rocket");
+
+    let mut body = client.get(format!("/nb_add/{uuid}/jwt")).dispatch().await;
+    assert_eq!(
+        body.content_type(),
+        Some(rocket::http::ContentType::EventStream)
+    );
+
+    assert_eq!(next_message(&mut body).await, "data:{\"nb\":1}\n\n");
+
+    repo_state
+        .add_command(
+            &ModelKey::new("sse_test", uuid),
+            SimpleCommand::Add(4),
+            None,
+        )
+        .await
+        .expect("add 4");
+
+    assert_eq!(next_message(&mut body).await, "data:{\"Added\":4}\n\n");
+}
@@ -1,8 +1,8 @@
-use std::thread;
 use std::time::Duration;
 
-use futures::executor::block_on;
 use kurrentdb::Client as EventClient;
+use tokio::runtime::Handle;
+use tokio::task::spawn_blocking;
 use tokio::time::sleep;
 use uuid::Uuid;
 
@@ -100,26 +100,28 @@ async fn concurrent_case() {
     {
         let repo = repo.clone();
         let key = key.clone();
-        thread::spawn(move || {
-            block_on(repo.add_command(
-                &key,
-                ConcurrentCommand::TakeTime(1, "one".to_string()),
-                None,
-            ))
-            .unwrap();
+        spawn_blocking(move || {
+            Handle::current()
+                .block_on(repo.add_command(
+                    &key,
+                    ConcurrentCommand::TakeTime(1, "one".to_string()),
+                    None,
+                ))
+                .unwrap();
         });
     }
 
     {
         let repo = repo.clone();
         let key = key.clone();
-        thread::spawn(move || {
-            block_on(repo.add_command(
-                &key,
-                ConcurrentCommand::TakeTime(2, "two".to_string()),
-                None,
-            ))
-            .unwrap();
+        spawn_blocking(move || {
+            Handle::current()
+                .block_on(repo.add_command(
+                    &key,
+                    ConcurrentCommand::TakeTime(2, "two".to_string()),
+                    None,
+                ))
+                .unwrap();
         });
     }
 
@@ -10,12 +10,12 @@ use redis::Commands;
 use tokio::time::sleep;
 use uuid::Uuid;
 
-use horfimbor_eventsource::Stream;
-use horfimbor_eventsource::cache_db::redis::StateDb;
+use horfimbor_eventsource::cache_db::redis::{RedisConnection, StateDb};
 use horfimbor_eventsource::model_key::ModelKey;
 use horfimbor_eventsource::repository::Repository;
 use horfimbor_eventsource::repository::StateRepository;
 use horfimbor_eventsource::repository::StateRepositoryConstructor;
+use horfimbor_eventsource::{Dto, Stream};
 
 use crate::state_db::{PokeCommand, PokeState};
 
@@ -40,7 +40,11 @@ lazy_static! {
 async fn with_cache() {
     let redis_client = redis::Client::open("redis://localhost:6379/").unwrap();
     let event_store = get_event_db();
-    let state_repo = StateRepository::new(event_store, EasyRedisCache::new(redis_client.clone()));
+    let connection = RedisConnection::new(redis_client.clone());
+    let state_repo = StateRepository::new(
+        event_store,
+        EasyRedisCache::with_connection(connection.clone()),
+    );
 
     let stream = Stream::Stream(&NAME);
 
@@ -54,7 +58,7 @@ async fn with_cache() {
 
     let repo = StateRepository::new(
         event_store.clone(),
-        EasyRedisCache::new(redis_client.clone()),
+        EasyRedisCache::with_connection(connection),
     );
 
     let key = ModelKey::new(&NAME, Uuid::new_v4());
@@ -83,7 +87,10 @@ async fn with_cache() {
     let data_redis: Option<String> = connection.get(key.format()).unwrap();
     assert_eq!(
         data_redis,
-        Some(r#"{"position":3,"model":{"nb":182}}"#.to_string())
+        Some(format!(
+            r#"{{"fingerprint":"{}","position":3,"model":{{"nb":182}}}}"#,
+            PokeState::cache_fingerprint()
+        ))
     );
 
     let data_es = repo.get_model(&key).await.unwrap();
@@ -0,0 +1,112 @@
+#![cfg(feature = "telemetry")]
+
+use opentelemetry::trace::{SpanId, TraceContextExt, TracerProvider};
+use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+use tracing::Instrument;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use uuid::Uuid;
+
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::event_store::{EventReader, EventStore};
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{StateRepository, StateRepositoryConstructor};
+use horfimbor_eventsource::telemetry::{event_span, parent_context};
+use kurrentdb::StreamPosition;
+
+use crate::simple::{SimpleCommand, SimpleState};
+
+// `SimpleNbAddDto` is not needed here
+#[allow(dead_code)]
+mod simple;
+
+fn span<'a>(spans: &'a [SpanData], name: &str) -> &'a SpanData {
+    spans
+        .iter()
+        .find(|span| span.name == name)
+        .unwrap_or_else(|| panic!("no `{name}` span"))
+}
+
+#[tokio::test]
+async fn trace_context_follow_the_events() {
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("telemetry_test")));
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let key = ModelKey::new("telemetry", Uuid::new_v4());
+
+    let action = tracing::info_span!("player_action");
+    let trace_id = action.context().span().span_context().trace_id();
+
+    repo.add_command(&key, SimpleCommand::Add(5), None)
+        .instrument(action)
+        .await
+        .expect("add 5");
+
+    let mut stream = event_db
+        .read_stream(&key.format(), StreamPosition::Position(1))
+        .await
+        .expect("read");
+    let added = stream.next().await.expect("next").expect("event");
+    let metadata = added.metadata().expect("metadata");
+
+    let parent = parent_context(&metadata).expect("trace context");
+    assert_eq!(parent.span().span_context().trace_id(), trace_id);
+    assert!(parent.span().span_context().is_remote());
+
+    // a subscriber handling the event continue the trace
+    event_span("consumer", &added).in_scope(|| {});
+
+    provider.force_flush().expect("flush");
+    let spans = exporter.get_finished_spans().expect("spans");
+
+    let add_command = span(&spans, "add_command");
+    let try_append = span(&spans, "try_append");
+    let complete_from_es = span(&spans, "complete_from_es");
+    let consumer = span(&spans, "consumer");
+
+    assert_eq!(
+        add_command.parent_span_id,
+        span(&spans, "player_action").span_context.span_id()
+    );
+    assert_eq!(
+        try_append.parent_span_id,
+        add_command.span_context.span_id()
+    );
+    assert_eq!(
+        complete_from_es.parent_span_id,
+        try_append.span_context.span_id()
+    );
+
+    assert_eq!(consumer.span_context.trace_id(), trace_id);
+    assert_eq!(consumer.parent_span_id, try_append.span_context.span_id());
+    assert_ne!(consumer.parent_span_id, SpanId::INVALID);
+}
+
+#[tokio::test]
+async fn no_trace_context_without_span() {
+    let event_db = InMemoryEventStore::new();
+    let repo = StateRepository::new(event_db.clone(), NoCache::<SimpleState>::new());
+    let key = ModelKey::new("telemetry", Uuid::new_v4());
+
+    repo.add_command(&key, SimpleCommand::Add(1), None)
+        .await
+        .expect("add 1");
+
+    let mut stream = event_db
+        .read_stream(&key.format(), StreamPosition::Start)
+        .await
+        .expect("read");
+    let command = stream.next().await.expect("next").expect("command");
+    let metadata = command.metadata().expect("metadata");
+
+    assert_eq!(metadata.traceparent(), None);
+    assert!(parent_context(&metadata).is_none());
+}
@@ -0,0 +1,92 @@
+use serde_json::Value;
+use uuid::Uuid;
+
+use horfimbor_eventsource::cache_db::NoCache;
+use horfimbor_eventsource::event_store::memory::InMemoryEventStore;
+use horfimbor_eventsource::event_store::{EventReader, EventStore};
+use horfimbor_eventsource::model_key::ModelKey;
+use horfimbor_eventsource::repository::{Repository, StateRepository, StateRepositoryConstructor};
+use horfimbor_eventsource::upcaster::Upcasters;
+use horfimbor_eventsource::{Event, EventSourceError};
+use kurrentdb::StreamPosition;
+
+use crate::versioned::{OldPlayerState, PlayerCommand, PlayerEvent, PlayerState};
+
+mod versioned;
+
+fn rename_name(mut payload: Value) -> Value {
+    if let Some(joined) = payload.get_mut("Joined").and_then(Value::as_object_mut)
+        && let Some(name) = joined.remove("name")
+    {
+        joined.insert("nickname".to_string(), name);
+    }
+    payload
+}
+
+#[tokio::test]
+async fn old_events_are_upcasted() {
+    let event_db = InMemoryEventStore::new();
+    let old_repo = StateRepository::new(event_db.clone(), NoCache::<OldPlayerState>::new());
+    let key = ModelKey::new("player_test", Uuid::new_v4());
+
+    old_repo
+        .add_command(&key, PlayerCommand::Join("alice".to_string()), None)
+        .await
+        .expect("join alice");
+
+    let event_name = PlayerEvent::Joined {
+        nickname: String::new(),
+    }
+    .event_name();
+
+    // without the upcaster the old payload cannot be read
+    let repo = StateRepository::new(event_db.clone(), NoCache::<PlayerState>::new());
+    assert!(matches!(
+        repo.get_model(&key).await,
+        Err(EventSourceError::Serde(_))
+    ));
+
+    let repo = repo.with_upcasters(Upcasters::new().register(event_name, 0, rename_name));
+
+    repo.add_command(&key, PlayerCommand::Join("bob".to_string()), None)
+        .await
+        .expect("join bob");
+
+    let model = repo.get_model(&key).await.expect("player");
+    assert_eq!(
+        model.state(),
+        &PlayerState {
+            nicknames: vec!["alice".to_string(), "bob".to_string()],
+        }
+    );
+
+    // each event keep the version it was written with
+    let mut reader = event_db
+        .read_stream(&key.format(), StreamPosition::Start)
+        .await
+        .expect("stream");
+    let mut versions = Vec::new();
+    while let Some(event) = reader.next().await.expect("event") {
+        let metadata = event.metadata().expect("metadata");
+        if metadata.is_event() {
+            versions.push(metadata.schema_version());
+        }
+    }
+    assert_eq!(versions, vec![0, 1]);
+}
+
+#[test]
+fn upcasters_are_chained() {
+    let upcasters = Upcasters::new()
+        .register("evt", 0, |v| {
+            Value::from(format!("{}-1", v.as_str().unwrap_or("")))
+        })
+        .register("evt", 2, |v| {
+            Value::from(format!("{}-3", v.as_str().unwrap_or("")))
+        });
+
+    assert_eq!(upcasters.upcast("evt", 0, Value::from("v0")), "v0-1-3");
+    assert_eq!(upcasters.upcast("evt", 1, Value::from("v1")), "v1-3");
+    assert_eq!(upcasters.upcast("evt", 3, Value::from("v3")), "v3");
+    assert_eq!(upcasters.upcast("other", 0, Value::from("v0")), "v0");
+}
@@ -0,0 +1,87 @@
+use horfimbor_eventsource_derive::{Command, Event, StateNamed};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use horfimbor_eventsource::{
+    Command, CommandName, Dto, Event, EventName, State, StateName, StateNamed,
+};
+
+const PLAYER_STATE_NAME: StateName = "PLAYER_STATE_NAME";
+
+#[derive(Deserialize, Serialize, Clone, Debug, Command)]
+#[state(PLAYER_STATE_NAME)]
+pub enum PlayerCommand {
+    Join(String),
+}
+
+#[derive(Error, Debug)]
+pub enum PlayerError {}
+
+/// the event as it was first written
+#[derive(Deserialize, Serialize, Debug, Clone, Event)]
+#[state(PLAYER_STATE_NAME)]
+pub enum OldPlayerEvent {
+    Joined { name: String },
+}
+
+/// `name` was renamed `nickname`
+#[derive(Deserialize, Serialize, Debug, Clone, Event)]
+#[state(PLAYER_STATE_NAME)]
+#[event_version(1)]
+pub enum PlayerEvent {
+    Joined { nickname: String },
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, StateNamed)]
+#[state(PLAYER_STATE_NAME)]
+pub struct OldPlayerState {
+    pub names: Vec<String>,
+}
+
+impl Dto for OldPlayerState {
+    type Event = OldPlayerEvent;
+
+    fn play_event(&mut self, event: &Self::Event) {
+        match event {
+            OldPlayerEvent::Joined { name } => self.names.push(name.clone()),
+        }
+    }
+}
+
+impl State for OldPlayerState {
+    type Command = PlayerCommand;
+    type Error = PlayerError;
+
+    fn try_command(&self, command: Self::Command) -> Result<Vec<Self::Event>, Self::Error> {
+        match command {
+            PlayerCommand::Join(name) => Ok(vec![OldPlayerEvent::Joined { name }]),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize, Clone, StateNamed)]
+#[state(PLAYER_STATE_NAME)]
+pub struct PlayerState {
+    pub nicknames: Vec<String>,
+}
+
+impl Dto for PlayerState {
+    type Event = PlayerEvent;
+
+    fn play_event(&mut self, event: &Self::Event) {
+        match event {
+            PlayerEvent::Joined { nickname } => self.nicknames.push(nickname.clone()),
+        }
+    }
+}
+
+impl State for PlayerState {
+    type Command = PlayerCommand;
+    type Error = PlayerError;
+
+    fn try_command(&self, command: Self::Command) -> Result<Vec<Self::Event>, Self::Error> {
+        match command {
+            PlayerCommand::Join(nickname) => Ok(vec![PlayerEvent::Joined { nickname }]),
+        }
+    }
+}